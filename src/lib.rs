@@ -0,0 +1,18 @@
+extern crate core;
+
+pub mod lexer;
+pub mod parser2;
+mod ir2;
+mod wasm;
+pub mod c;
+pub mod second_attempt;
+
+// the first attempt's modules find these at the crate root
+use crate::lexer::Span;
+use crate::parser2::ParserFile;
+
+pub use crate::second_attempt::engine::{Engine, EngineError, FromValue, IntoArgs, IntoValue, NativeFn};
+pub use crate::second_attempt::vm2::misc::Value;
+pub use crate::second_attempt::vm2::Vm;
+pub use crate::second_attempt::vm2::limits::Limits;
+pub use crate::second_attempt::vm_error::{Limit, VmError, VmErrorKind};
@@ -1,25 +1,43 @@
-extern crate core;
-
-mod lexer;
-mod parser2;
-mod ir2;
-mod wasm;
-mod c;
-mod second_attempt;
-
 use std::fs;
 use chumsky::{Parser, Stream};
 use std::ops::Range;
 use ariadne::{Color, Fmt, Label, Report, ReportKind, Source};
 use chumsky::prelude::Simple;
 use terminal_emoji::Emoji;
-use crate::c::do_full_compilation;
-use crate::lexer::{Span, Token};
-use crate::parser2::ParserFile;
+use table::{lexer, parser2, second_attempt};
+use table::c::do_full_compilation;
+use table::lexer::{Span, Token};
+use table::parser2::ParserFile;
 //
 // mod parser;
 
 fn main() {
+    if std::env::args().nth(1).as_deref() == Some("compile") {
+        let usage = "usage: table compile <source.tbl> <file.tblc>";
+        let source_path = std::env::args().nth(2).expect(usage);
+        let path = std::env::args().nth(3).expect(usage);
+        second_attempt::compile_entrypoint(source_path, path);
+        return;
+    }
+    if std::env::args().nth(1).as_deref() == Some("disassemble") {
+        let path = std::env::args().nth(2).expect("usage: table disassemble <file.tblc>");
+        second_attempt::disassemble_entrypoint(path);
+        return;
+    }
+    if std::env::args().nth(1).as_deref() == Some("debug") {
+        let path = std::env::args().nth(2).expect("usage: table debug <file.tblc> [source.tbl]");
+        second_attempt::debug_entrypoint(path, std::env::args().nth(3));
+        return;
+    }
+    if std::env::args().nth(1).as_deref() == Some("profile") {
+        let path = std::env::args().nth(2).expect("usage: table profile <file.tblc> [collapsed-stacks.txt]");
+        second_attempt::profile_entrypoint(path, std::env::args().nth(3));
+        return;
+    }
+    if std::env::args().nth(1).as_deref() == Some("bench") {
+        second_attempt::bench_entrypoint();
+        return;
+    }
     let src = match std::env::args().nth(1) {
         Some(filename) => std::fs::read_to_string(filename).unwrap(),
         None => {
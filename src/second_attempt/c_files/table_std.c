@@ -64,6 +64,11 @@ void print_value(Value* value) {
         case CLOSURE:
             print("Type: Closure");
             break;
+        case FLOAT:
+            print("Type: Float, Value: ");
+            print_float(value->variant.floating);
+            print("\n");
+            break;
         case BOOLEAN:
             print("Type: Boolean, Value: ");
             print_boolean(value->variant.boolean);
@@ -86,6 +91,13 @@ Value* Number_new(int num) {
     number->ref_count = 1;
     return number;
 }
+Value* Float_new(double num) {
+    Value* number = malloc(sizeof(Value));
+    number->variant.floating = num;
+    number->tag = FLOAT;
+    number->ref_count = 1;
+    return number;
+}
 Value* String_new(const char* str) {
     Value* string = malloc(sizeof(Value));
     string->variant.string = stralloc(str);
@@ -130,6 +142,18 @@ void increment(Value* value) {
     value->ref_count += 1;
 }
 
+// EXCEPTIONS
+    static Value* thrown = NULL;
+    void throw_value(Value* value) {
+        increment(value);
+        thrown = value;
+    }
+    Value* catch_value() {
+        Value* value = thrown;
+        thrown = NULL;
+        return value;
+    }
+
 Value* run_closure(Value* closure) {
     return (*closure->variant.closure->p)(closure->variant.closure->args);
 }
@@ -157,19 +181,68 @@ void run_test() {
 }
 
 // NUMBER
+    bool is_number(Value* value) {
+        return value->tag == NUMBER || value->tag == FLOAT;
+    }
+    double Number_as_float(Value* value) {
+        if(value->tag == NUMBER) {
+            return (double) value->variant.number;
+        }
+        return value->variant.floating;
+    }
+    Value* Float_operation(double rhs, double lhs, enum NumberOperator number_operator) {
+        switch(number_operator) {
+            case ADD:
+                return Float_new(rhs+lhs);
+            case SUBTRACT:
+                return Float_new(rhs-lhs);
+            case MULTIPLY:
+                return Float_new(rhs*lhs);
+            case DIVIDE:
+                return Float_new(rhs/lhs);
+        }
+        return None_new();
+    }
     Value* Number_operation(Value* rhs, Value* lhs, enum NumberOperator number_operator) {
+        if(!is_number(rhs) || !is_number(lhs)) {
+            Value* message = String_new("type error: expected a number");
+            throw_value(message);
+            decrement(message);
+            return NULL;
+        }
+        if(rhs->tag != NUMBER || lhs->tag != NUMBER) {
+            return Float_operation(Number_as_float(rhs), Number_as_float(lhs), number_operator);
+        }
+        int a = rhs->variant.number;
+        int b = lhs->variant.number;
+        int result;
+        bool overflowed = false;
         switch(number_operator) {
             case ADD:
-                return Number_new(rhs->variant.number+lhs->variant.number);
-            break;
+                overflowed = __builtin_add_overflow(a, b, &result);
+                break;
             case SUBTRACT:
-                exception("subtract not implemented yet");
-            break;
+                overflowed = __builtin_sub_overflow(a, b, &result);
+                break;
             case MULTIPLY:
-                exception("multiply not implemented yet");
-            break;
+                overflowed = __builtin_mul_overflow(a, b, &result);
+                break;
             case DIVIDE:
-                exception("divide not implemented yet");
-            break;
+                if(b == 0) {
+                    Value* message = String_new("integer division by zero");
+                    throw_value(message);
+                    decrement(message);
+                    return NULL;
+                }
+                // INT_MIN / -1 is the only integer division that overflows
+                overflowed = b == -1 && a == (-2147483647 - 1);
+                if(!overflowed) {
+                    result = a / b;
+                }
+                break;
+        }
+        if(overflowed) {
+            return Float_operation((double) a, (double) b, number_operator);
         }
-    }
\ No newline at end of file
+        return Number_new(result);
+    }
@@ -1,6 +1,7 @@
 #pragma once
 __attribute__((import_module("host"), import_name("print"))) void print(char*);
 __attribute__((import_module("host"), import_name("print_num"))) void print_num(int);
+__attribute__((import_module("host"), import_name("print_float"))) void print_float(double);
 __attribute__((import_module("host"), import_name("print_boolean"))) void print_boolean(int);
 __attribute__((import_module("host"), import_name("exception"))) void exception(char*);
 #include "shared_std.h"
@@ -12,6 +13,7 @@ char* strcpy(char* destination, const char* source);
 
 void print(char*);
 void print_num(int);
+void print_float(double);
 void print_boolean(bool boolean);
 void exception(char*);
 
@@ -29,11 +31,13 @@ typedef enum TYPE_TAG {
     NUMBER,
     CLOSURE,
     BOOLEAN,
+    FLOAT,
 } TypeTag;
 
 typedef union TYPE_VARIANT {
     char* string;
     int number;
+    double floating;
     Closure* closure;
     bool boolean;
 } TypeVariant;
@@ -46,6 +50,7 @@ typedef struct VALUE {
 
 
 Value* Number_new(int num);
+Value* Float_new(double num);
 Value* String_new(const char* str);
 Value* Closure_new(Closure closure);
 Value* Boolean_new(bool boolean);
@@ -58,6 +63,12 @@ void increment(Value* value);
 
 void print_value(Value* value);
 
+// Exceptions
+    // a function that throws calls throw_value and returns NULL, callers check for NULL and unwind.
+    // throw_value takes its own reference to the value, catch_value hands that reference to the catcher.
+    void throw_value(Value* value);
+    Value* catch_value();
+
 Value* run_closure(Value* closure);
 
 Value test();
@@ -65,10 +76,16 @@ Value test();
 void run_test();
 
 // Number
+    // NUMBER op NUMBER stays a NUMBER unless it overflows, anything involving a FLOAT becomes a FLOAT.
+    // NUMBER / NUMBER truncates towards zero, dividing by zero throws a STRING and returns NULL.
+    // so does an operand that isn't a NUMBER or a FLOAT, Number_as_float only takes those two.
     enum NumberOperator {
         ADD,
         SUBTRACT,
         DIVIDE,
         MULTIPLY
     };
+    bool is_number(Value* value);
+    double Number_as_float(Value* value);
+    Value* Float_operation(double rhs, double lhs, enum NumberOperator number_operator);
     Value* Number_operation(Value* rhs, Value* lhs, enum NumberOperator number_operator);
\ No newline at end of file
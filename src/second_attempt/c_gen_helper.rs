@@ -5,6 +5,9 @@ pub type TIdentifier = String;
 pub type CIdentifier = String;
 pub type Buffer = String;
 
+// the wasm module imported functions come from, the runtime's own imports are in "host"
+pub const IMPORT_MODULE: &str = "imports";
+
 pub fn generate_inline_identifier(level: u32, num_local: &mut u32) -> CIdentifier {
     let name = format!("_inline_{}_{}", level, num_local);
     use std::ops::AddAssign;
@@ -23,6 +26,19 @@ pub fn generate_binary_operation(identifier: CIdentifier, lhs: Buffer, rhs: Buff
 pub fn generate_function_def(identifier: CIdentifier, args: Buffer, fn_body: Buffer) -> Buffer {
     format!("Value* {}({}){{{}}}", identifier, args, fn_body)
 }
+pub fn generate_import_identifier(fn_name: TIdentifier) -> CIdentifier {
+    format!("_import_{}", fn_name)
+}
+// the host gets the arguments as an array and hands back a new value, or NULL once it's thrown one
+pub fn generate_import_declaration(identifier: CIdentifier, fn_name: TIdentifier) -> Buffer {
+    format!("__attribute__((import_module(\"{}\"), import_name(\"{}\"))) Value* {}(Value** arguments, int count);", IMPORT_MODULE, fn_name, identifier)
+}
+pub fn generate_import_shim_body(import_identifier: CIdentifier, args: Vec<CIdentifier>) -> Buffer {
+    if args.is_empty() {
+        return format!("return {}(NULL, 0);", import_identifier);
+    }
+    format!("Value* arguments[{}] = {{{}}};return {}(arguments, {});", args.len(), args.join(", "), import_identifier, args.len())
+}
 pub fn generate_function_header(identifier: CIdentifier, args: Buffer) -> Buffer {
     format!("Value* {}({});", identifier, args)
 }
@@ -35,9 +51,30 @@ pub fn generate_decrement(identifier: CIdentifier) -> Buffer {
 pub fn generate_return_line(identifier: CIdentifier) -> Buffer {
     format!("return({});", identifier)
 }
+pub fn generate_exception_check(identifier: CIdentifier, unwind: Buffer) -> Buffer {
+    format!("if({} == NULL){{{}}}", identifier, unwind)
+}
+pub fn generate_throw(identifier: CIdentifier) -> Buffer {
+    format!("throw_value({});", identifier)
+}
+pub fn generate_catch_jump(label: u32) -> Buffer {
+    format!("goto _catch_{};", label)
+}
+// the try body falls through to here and skips the catch
+pub fn generate_catch_label(label: u32) -> Buffer {
+    format!("goto _try_end_{};_catch_{}:;", label, label)
+}
+pub fn generate_try_end_label(label: u32) -> Buffer {
+    format!("_try_end_{}:;", label)
+}
 pub fn generate_value_new(value: ir::Value) -> Buffer {
     match value {
-        Value::Number(number) => format!("Number_new({})", number as i32),
+        // the runtime's integers are 32 bit, anything bigger gets promoted like an overflow would
+        Value::Integer(number) if number >= i32::MIN as i64 && number <= i32::MAX as i64 => {
+            format!("Number_new({})", number)
+        }
+        Value::Integer(number) => format!("Float_new({:?})", number as f64),
+        Value::Float(number) => format!("Float_new({:?})", number),
         Value::String(string) => format!("String_new(\"{}\")", string),
         Value::Table(_) => unimplemented!()
     }
@@ -0,0 +1,440 @@
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use chumsky::{Parser, Stream};
+use crate::second_attempt::ir::File;
+use crate::second_attempt::lexer::lexer;
+use crate::second_attempt::isolate::{self, Isolate};
+use crate::second_attempt::parser;
+use crate::second_attempt::pretty::Layout;
+use crate::second_attempt::vm2::{Chunk, Vm};
+use crate::second_attempt::vm2::compiler::{compile, CompileError};
+use crate::second_attempt::vm2::coroutine::CoroutineState;
+use crate::second_attempt::vm2::limits::Limits;
+use crate::second_attempt::vm2::reload::ReloadReport;
+use crate::second_attempt::vm2::misc::{Table, TableKey, Value};
+use crate::second_attempt::vm_error::{VmError, VmErrorKind};
+
+/*
+Everything a Rust program needs to run Table code, on top of a `vm2::Vm`.
+
+    let mut engine = Engine::new();
+    engine.register_fn("double", |x: i64| x * 2);
+    engine.eval("export fn twice(x) capture { double(x) } let answer = twice(21);")?;
+    let answer: i64 = engine.get_global("answer")?;
+    let again: i64 = engine.call("twice", (4,))?;
+
+Tables, strings and functions are handles into the engine's vm, so converting them to and from Rust
+goes through the engine. These natives are registered up front and can be replaced like any other:
+
+    print(values...)        prints the values on one line
+    compile(source)         compiles source into a table with `constants` and `instructions`
+    inject(table)           runs a table like the one `compile` gives and returns its result
+    disassemble(function)   the chunk of a Table function as the same kind of table
+    deep_equal(a, b)        whether two values are equal all the way down, `==` only compares
+                            tables by reference
+    to_string(value, [indented])
+                            the value as `print` writes it, spread over lines with `indented`
+    resume(coroutine, value)
+                            sends the value to a coroutine, returns what it yields next or its
+                            result once it finishes
+    finished(coroutine)     whether a coroutine has finished, resuming it again is an error
+    spawn, send, receive    run functions in parallel, see `isolate`
+
+A table from `compile` or `disassemble` can be edited like any other before it's injected, the
+bytes are verified first so a broken chunk is an error rather than a crash. Compiled code shares
+the engine's globals, a `let` or `fn` at its top level defines one.
+ */
+#[derive(Debug)]
+pub enum EngineError {
+    // the lexer's and parser's messages
+    Parse(Vec<String>),
+    Compile(CompileError),
+    Runtime(VmError),
+    UndefinedGlobal(String),
+    NotExported(String),
+    // converting between a Rust type and a Table value
+    Conversion(VmErrorKind),
+    // spawning, messaging or joining another isolate
+    Isolate(String),
+}
+impl Display for EngineError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EngineError::Parse(messages) => write!(f, "parse error: {}", messages.join(", ")),
+            EngineError::Compile(err) => write!(f, "compile error: {}", err),
+            EngineError::Runtime(err) => write!(f, "runtime error: {}", err),
+            EngineError::UndefinedGlobal(name) => write!(f, "undefined global `{}`", name),
+            EngineError::NotExported(name) => write!(f, "`{}` isn't an exported function", name),
+            EngineError::Conversion(err) => write!(f, "conversion error: {}", err),
+            EngineError::Isolate(err) => write!(f, "isolate error: {}", err),
+        }
+    }
+}
+impl std::error::Error for EngineError {}
+impl From<CompileError> for EngineError {
+    fn from(err: CompileError) -> Self {
+        EngineError::Compile(err)
+    }
+}
+impl From<VmError> for EngineError {
+    fn from(err: VmError) -> Self {
+        EngineError::Runtime(err)
+    }
+}
+
+pub struct Engine {
+    vm: Vm,
+    pub(super) isolate: Arc<Isolate>,
+}
+impl Engine {
+    pub fn new() -> Self {
+        Self::with_isolate(Isolate::root())
+    }
+    pub(super) fn with_isolate(isolate: Arc<Isolate>) -> Self {
+        let mut engine = Self {
+            vm: Vm::default(),
+            isolate,
+        };
+        engine.register("print", |vm, arguments| {
+            let line = arguments.iter().map(|value| vm.describe(*value)).collect::<Vec<_>>().join(" ");
+            println!("{}", line);
+            Ok(Value::Nil)
+        });
+        engine.register("compile", |vm, arguments| {
+            let [source] = arguments else {
+                return Err(VmErrorKind::ArityMismatch { expected: 1, found: arguments.len() });
+            };
+            let source = vm.string(*source)?.to_string();
+            let chunk = compile_source(source.as_str(), vm).map_err(|err| VmErrorKind::Native(err.to_string()))?;
+            vm.chunk_to_table(&chunk)
+        });
+        engine.register("inject", |vm, arguments| {
+            let [table] = arguments else {
+                return Err(VmErrorKind::ArityMismatch { expected: 1, found: arguments.len() });
+            };
+            let chunk = vm.table_to_chunk(Vm::table_handle(*table)?)?;
+            vm.run_chunk(chunk).map_err(|err| err.kind)
+        });
+        engine.register("disassemble", |vm, arguments| {
+            let [function] = arguments else {
+                return Err(VmErrorKind::ArityMismatch { expected: 1, found: arguments.len() });
+            };
+            let handle = Vm::table_handle(*function)?;
+            let chunk = match vm.get_tables().get(handle) {
+                Some(Table::Function(function)) => function.chunk.clone(),
+                Some(Table::Native(native)) => return Err(VmErrorKind::Native(format!("`{}` is a native function and has no chunk", native.name))),
+                Some(table) => return Err(VmErrorKind::TypeError { expected: "function", found: table.type_name() }),
+                None => return Err(VmErrorKind::BadTableHandle(handle)),
+            };
+            vm.chunk_to_table(&chunk)
+        });
+        engine.register("deep_equal", |vm, arguments| {
+            let [lhs, rhs] = arguments else {
+                return Err(VmErrorKind::ArityMismatch { expected: 2, found: arguments.len() });
+            };
+            Ok(Value::Boolean(vm.deep_equal(*lhs, *rhs)))
+        });
+        engine.register("to_string", |vm, arguments| {
+            let (value, layout) = match arguments {
+                [value] => (*value, Layout::Compact),
+                [value, indented] if indented.get_truthy() => (*value, Layout::Indented),
+                [value, _] => (*value, Layout::Compact),
+                _ => return Err(VmErrorKind::ArityMismatch { expected: 1, found: arguments.len() }),
+            };
+            let string = vm.format_value(value, layout);
+            vm.alloc_string(string)
+        });
+        engine.register("resume", |vm, arguments| {
+            let [coroutine, sent] = arguments else {
+                return Err(VmErrorKind::ArityMismatch { expected: 2, found: arguments.len() });
+            };
+            let (value, _) = vm.resume_coroutine(*coroutine, *sent).map_err(|err| err.kind)?;
+            Ok(value)
+        });
+        engine.register("finished", |vm, arguments| {
+            let [coroutine] = arguments else {
+                return Err(VmErrorKind::ArityMismatch { expected: 1, found: arguments.len() });
+            };
+            let handle = Vm::table_handle(*coroutine)?;
+            match vm.get_tables().get(handle) {
+                Some(Table::Coroutine(coroutine)) => Ok(Value::Boolean(coroutine.get_state() == CoroutineState::Finished)),
+                Some(table) => Err(VmErrorKind::TypeError { expected: "coroutine", found: table.type_name() }),
+                None => Err(VmErrorKind::BadTableHandle(handle)),
+            }
+        });
+        isolate::register_natives(&mut engine);
+        engine
+    }
+    // see `vm2::limits`, the limits apply to everything the engine runs from now on
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.vm.set_limits(limits);
+    }
+    pub fn vm(&self) -> &Vm {
+        &self.vm
+    }
+    pub fn vm_mut(&mut self) -> &mut Vm {
+        &mut self.vm
+    }
+    // compiles source into a chunk for this engine, nothing runs until it's passed to `run`
+    pub fn compile(&mut self, source: &str) -> Result<Chunk, EngineError> {
+        compile_source(source, &mut self.vm)
+    }
+    // runs a chunk from `compile` to the end and returns its value
+    pub fn run(&mut self, chunk: Chunk) -> Result<Value, EngineError> {
+        self.vm.load(chunk).map_err(|err| EngineError::Compile(CompileError::Invalid(err)))?;
+        let result = self.vm.run();
+        if result.is_err() {
+            self.vm.reset();
+        }
+        Ok(result?)
+    }
+    pub fn eval(&mut self, source: &str) -> Result<Value, EngineError> {
+        let chunk = self.compile(source)?;
+        self.run(chunk)
+    }
+    // swaps in the functions from a new version of source that was already run, see `vm2::reload`
+    pub fn reload(&mut self, source: &str) -> Result<ReloadReport, EngineError> {
+        Ok(self.vm.reload(parse_source(source)?)?)
+    }
+    // registers a native function as a global, it gets the vm so it can read and allocate tables
+    pub fn register<F>(&mut self, name: &str, function: F)
+        where F: Fn(&mut Vm, &[Value]) -> Result<Value, VmErrorKind> + Send + Sync + 'static {
+        self.vm.define_native(name, Arc::new(function));
+    }
+    // like `register` but arguments and the result are converted from and to Rust types
+    pub fn register_fn<Args, F: NativeFn<Args>>(&mut self, name: &str, function: F) {
+        self.vm.define_native(name, function.into_native());
+    }
+    // calls a function that was defined with `export fn`
+    pub fn call<A: IntoArgs, R: FromValue>(&mut self, name: &str, args: A) -> Result<R, EngineError> {
+        let function = self.vm.get_global(name).ok_or_else(|| EngineError::UndefinedGlobal(name.to_string()))?;
+        let exported = match function {
+            Value::Table(handle) => matches!(self.vm.get_tables().get(handle), Some(Table::Function(function)) if function.exported),
+            _ => false,
+        };
+        if !exported {
+            return Err(EngineError::NotExported(name.to_string()));
+        }
+        let args = args.into_args(&mut self.vm).map_err(EngineError::Conversion)?;
+        let result = self.vm.call_function(function, &args);
+        if result.is_err() {
+            self.vm.reset();
+        }
+        R::from_value(result?, &self.vm).map_err(EngineError::Conversion)
+    }
+    // resumes a coroutine with a value and runs it until it yields again or finishes, gives back
+    // what it yielded or returned and whether it finished
+    pub fn resume<S: IntoValue, R: FromValue>(&mut self, coroutine: Value, sent: S) -> Result<(R, bool), EngineError> {
+        let sent = sent.into_value(&mut self.vm).map_err(EngineError::Conversion)?;
+        let result = self.vm.resume_coroutine(coroutine, sent);
+        if result.is_err() {
+            self.vm.reset();
+        }
+        let (value, finished) = result?;
+        Ok((R::from_value(value, &self.vm).map_err(EngineError::Conversion)?, finished))
+    }
+    pub fn get_global<T: FromValue>(&self, name: &str) -> Result<T, EngineError> {
+        let value = self.vm.get_global(name).ok_or_else(|| EngineError::UndefinedGlobal(name.to_string()))?;
+        T::from_value(value, &self.vm).map_err(EngineError::Conversion)
+    }
+    pub fn set_global<T: IntoValue>(&mut self, name: &str, value: T) -> Result<(), EngineError> {
+        let value = value.into_value(&mut self.vm).map_err(EngineError::Conversion)?;
+        self.vm.set_global(name, value);
+        Ok(())
+    }
+}
+impl Default for Engine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+// lexes, parses and compiles source against a vm, shared by `Engine::compile` and the `compile` native
+fn compile_source(source: &str, vm: &mut Vm) -> Result<Chunk, EngineError> {
+    Ok(compile(parse_source(source)?, vm)?)
+}
+fn parse_source(source: &str) -> Result<File, EngineError> {
+    let (tokens, lex_errors) = lexer().parse_recovery(source);
+    let mut messages = lex_errors.iter().map(|err| err.to_string()).collect::<Vec<_>>();
+    let Some(tokens) = tokens else {
+        return Err(EngineError::Parse(messages));
+    };
+    let len = source.chars().count();
+    let (file, parse_errors) = parser::parse().parse_recovery(Stream::from_iter(len..len + 1, tokens.into_iter()));
+    messages.extend(parse_errors.iter().map(|err| err.to_string()));
+    match file {
+        Some(file) if messages.is_empty() => Ok(file),
+        _ => Err(EngineError::Parse(messages)),
+    }
+}
+
+// Rust values that can become Table values, strings and vectors are allocated in the vm so they
+// can go over its table limits
+pub trait IntoValue {
+    fn into_value(self, vm: &mut Vm) -> Result<Value, VmErrorKind>;
+}
+// Table values that can be read back as Rust values
+pub trait FromValue: Sized {
+    fn from_value(value: Value, vm: &Vm) -> Result<Self, VmErrorKind>;
+}
+impl IntoValue for Value {
+    fn into_value(self, _vm: &mut Vm) -> Result<Value, VmErrorKind> {
+        Ok(self)
+    }
+}
+impl FromValue for Value {
+    fn from_value(value: Value, _vm: &Vm) -> Result<Self, VmErrorKind> {
+        Ok(value)
+    }
+}
+impl IntoValue for () {
+    fn into_value(self, _vm: &mut Vm) -> Result<Value, VmErrorKind> {
+        Ok(Value::Nil)
+    }
+}
+impl FromValue for () {
+    fn from_value(_value: Value, _vm: &Vm) -> Result<Self, VmErrorKind> {
+        Ok(())
+    }
+}
+impl IntoValue for i64 {
+    fn into_value(self, _vm: &mut Vm) -> Result<Value, VmErrorKind> {
+        Ok(Value::Number(self))
+    }
+}
+impl FromValue for i64 {
+    fn from_value(value: Value, _vm: &Vm) -> Result<Self, VmErrorKind> {
+        value.get_number().ok_or(VmErrorKind::TypeError { expected: "number", found: value.type_name() })
+    }
+}
+impl IntoValue for f64 {
+    fn into_value(self, _vm: &mut Vm) -> Result<Value, VmErrorKind> {
+        Ok(Value::Float(self))
+    }
+}
+// integers are widened, like they are in arithmetic
+impl FromValue for f64 {
+    fn from_value(value: Value, _vm: &Vm) -> Result<Self, VmErrorKind> {
+        match value {
+            Value::Float(num) => Ok(num),
+            Value::Number(num) => Ok(num as f64),
+            value => Err(VmErrorKind::TypeError { expected: "float", found: value.type_name() }),
+        }
+    }
+}
+impl IntoValue for bool {
+    fn into_value(self, _vm: &mut Vm) -> Result<Value, VmErrorKind> {
+        Ok(Value::Boolean(self))
+    }
+}
+impl FromValue for bool {
+    fn from_value(value: Value, _vm: &Vm) -> Result<Self, VmErrorKind> {
+        match value {
+            Value::Boolean(bool) => Ok(bool),
+            value => Err(VmErrorKind::TypeError { expected: "boolean", found: value.type_name() }),
+        }
+    }
+}
+impl IntoValue for String {
+    fn into_value(self, vm: &mut Vm) -> Result<Value, VmErrorKind> {
+        vm.alloc_string(self)
+    }
+}
+impl IntoValue for &str {
+    fn into_value(self, vm: &mut Vm) -> Result<Value, VmErrorKind> {
+        vm.alloc_string(self.to_string())
+    }
+}
+impl FromValue for String {
+    fn from_value(value: Value, vm: &Vm) -> Result<Self, VmErrorKind> {
+        vm.string(value).map(|string| string.to_string())
+    }
+}
+// nil is None
+impl<T: IntoValue> IntoValue for Option<T> {
+    fn into_value(self, vm: &mut Vm) -> Result<Value, VmErrorKind> {
+        match self {
+            Some(value) => value.into_value(vm),
+            None => Ok(Value::Nil),
+        }
+    }
+}
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(value: Value, vm: &Vm) -> Result<Self, VmErrorKind> {
+        match value {
+            Value::Nil => Ok(None),
+            value => T::from_value(value, vm).map(Some),
+        }
+    }
+}
+// vectors are tables with positional entries
+impl<T: IntoValue> IntoValue for Vec<T> {
+    fn into_value(self, vm: &mut Vm) -> Result<Value, VmErrorKind> {
+        let entries = self.into_iter().enumerate()
+            .map(|(index, value)| Ok((TableKey::NoIdentifier(index), value.into_value(vm)?)))
+            .collect::<Result<_, VmErrorKind>>()?;
+        vm.alloc_table(entries)
+    }
+}
+impl<T: FromValue> FromValue for Vec<T> {
+    fn from_value(value: Value, vm: &Vm) -> Result<Self, VmErrorKind> {
+        let handle = match value {
+            Value::Table(handle) => handle,
+            value => return Err(VmErrorKind::TypeError { expected: "table", found: value.type_name() }),
+        };
+        match vm.get_tables().get(handle) {
+            Some(Table::Map(map)) => map.iter()
+                .filter(|(key, _)| matches!(key, TableKey::NoIdentifier(_)))
+                .map(|(_, value)| T::from_value(*value, vm))
+                .collect(),
+            Some(Table::Array(values)) => values.iter().map(|value| T::from_value(*value, vm)).collect(),
+            Some(table) => Err(VmErrorKind::TypeError { expected: "table", found: table.type_name() }),
+            None => Err(VmErrorKind::BadTableHandle(handle)),
+        }
+    }
+}
+
+// the arguments to `Engine::call`, a tuple of things that convert to values or a slice of values
+pub trait IntoArgs {
+    fn into_args(self, vm: &mut Vm) -> Result<Vec<Value>, VmErrorKind>;
+}
+impl IntoArgs for &[Value] {
+    fn into_args(self, _vm: &mut Vm) -> Result<Vec<Value>, VmErrorKind> {
+        Ok(self.to_vec())
+    }
+}
+// a Rust closure `register_fn` can wrap, `Args` is the tuple of its argument types
+pub trait NativeFn<Args> {
+    fn into_native(self) -> crate::second_attempt::vm2::function::NativeFunction;
+}
+macro_rules! native_fn {
+    ($count:literal $(, $arg:ident)*) => {
+        impl<$($arg: IntoValue,)*> IntoArgs for ($($arg,)*) {
+            #[allow(non_snake_case, unused_variables)]
+            fn into_args(self, vm: &mut Vm) -> Result<Vec<Value>, VmErrorKind> {
+                let ($($arg,)*) = self;
+                Ok(vec![$($arg.into_value(vm)?),*])
+            }
+        }
+        impl<Function, Return, $($arg,)*> NativeFn<($($arg,)*)> for Function
+            where Function: Fn($($arg),*) -> Return + Send + Sync + 'static,
+                  Return: IntoValue,
+                  $($arg: FromValue,)* {
+            #[allow(non_snake_case, unused_variables, unused_mut)]
+            fn into_native(self) -> crate::second_attempt::vm2::function::NativeFunction {
+                Arc::new(move |vm: &mut Vm, arguments: &[Value]| {
+                    if arguments.len() != $count {
+                        return Err(VmErrorKind::ArityMismatch { expected: $count, found: arguments.len() });
+                    }
+                    let mut arguments = arguments.iter();
+                    $(let $arg = $arg::from_value(*arguments.next().unwrap(), vm)?;)*
+                    self($($arg),*).into_value(vm)
+                })
+            }
+        }
+    };
+}
+native_fn!(0);
+native_fn!(1, A);
+native_fn!(2, A, B);
+native_fn!(3, A, B, C);
+native_fn!(4, A, B, C, D);
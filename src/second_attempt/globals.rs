@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+use crate::second_attempt::vm_error::VmErrorKind;
+
+/*
+The globals table the vms share, generic over each vm's own value type. Names are interned once into
+a small number, so bytecode can carry that number (or resolve a name to it once) and a global is
+then just an index into `values`.
+
+A name can be interned without being defined, say by code that reads a global the host hasn't set
+yet, so reading or assigning it is an `UndefinedGlobal` error until something defines it.
+ */
+pub struct Globals<V> {
+    ids: HashMap<String, usize>,
+    names: Vec<String>,
+    values: Vec<Option<V>>,
+}
+impl<V> Default for Globals<V> {
+    fn default() -> Self {
+        Self {
+            ids: HashMap::new(),
+            names: vec![],
+            values: vec![],
+        }
+    }
+}
+impl<V: Copy> Globals<V> {
+    pub fn intern(&mut self, name: &str) -> usize {
+        if let Some(id) = self.ids.get(name) {
+            return *id;
+        }
+        let id = self.names.len();
+        self.ids.insert(name.to_string(), id);
+        self.names.push(name.to_string());
+        self.values.push(None);
+        id
+    }
+    // the id of a name that's been interned, without interning it
+    pub fn lookup(&self, name: &str) -> Option<usize> {
+        self.ids.get(name).copied()
+    }
+    pub fn name(&self, id: usize) -> &str {
+        self.names[id].as_str()
+    }
+    pub fn get(&self, id: usize) -> Result<V, VmErrorKind> {
+        self.values.get(id).copied().flatten().ok_or_else(|| self.undefined(id))
+    }
+    // assigns a global that's already defined
+    pub fn set(&mut self, id: usize, value: V) -> Result<(), VmErrorKind> {
+        match self.values.get_mut(id) {
+            Some(slot @ Some(_)) => {
+                *slot = Some(value);
+                Ok(())
+            }
+            _ => Err(self.undefined(id)),
+        }
+    }
+    // only fails for an id that was never interned
+    pub fn define(&mut self, id: usize, value: V) -> Result<(), VmErrorKind> {
+        let slot = self.values.get_mut(id).ok_or_else(|| VmErrorKind::UndefinedGlobal(format!("#{}", id)))?;
+        *slot = Some(value);
+        Ok(())
+    }
+    pub fn get_by_name(&self, name: &str) -> Option<V> {
+        self.lookup(name).and_then(|id| self.values[id])
+    }
+    // defines the global if it isn't already, for the host setting things up before a run
+    pub fn set_by_name(&mut self, name: &str, value: V) {
+        let id = self.intern(name);
+        self.values[id] = Some(value);
+    }
+    // every defined global, in the order the names were interned
+    pub fn iter(&self) -> impl Iterator<Item = (&str, V)> + '_ {
+        self.names.iter().zip(&self.values).filter_map(|(name, value)| Some((name.as_str(), (*value)?)))
+    }
+    pub fn len(&self) -> usize {
+        self.values.iter().flatten().count()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    // bytecode built by hand can name an id that was never interned
+    fn undefined(&self, id: usize) -> VmErrorKind {
+        VmErrorKind::UndefinedGlobal(self.names.get(id).cloned().unwrap_or_else(|| format!("#{}", id)))
+    }
+}
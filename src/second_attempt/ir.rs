@@ -10,6 +10,8 @@ pub enum Exp {
     Value(Value),
     Variable(Identifier),
     Block(Vec<BStatement>, BExp),
+    // hands a value to whoever resumed the function's coroutine, evaluates to what they send back
+    Yield(BExp),
 }
 #[derive(Debug)]
 pub enum BinaryOperator {
@@ -32,10 +34,13 @@ pub struct BinaryOperation {
 pub struct FnCall {
     pub(crate) identifier: Identifier,
     pub(crate) args: Vec<BExp>,
+    // where the call is in the source, from the callee's name to the closing paren
+    pub(crate) span: lexer::Span,
 }
 #[derive(Debug)]
 pub enum Value {
-    Number(f64),
+    Integer(i64),
+    Float(f64),
     String(String),
     Table(Table),
 }
@@ -57,11 +62,29 @@ pub enum Statement {
     LetStatement(LetStatement),
     ExpStatement(BExp),
     Block(Vec<BStatement>),
+    TryCatch(TryCatch),
+    Throw(BExp),
+    For(ForLoop),
+}
+// try { body } catch identifier { handler }
+#[derive(Debug)]
+pub struct TryCatch {
+    pub body: Vec<BStatement>,
+    pub identifier: Identifier,
+    pub handler: Vec<BStatement>,
+}
+// for identifier in exp { body }, runs the body once for every value the coroutine yields
+#[derive(Debug)]
+pub struct ForLoop {
+    pub identifier: Identifier,
+    pub exp: BExp,
+    pub body: Vec<BStatement>,
 }
 #[derive(Debug)]
 pub struct LetStatement {
     pub identifier: Identifier,
     pub exp: BExp,
+    pub span: lexer::Span,
 }
 #[derive(Debug)]
 pub struct NormalFnDef {
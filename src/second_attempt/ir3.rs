@@ -1,13 +1,16 @@
+use std::fmt::{Display, Formatter};
 use chumsky::chain::Chain;
 use lang_c::ast::Identifier;
 use crate::second_attempt::c_gen_helper::*;
 use crate::second_attempt::ir;
-use crate::second_attempt::ir::{Block, Exp, File, FnCall, FnDef, LetStatement, NormalFnDef, Statement, Value};
+use crate::second_attempt::ir::{Block, Exp, File, FnCall, FnDef, ImportedFnDef, LetStatement, NormalFnDef, Statement, TryCatch, Value};
 
 #[derive(Debug, Default)]
 pub struct Scope {
     var_declare: Buffer,
     var_in_scope: Vec<CIdentifier>,
+    // variables this scope decrements when it ends, in declaration order
+    owned: Vec<CIdentifier>,
     buffer: Buffer,
     var_increment: Buffer,
     var_decrement: Buffer,
@@ -33,22 +36,32 @@ impl Scope {
     }
     pub fn gen_buffer(self) -> Buffer {
         match self {
-            Scope { var_declare, buffer, var_increment, var_decrement, end_buffer, num_local, var_in_scope } => {
+            Scope { var_declare, buffer, var_increment, var_decrement, end_buffer, num_local, var_in_scope, owned } => {
                 format!("{}{}{}{}{}", var_declare, buffer, var_increment, var_decrement, end_buffer)
             }
         }
     }
 }
+/*
+Exceptions don't use setjmp. A function that throws stores the value with `throw_value` and returns NULL,
+so after every call we check for NULL and unwind: decrement everything declared so far, then either
+jump to the catch of the innermost `try` or return NULL ourselves.
+ */
 #[derive(Debug)]
 pub struct ScopeHolder {
     scopes: Vec<Scope>,
     stack: Vec<CIdentifier>,
+    // (label, index of the try body's scope) for every try we're inside of
+    handlers: Vec<(u32, usize)>,
+    num_try: u32,
 }
 impl ScopeHolder {
     pub fn new() -> Self {
         let mut this = Self {
             scopes: vec![],
-            stack: vec![]
+            stack: vec![],
+            handlers: vec![],
+            num_try: 0,
         };
         this.push_scope();
         this
@@ -79,6 +92,7 @@ impl ScopeHolder {
         self.get_mut().var_increment(generate_increment(identifier))
     }
     pub fn var_decrement(&mut self, identifier: CIdentifier) {
+        self.get_mut().owned.push(identifier.clone());
         self.get_mut().var_decrement(generate_decrement(identifier))
     }
     pub fn var_declaration(&mut self, identifier: CIdentifier, rhs: Buffer) {
@@ -88,6 +102,39 @@ impl ScopeHolder {
     pub fn push_buffer(&mut self, buffer: Buffer) {
         self.get_mut().buffer(buffer);
     }
+    // code that has to run in order with the declarations around it
+    pub fn push_declare(&mut self, buffer: Buffer) {
+        self.get_mut().var_declare.push_str(buffer.as_str());
+    }
+    // like pop_scope but the child stays where it is in the parent, in its own braces
+    pub fn pop_scope_inline(&mut self) {
+        let child = self.scopes.pop().unwrap();
+        self.push_declare(format!("{{{}}}", child.gen_buffer()));
+    }
+    pub fn push_handler(&mut self) -> u32 {
+        let label = self.num_try;
+        self.num_try += 1;
+        self.handlers.push((label, self.scopes.len() - 1));
+        label
+    }
+    pub fn pop_handler(&mut self) {
+        self.handlers.pop();
+    }
+    // what to do once something has been thrown
+    pub fn generate_unwind(&self) -> Buffer {
+        let (first_scope, exit) = match self.handlers.last() {
+            Some((label, scope_index)) => (*scope_index, generate_catch_jump(*label)),
+            None => (0, "return NULL;".to_string()),
+        };
+        let mut buffer = Buffer::default();
+        for scope in self.scopes[first_scope..].iter().rev() {
+            for identifier in scope.owned.iter().rev() {
+                buffer.push_str(generate_decrement(identifier.clone()).as_str());
+            }
+        }
+        buffer.push_str(exit.as_str());
+        buffer
+    }
     pub fn push_end_buffer(&mut self, buffer: Buffer) {
         self.get_mut().end_buffer(buffer);
     }
@@ -117,6 +164,20 @@ impl ScopeHolder {
     }
 }
 
+#[derive(Clone, Debug, PartialEq)]
+pub enum TranspileError {
+    // coroutines have no C runtime support yet, the vm2 compiler is the only one that takes them
+    Unsupported(&'static str),
+}
+impl Display for TranspileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TranspileError::Unsupported(feature) => write!(f, "the C backend doesn't support {} yet", feature),
+        }
+    }
+}
+impl std::error::Error for TranspileError {}
+
 pub type CFnHeader = String;
 pub type CFnDef = String;
 #[derive(Default)]
@@ -125,19 +186,19 @@ pub struct TranslationUnit {
     c_fn_defs: Vec<CFnDef>,
 }
 impl TranslationUnit {
-    pub fn gen_from_file(file: ir::File) -> Buffer {
+    pub fn gen_from_file(file: ir::File) -> Result<Buffer, TranspileError> {
         let mut scope = ScopeHolder::new();
         let mut this = Self::default();
         match file {
             File::Block(block) => {
-                this.unscoped_block(&mut scope, block)
+                this.unscoped_block(&mut scope, block)?
             }
             File::None => {}
         };
         let fn_body = scope.generate_string();
         this.c_fn_headers.push("Value* _main();".to_string());
         this.c_fn_defs.push(format!("Value* _main(){{{}}}", fn_body));
-        this.generate_string()
+        Ok(this.generate_string())
     }
     fn generate_string(mut self) -> Buffer {
         let mut buffer = Buffer::default();
@@ -150,18 +211,19 @@ impl TranslationUnit {
         buffer
     }
     /// this one should be used by all but our main function
-    fn block(&mut self, scope: &mut ScopeHolder, block: ir::Block) {
+    fn block(&mut self, scope: &mut ScopeHolder, block: ir::Block) -> Result<(), TranspileError> {
         scope.push_scope();
-        self.unscoped_block(scope, block);
+        self.unscoped_block(scope, block)?;
         scope.pop_scope();
+        Ok(())
     }
-    fn unscoped_block(&mut self, scope: &mut ScopeHolder, block: ir::Block) {
+    fn unscoped_block(&mut self, scope: &mut ScopeHolder, block: ir::Block) -> Result<(), TranspileError> {
         match block {
             Block::WithExp(statements, exp) => {
                 for statement in statements {
-                    self.statement(scope, *statement);
+                    self.statement(scope, *statement)?;
                 }
-                self.expression(scope, *exp);
+                self.expression(scope, *exp)?;
                 let last_var = scope.stack.last().unwrap().clone();
                 scope.var_increment(last_var.clone());
                 if scope.len() == 1 {
@@ -171,34 +233,37 @@ impl TranslationUnit {
             }
             Block::WithoutExp(statements) => {
                 for statement in statements {
-                    self.statement(scope, *statement);
+                    self.statement(scope, *statement)?;
                 }
                 if scope.len() == 1 {
                     scope.push_end_buffer("return None();".to_string());
                 }
             }
         }
+        Ok(())
     }
-    fn expression(&mut self, scope: &mut ScopeHolder, exp: ir::Exp) {
+    fn expression(&mut self, scope: &mut ScopeHolder, exp: ir::Exp) -> Result<(), TranspileError> {
         match exp {
-            Exp::FnCall(fn_call) => self.fn_call(scope, fn_call),
+            Exp::FnCall(fn_call) => self.fn_call(scope, fn_call)?,
             Exp::BinaryOperation(_) => unimplemented!(),
+            Exp::Yield(_) => return Err(TranspileError::Unsupported("`yield`")),
             Exp::Value(value) => self.value(scope, value),
             Exp::Variable(variable) => {
                 let var = scope.find_var_in_scope(variable).unwrap();
                 scope.push_identifier(var);
             },
             Exp::Block(statements, exp) => {
-                self.block(scope, Block::WithExp(statements, exp));
+                self.block(scope, Block::WithExp(statements, exp))?;
             }
         }
+        Ok(())
     }
-    fn fn_call(&mut self, scope: &mut ScopeHolder, fn_call: ir::FnCall) {
+    fn fn_call(&mut self, scope: &mut ScopeHolder, fn_call: ir::FnCall) -> Result<(), TranspileError> {
         match fn_call {
-            FnCall { identifier, args } => {
+            FnCall { identifier, args, .. } => {
                 let arg_len = args.len();
                 for arg in args {
-                    self.expression(scope, *arg);
+                    self.expression(scope, *arg)?;
                 }
                 let inline_ret = scope.generate_inline_identifier();
                 let closure_name = scope.find_var_in_scope(identifier).unwrap();
@@ -207,38 +272,76 @@ impl TranslationUnit {
                     arg_idents.push(scope.pop_identifier());
                 }
                 let buffer = format!("(*{}->variant.closure->p)({})", closure_name.clone(), call_args_to_string(closure_name.clone(),arg_idents));
+                // worked out before the result is declared, a NULL result isn't ours to decrement
+                let unwind = scope.generate_unwind();
                 scope.var_declaration(inline_ret.clone(), buffer);
+                scope.push_declare(generate_exception_check(inline_ret.clone(), unwind));
                 scope.push_identifier(inline_ret);
             }
         }
+        Ok(())
     }
     fn value(&mut self, scope: &mut ScopeHolder, value: ir::Value) {
         let inline_name = scope.generate_inline_identifier();
         scope.var_declaration(inline_name.clone(), generate_value_new(value));
         scope.push_identifier(inline_name);
     }
-    fn statement(&mut self, scope: &mut ScopeHolder, statement: ir::Statement) {
+    fn statement(&mut self, scope: &mut ScopeHolder, statement: ir::Statement) -> Result<(), TranspileError> {
         match statement {
-            Statement::FnDef(fn_def) => self.fn_def(scope, fn_def),
-            Statement::LetStatement(let_statement) => self.let_statement(scope, let_statement),
+            Statement::FnDef(fn_def) => self.fn_def(scope, fn_def)?,
+            Statement::LetStatement(let_statement) => self.let_statement(scope, let_statement)?,
             Statement::ExpStatement(exp_statement) => {
-                self.expression(scope, *exp_statement);
+                self.expression(scope, *exp_statement)?;
                 scope.stack.pop().unwrap();
             },
             Statement::Block(_) => unimplemented!(),
+            // a `for` loop runs a coroutine
+            Statement::For(_) => return Err(TranspileError::Unsupported("`for` loops")),
+            Statement::TryCatch(try_catch) => self.try_catch(scope, try_catch)?,
+            Statement::Throw(exp) => {
+                self.expression(scope, *exp)?;
+                let value = scope.pop_identifier();
+                scope.push_declare(generate_throw(value));
+                scope.push_declare(scope.generate_unwind());
+            }
+        }
+        Ok(())
+    }
+    fn try_catch(&mut self, scope: &mut ScopeHolder, try_catch: TryCatch) -> Result<(), TranspileError> {
+        match try_catch {
+            TryCatch { body, identifier, handler } => {
+                scope.push_scope();
+                let label = scope.push_handler();
+                for statement in body {
+                    self.statement(scope, *statement)?;
+                }
+                scope.pop_handler();
+                scope.pop_scope_inline();
+                scope.push_declare(generate_catch_label(label));
+                scope.push_scope();
+                let c_identifier = scope.generate_variable_identifier(identifier);
+                scope.var_declaration(c_identifier, "catch_value()".to_string());
+                for statement in handler {
+                    self.statement(scope, *statement)?;
+                }
+                scope.pop_scope_inline();
+                scope.push_declare(generate_try_end_label(label));
+            }
         }
+        Ok(())
     }
-    fn let_statement(&mut self, scope: &mut ScopeHolder, let_stmt: LetStatement) {
+    fn let_statement(&mut self, scope: &mut ScopeHolder, let_stmt: LetStatement) -> Result<(), TranspileError> {
         match let_stmt {
-            LetStatement { identifier, exp } => {
-                self.expression(scope, *exp);
+            LetStatement { identifier, exp, .. } => {
+                self.expression(scope, *exp)?;
                 let c_identifier = scope.generate_variable_identifier(identifier);
                 let exp_ident = scope.stack.pop().unwrap();
                 scope.var_declaration(c_identifier.clone(), exp_ident);
             }
         }
+        Ok(())
     }
-    fn normal_fn_def(&mut self, scope: &mut ScopeHolder, normal_fn_def: NormalFnDef) {
+    fn normal_fn_def(&mut self, scope: &mut ScopeHolder, normal_fn_def: NormalFnDef) -> Result<(), TranspileError> {
         match normal_fn_def {
             NormalFnDef { identifier, args, body, closure_idents, exported } => {
                 let original_args = args.clone();
@@ -260,7 +363,7 @@ impl TranslationUnit {
                     let ident = fn_scope.generate_variable_identifier(arg);
                     fn_scope.get_mut().var_in_scope.push(ident)
                 }
-                self.unscoped_block(&mut fn_scope, body);
+                self.unscoped_block(&mut fn_scope, body)?;
                 let fn_body = fn_scope.generate_string();
                 let fn_def = generate_function_def(fn_identifier.clone(), args, fn_body);
                 scope.get_mut().var_declare.push_str(closure_generation.as_str());
@@ -268,11 +371,35 @@ impl TranslationUnit {
                 self.c_fn_headers.push(fn_header);
             }
         };
+        Ok(())
     }
-    fn fn_def(&mut self, scope: &mut ScopeHolder, fn_definition: FnDef) {
+    fn fn_def(&mut self, scope: &mut ScopeHolder, fn_definition: FnDef) -> Result<(), TranspileError> {
         match fn_definition {
             FnDef::FnDef(normal_fn_def) => self.normal_fn_def(scope, normal_fn_def),
-            FnDef::Imported(_) => unimplemented!(),
+            FnDef::Imported(imported_fn_def) => {
+                self.imported_fn_def(scope, imported_fn_def);
+                Ok(())
+            }
+        }
+    }
+    // the host's function is a wasm import, a shim with the usual function signature packs the
+    // arguments up for it so the import can be called like any other closure
+    fn imported_fn_def(&mut self, scope: &mut ScopeHolder, imported_fn_def: ImportedFnDef) {
+        match imported_fn_def {
+            ImportedFnDef { identifier, args } => {
+                let level = scope.get_level();
+                let arg_identifiers = args.iter().map(|arg| generate_variable_identifier(arg.clone(), level)).collect();
+                let args = args_to_string(args, level);
+                let import_identifier = generate_import_identifier(identifier.clone());
+                let fn_identifier = scope.generate_function_identifier(identifier.clone());
+                let inline_dec = scope.generate_inline_identifier();
+                let closure_generation = generate_closure_declaration(inline_dec, fn_identifier.clone(), vec![]);
+                let fn_body = generate_import_shim_body(import_identifier.clone(), arg_identifiers);
+                scope.get_mut().var_declare.push_str(closure_generation.as_str());
+                self.c_fn_headers.push(generate_import_declaration(import_identifier, identifier));
+                self.c_fn_headers.push(generate_function_header(fn_identifier.clone(), args.clone()));
+                self.c_fn_defs.push(generate_function_def(fn_identifier, args, fn_body));
+            }
         }
     }
 }
\ No newline at end of file
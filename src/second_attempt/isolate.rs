@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::JoinHandle;
+use crate::second_attempt::engine::{Engine, EngineError, FromValue, IntoValue};
+use crate::second_attempt::vm2::Vm;
+use crate::second_attempt::vm2::misc::{Table, Value};
+use crate::second_attempt::vm2::transfer::Message;
+use crate::second_attempt::vm_error::VmErrorKind;
+
+/*
+Isolates run Table code in parallel. Each one is an `Engine` with its own vm on its own thread, they
+share nothing and only talk by sending messages, which are deep copies (see `vm2::transfer`).
+
+    spawn(function)         starts `function` in a new isolate and gives back its handle, a number.
+                            The isolate gets a copy of every global, and if the function takes an
+                            argument it's called with the handle of the isolate that spawned it
+    send(handle, value)     copies `value` into the isolate's inbox
+    receive()               waits for the next value in this isolate's inbox
+
+From Rust the same goes through `Engine::spawn`, `send`, `receive` and `join`, where joining waits
+for the isolate to finish and copies back whatever its function returned. Every engine is an isolate,
+handle 0 unless it was spawned, and isolates spawned from it share its handles. An isolate that
+finishes stops taking messages. Limits aren't passed on, a new isolate has none.
+ */
+#[derive(Default)]
+struct Registry {
+    next: AtomicUsize,
+    inboxes: Mutex<HashMap<usize, Sender<Message>>>,
+    threads: Mutex<HashMap<usize, JoinHandle<Result<Message, String>>>>,
+}
+pub(super) struct Isolate {
+    id: usize,
+    inbox: Mutex<Receiver<Message>>,
+    registry: Arc<Registry>,
+}
+impl Isolate {
+    // the first isolate of its own registry
+    pub(super) fn root() -> Arc<Self> {
+        Self::register(Arc::new(Registry::default()))
+    }
+    fn register(registry: Arc<Registry>) -> Arc<Self> {
+        let id = registry.next.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = channel();
+        registry.inboxes.lock().unwrap().insert(id, sender);
+        Arc::new(Self {
+            id,
+            inbox: Mutex::new(receiver),
+            registry,
+        })
+    }
+    fn send(&self, to: usize, message: Message) -> Result<(), String> {
+        let inboxes = self.registry.inboxes.lock().unwrap();
+        let inbox = inboxes.get(&to).ok_or_else(|| format!("no running isolate {}", to))?;
+        inbox.send(message).map_err(|_| format!("isolate {} has stopped", to))
+    }
+    fn receive(&self) -> Result<Message, String> {
+        self.inbox.lock().unwrap().recv().map_err(|_| "this isolate's inbox was closed".to_string())
+    }
+    // the first value in `message` is the function to run, the rest are the globals in `names`
+    fn spawn(&self, names: Vec<String>, message: Message) -> Result<usize, String> {
+        let child = Self::register(self.registry.clone());
+        let (id, parent) = (child.id, self.id);
+        let thread = std::thread::Builder::new()
+            .name(format!("isolate {}", id))
+            .spawn(move || {
+                let registry = child.registry.clone();
+                let result = run(child, parent, names, message);
+                registry.inboxes.lock().unwrap().remove(&id);
+                result
+            })
+            .map_err(|err| format!("couldn't start a thread: {}", err))?;
+        self.registry.threads.lock().unwrap().insert(id, thread);
+        Ok(id)
+    }
+    fn join(&self, id: usize) -> Result<Message, String> {
+        let thread = self.registry.threads.lock().unwrap().remove(&id)
+            .ok_or_else(|| format!("no isolate {} to join", id))?;
+        thread.join().map_err(|_| format!("isolate {} panicked", id))?
+    }
+}
+fn run(isolate: Arc<Isolate>, parent: usize, names: Vec<String>, message: Message) -> Result<Message, String> {
+    let mut engine = Engine::with_isolate(isolate);
+    let vm = engine.vm_mut();
+    let values = vm.import_values(message).map_err(|err| err.to_string())?;
+    let function = values[0];
+    for (name, value) in names.iter().zip(&values[1..]) {
+        vm.set_global(name, *value);
+    }
+    // the copied spawn, send and receive still belong to the parent
+    register_natives(&mut engine);
+    let vm = engine.vm_mut();
+    let arguments = match vm.get_tables().get(Vm::table_handle(function).map_err(|err| err.to_string())?) {
+        Some(Table::Function(function)) if function.arity == 1 => vec![Value::Number(parent as i64)],
+        _ => vec![],
+    };
+    let result = vm.call_function(function, &arguments).map_err(|err| err.to_string())?;
+    vm.export_values(&[result]).map_err(|err| err.to_string())
+}
+// everything a new isolate needs, the function first and then every global
+fn export_for_spawn(vm: &Vm, function: Value) -> Result<(Vec<String>, Message), VmErrorKind> {
+    let handle = Vm::table_handle(function)?;
+    match vm.get_tables().get(handle) {
+        Some(Table::Function(_) | Table::Native(_)) => {}
+        Some(table) => return Err(VmErrorKind::TypeError { expected: "function", found: table.type_name() }),
+        None => return Err(VmErrorKind::BadTableHandle(handle)),
+    }
+    let mut globals = vm.get_globals().iter().collect::<Vec<_>>();
+    globals.sort_by_key(|(name, _)| *name);
+    let names = globals.iter().map(|(name, _)| name.to_string()).collect();
+    let values = std::iter::once(function).chain(globals.iter().map(|(_, value)| *value)).collect::<Vec<_>>();
+    Ok((names, vm.export_values(&values)?))
+}
+
+pub(super) fn register_natives(engine: &mut Engine) {
+    let isolate = engine.isolate.clone();
+    engine.register("spawn", move |vm, arguments| {
+        let [function] = arguments else {
+            return Err(VmErrorKind::ArityMismatch { expected: 1, found: arguments.len() });
+        };
+        let (names, message) = export_for_spawn(vm, *function)?;
+        let id = isolate.spawn(names, message).map_err(VmErrorKind::Native)?;
+        Ok(Value::Number(id as i64))
+    });
+    let isolate = engine.isolate.clone();
+    engine.register("send", move |vm, arguments| {
+        let [to, value] = arguments else {
+            return Err(VmErrorKind::ArityMismatch { expected: 2, found: arguments.len() });
+        };
+        let to = to.get_number().ok_or(VmErrorKind::TypeError { expected: "number", found: to.type_name() })?;
+        let message = vm.export_values(&[*value])?;
+        isolate.send(to as usize, message).map_err(VmErrorKind::Native)?;
+        Ok(Value::Nil)
+    });
+    let isolate = engine.isolate.clone();
+    engine.register("receive", move |vm, arguments| {
+        if !arguments.is_empty() {
+            return Err(VmErrorKind::ArityMismatch { expected: 0, found: arguments.len() });
+        }
+        let message = isolate.receive().map_err(VmErrorKind::Native)?;
+        Ok(vm.import_values(message)?[0])
+    });
+}
+
+impl Engine {
+    // this engine's handle, what other isolates send to
+    pub fn isolate_id(&self) -> usize {
+        self.isolate.id
+    }
+    // starts the global function `function` in a new isolate, like `spawn` does
+    pub fn spawn(&mut self, function: &str) -> Result<usize, EngineError> {
+        let function = self.vm().get_global(function).ok_or_else(|| EngineError::UndefinedGlobal(function.to_string()))?;
+        let (names, message) = export_for_spawn(self.vm(), function).map_err(EngineError::Conversion)?;
+        self.isolate.spawn(names, message).map_err(EngineError::Isolate)
+    }
+    pub fn send<T: IntoValue>(&mut self, isolate: usize, value: T) -> Result<(), EngineError> {
+        let value = value.into_value(self.vm_mut()).map_err(EngineError::Conversion)?;
+        let message = self.vm().export_values(&[value]).map_err(EngineError::Conversion)?;
+        self.isolate.send(isolate, message).map_err(EngineError::Isolate)
+    }
+    // waits for the next message sent to this engine
+    pub fn receive<T: FromValue>(&mut self) -> Result<T, EngineError> {
+        let message = self.isolate.receive().map_err(EngineError::Isolate)?;
+        let value = self.vm_mut().import_values(message).map_err(EngineError::Conversion)?[0];
+        T::from_value(value, self.vm()).map_err(EngineError::Conversion)
+    }
+    // waits for an isolate spawned from this engine, or any isolate sharing its handles, to finish
+    pub fn join<T: FromValue>(&mut self, isolate: usize) -> Result<T, EngineError> {
+        let message = self.isolate.join(isolate).map_err(EngineError::Isolate)?;
+        let value = self.vm_mut().import_values(message).map_err(EngineError::Conversion)?[0];
+        T::from_value(value, self.vm()).map_err(EngineError::Conversion)
+    }
+}
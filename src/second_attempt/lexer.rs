@@ -22,6 +22,12 @@ pub enum Token {
     Switch,
     Boolean(BooleanValues),
     Capture,
+    Try,
+    Catch,
+    Throw,
+    Yield,
+    For,
+    In,
 }
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
 pub enum BooleanValues {
@@ -55,6 +61,12 @@ pub fn lexer() -> impl Parser<char, Vec<(Token, Span)>, Error = Simple<char>> {
         "export" => Token::Export,
         "capture" => Token::Capture,
         "import" => Token::Import,
+        "try" => Token::Try,
+        "catch" => Token::Catch,
+        "throw" => Token::Throw,
+        "yield" => Token::Yield,
+        "for" => Token::For,
+        "in" => Token::In,
         "_" => Token::InferenceIdentifier,
         _ => Token::Identifier(ident)
     });
@@ -97,6 +109,12 @@ impl fmt::Display for Token {
             }
             Token::Capture => write!(f, "capture"),
             Token::Import => write!(f, "import"),
+            Token::Try => write!(f, "try"),
+            Token::Catch => write!(f, "catch"),
+            Token::Throw => write!(f, "throw"),
+            Token::Yield => write!(f, "yield"),
+            Token::For => write!(f, "for"),
+            Token::In => write!(f, "in"),
         }
     }
 }
\ No newline at end of file
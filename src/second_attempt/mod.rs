@@ -1,5 +1,4 @@
 use std::fs;
-use crate::main;
 use crate::second_attempt::ir::File;
 use crate::second_attempt::transpiler::{compile_c_file, compile_c_files};
 use crate::second_attempt::vm::bytecode::Value;
@@ -12,34 +11,117 @@ mod transpiler;
 mod ir_to_string;
 mod ir_to_string_2;
 mod test_transpiler;
-mod c_gen_helper;
+pub(crate) mod c_gen_helper;
 mod ir3;
 mod parser;
 mod vm;
-mod vm2;
+pub mod vm2;
 mod vm3;
+pub mod numeric;
+pub mod scalar;
+pub mod vm_error;
+pub mod globals;
+pub mod stack;
+pub mod pretty;
+mod vm_bench;
+pub mod engine;
+pub mod isolate;
 
 pub fn new_entrypoint(string: String) {
-    //let file = prefix_setup_file(string);
+    //let file = prefix_setup_file(string).unwrap();
     //compile_files(Some(file.into_bytes()));
     //wasmtime_runner(fs::read("target/output.wasm").unwrap());
     vm3::test_vm();
 }
+// compiles a source file to a `.tblc` the other subcommands and `Vm::load_file` can read
+pub fn compile_entrypoint(source_path: String, path: String) {
+    let source = match std::fs::read_to_string(source_path.as_str()) {
+        Ok(source) => source,
+        Err(err) => {
+            eprintln!("couldn't read {}: {}", source_path, err);
+            return;
+        }
+    };
+    let mut engine = engine::Engine::new();
+    let chunk = match engine.compile(source.as_str()) {
+        Ok(chunk) => chunk,
+        Err(err) => {
+            eprintln!("{}", err);
+            return;
+        }
+    };
+    let written = vm2::file::BytecodeFile::from_vm(chunk, engine.vm())
+        .and_then(|file| file.write_to(path.as_str()));
+    if let Err(err) = written {
+        eprintln!("couldn't write {}: {}", path, err);
+    }
+}
+pub fn disassemble_entrypoint(path: String) {
+    match vm2::file::BytecodeFile::read_from(path.as_str()) {
+        Ok(file) => print!("{}", vm2::disassemble::disassemble_file(&file)),
+        Err(err) => eprintln!("couldn't read {}: {}", path, err),
+    }
+}
+// runs a compiled file under the terminal debugger, the source is only needed for line numbers
+pub fn debug_entrypoint(path: String, source_path: Option<String>) {
+    let source = source_path.map(|source_path| std::fs::read_to_string(source_path).unwrap());
+    let mut vm = vm2::Vm::default();
+    if let Err(err) = vm.load_file(path.as_str()) {
+        eprintln!("couldn't load {}: {}", path, err);
+        return;
+    }
+    let mut debugger = vm2::debugger::Debugger::new(vm2::debugger::Terminal, source);
+    match vm.run_with(&mut debugger) {
+        Ok(value) => println!("finished with {}", vm2::disassemble::constant_literal(value, Some(&vm))),
+        Err(err) => println!("runtime error: {}", err),
+    }
+}
+// runs a compiled file with the profiler attached and prints its report, optionally writing collapsed stacks for flamegraphs
+pub fn profile_entrypoint(path: String, collapsed_path: Option<String>) {
+    let mut vm = vm2::Vm::default();
+    if let Err(err) = vm.load_file(path.as_str()) {
+        eprintln!("couldn't load {}: {}", path, err);
+        return;
+    }
+    let mut profiler = vm2::profiler::Profiler::new();
+    let result = vm.run_with(&mut profiler);
+    profiler.finish();
+    match result {
+        Ok(value) => println!("finished with {}", vm2::disassemble::constant_literal(value, Some(&vm))),
+        Err(err) => println!("runtime error: {}", err),
+    }
+    print!("\n{}", profiler.report());
+    if let Some(collapsed_path) = collapsed_path {
+        if let Err(err) = profiler.write_collapsed_stacks(collapsed_path.as_str()) {
+            eprintln!("couldn't write {}: {}", collapsed_path, err);
+        }
+    }
+}
+pub fn bench_entrypoint() {
+    vm_bench::bench_vms();
+}
 fn to_paths(str: Vec<&str>) -> Vec<&std::path::Path> {
     str.into_iter().map(|str| {
         std::path::Path::new(str)
     }).collect()
 }
-fn prefix_setup_file(file: String) -> String {
+fn prefix_setup_file(file: String) -> Result<String, ir3::TranspileError> {
     let mut buffer = String::default();
-    let file = test_transpiler::test_parser(file);
+    let file = test_transpiler::test_parser(file)?;
     buffer.push_str(file.as_str());
     buffer.push_str(r#"
     void _start() {
-        print_value(_main());
+        Value* result = _main();
+        if(result == NULL) {
+            print("uncaught exception: ");
+            print_value(catch_value());
+            exception("uncaught exception");
+        } else {
+            print_value(result);
+        }
     }
     "#);
-    buffer
+    Ok(buffer)
 }
 fn compile_files(main_file: Option<Vec<u8>>) {
     use std::path::Path;
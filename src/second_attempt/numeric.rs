@@ -0,0 +1,86 @@
+use std::cmp::Ordering;
+use std::fmt::{Display, Formatter};
+
+// The numeric tower shared by every vm.
+// Integers stay integers as long as both sides are integers and the result fits in an i64,
+// anything involving a float (or an integer operation that overflows) is promoted to a float.
+// Integer division truncates towards zero, integer division by zero is an error,
+// float division by zero follows IEEE 754 and gives inf/-inf/NaN.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Numeric {
+    Integer(i64),
+    Float(f64),
+}
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ArithmeticOperator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+}
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ArithmeticError {
+    DivisionByZero,
+}
+impl Display for ArithmeticError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArithmeticError::DivisionByZero => write!(f, "integer division by zero"),
+        }
+    }
+}
+impl Numeric {
+    pub fn as_float(self) -> f64 {
+        match self {
+            Numeric::Integer(num) => num as f64,
+            Numeric::Float(num) => num,
+        }
+    }
+    pub fn operate(self, rhs: Numeric, operator: ArithmeticOperator) -> Result<Numeric, ArithmeticError> {
+        match (self, rhs) {
+            (Numeric::Integer(lhs), Numeric::Integer(rhs)) => {
+                let result = match operator {
+                    ArithmeticOperator::Add => lhs.checked_add(rhs),
+                    ArithmeticOperator::Subtract => lhs.checked_sub(rhs),
+                    ArithmeticOperator::Multiply => lhs.checked_mul(rhs),
+                    ArithmeticOperator::Divide => {
+                        if rhs == 0 {
+                            return Err(ArithmeticError::DivisionByZero);
+                        }
+                        lhs.checked_div(rhs)
+                    }
+                };
+                match result {
+                    Some(result) => Ok(Numeric::Integer(result)),
+                    // overflowed, so promote instead of wrapping
+                    None => Numeric::Float(lhs as f64).operate(Numeric::Float(rhs as f64), operator),
+                }
+            }
+            (lhs, rhs) => {
+                let (lhs, rhs) = (lhs.as_float(), rhs.as_float());
+                Ok(Numeric::Float(match operator {
+                    ArithmeticOperator::Add => lhs + rhs,
+                    ArithmeticOperator::Subtract => lhs - rhs,
+                    ArithmeticOperator::Multiply => lhs * rhs,
+                    ArithmeticOperator::Divide => lhs / rhs,
+                }))
+            }
+        }
+    }
+    pub fn compare(self, rhs: Numeric) -> Option<Ordering> {
+        match (self, rhs) {
+            (Numeric::Integer(lhs), Numeric::Integer(rhs)) => Some(lhs.cmp(&rhs)),
+            (lhs, rhs) => lhs.as_float().partial_cmp(&rhs.as_float()),
+        }
+    }
+}
+impl Display for Numeric {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Numeric::Integer(num) => write!(f, "{}", num),
+            // always print a decimal point so floats and integers can be told apart
+            Numeric::Float(num) if num.is_finite() && num.fract() == 0.0 => write!(f, "{:.1}", num),
+            Numeric::Float(num) => write!(f, "{}", num),
+        }
+    }
+}
@@ -2,7 +2,7 @@ use ariadne::{Color, Fmt, Label, Report, ReportKind, Source};
 use chumsky::{Error, Parser, select};
 use chumsky::prelude::{empty, end, filter_map, just, Recursive, Simple};
 use crate::parser2::Spanned;
-use crate::second_attempt::ir::{Block, Exp, File, FnCall, FnDef, ImportedFnDef, LetStatement, NormalFnDef, Statement, Value};
+use crate::second_attempt::ir::{Block, Exp, File, FnCall, FnDef, ForLoop, ImportedFnDef, LetStatement, NormalFnDef, Statement, TryCatch, Value};
 use crate::second_attempt::lexer::Token;
 use crate::second_attempt::lexer::BooleanValues;
 
@@ -18,10 +18,11 @@ pub fn parse() -> impl Parser<Token, File, Error = Simple<Token>> + Clone {
     let let_statement = {
         just(Token::Let).ignore_then(ident.clone())
             .then_ignore(just(Token::Operator("=".to_string()))).then(exp.clone()).then_ignore(just(Token::Control(';')))
-            .map(|(identifier, exp)|{
+            .map_with_span(|(identifier, exp), span|{
                 Statement::LetStatement(LetStatement {
                     identifier,
-                    exp: Box::new(exp)
+                    exp: Box::new(exp),
+                    span
                 })
             })
     };
@@ -30,12 +31,17 @@ pub fn parse() -> impl Parser<Token, File, Error = Simple<Token>> + Clone {
         let capture_clause = just(Token::Capture).ignore_then(
             ident.clone().separated_by(just(Token::Control(','))).allow_trailing()
         );
+        // the body's braces are its own, otherwise a body without a final expression would be a
+        // block statement and run on into whatever follows the function
+        let body = block.clone()
+            .or(empty().map(|_| Block::WithoutExp(vec![])))
+            .delimited_by(just(Token::Control('{')), just(Token::Control('}')));
         just(Token::Export).repeated().at_most(1).then_ignore(
         just(Token::Fn))
             .then(ident.clone())
             .then(fn_def_args.clone().delimited_by(just(Token::Control('(')), just(Token::Control(')'))))
             .then(capture_clause)
-            .then(block.clone())
+            .then(body)
             .map(|((((export, identifier), args), capture_clause), block)| {
                 FnDef::FnDef(NormalFnDef{
                     identifier,
@@ -60,11 +66,30 @@ pub fn parse() -> impl Parser<Token, File, Error = Simple<Token>> + Clone {
             )
     };
     statement.define({
-        let statement_block = statement.clone().repeated().delimited_by(just(Token::Control('{')), just(Token::Control('}')))
-            .map(|statements| {
-               Statement::Block(statements.into_iter().map(|statement| Box::new(statement)).collect())
+        let statements = statement.clone().repeated().delimited_by(just(Token::Control('{')), just(Token::Control('}')))
+            .map(|statements| statements.into_iter().map(|statement| Box::new(statement)).collect::<Vec<_>>());
+        let statement_block = statements.clone().map(Statement::Block);
+        let try_catch = just(Token::Try).ignore_then(statements.clone())
+            .then_ignore(just(Token::Catch)).then(ident.clone()).then(statements.clone())
+            .map(|((body, identifier), handler)| {
+                Statement::TryCatch(TryCatch {
+                    body,
+                    identifier,
+                    handler
+                })
+            });
+        let throw = just(Token::Throw).ignore_then(exp.clone()).then_ignore(just(Token::Control(';')))
+            .map(|exp| Statement::Throw(Box::new(exp)));
+        let for_loop = just(Token::For).ignore_then(ident.clone())
+            .then_ignore(just(Token::In)).then(exp.clone()).then(statements.clone())
+            .map(|((identifier, exp), body)| {
+                Statement::For(ForLoop {
+                    identifier,
+                    exp: Box::new(exp),
+                    body
+                })
             });
-        let_statement.or(
+        let_statement.or(try_catch).or(throw).or(for_loop).or(
             exp.clone().then_ignore(just(Token::Control(';'))).map(|exp| {
                 Statement::ExpStatement(Box::new(exp))
             })
@@ -91,16 +116,28 @@ pub fn parse() -> impl Parser<Token, File, Error = Simple<Token>> + Clone {
             .map(|(statements, exp)| {
                Exp::Block(statements.into_iter().map(|statement| Box::new(statement)).collect(), Box::new(exp))
             });
-        let val = select!{
-            Token::Number(n) => Exp::Value(Value::Number(n.parse().unwrap())),
+        // an integer too big for i64 is promoted to a float, like an operation that overflows
+        let number = filter_map(|span, tok| match tok {
+            Token::Number(n) => match n.parse::<i64>() {
+                Ok(integer) if !n.contains('.') => Ok(Exp::Value(Value::Integer(integer))),
+                _ => n.parse::<f64>()
+                    .map(|float| Exp::Value(Value::Float(float)))
+                    .map_err(|_| Simple::custom(span, format!("`{}` isn't a number", n))),
+            },
+            _ => Err(Simple::expected_input_found(span, Vec::new(), Some(tok))),
+        });
+        let val = number.or(select!{
             Token::String(string) => Exp::Value(Value::String(string)),
             Token::Boolean(BooleanValues::True) => unimplemented!(),
             Token::Boolean(BooleanValues::False) => unimplemented!(),
-        }.labelled("value");
+        }).labelled("value");
         let identifier = select! {
             Token::Identifier(string) => Exp::Variable(string)
         }.labelled("identifier");
+        let yield_exp = just(Token::Yield).ignore_then(exp.clone())
+            .map(|exp| Exp::Yield(Box::new(exp)));
         let atom = val
+            .or(yield_exp)
             //.or(exp.clone().delimited_by(just(Token::Control('{')), just(Token::Control('}'))))
             .or(fn_call.clone())
             .or(identifier);
@@ -111,10 +148,11 @@ pub fn parse() -> impl Parser<Token, File, Error = Simple<Token>> + Clone {
         let ident = ident.clone();
         let fn_call_args = exp.clone().separated_by(just(Token::Control(','))).allow_trailing()
             .delimited_by(just(Token::Control('(')), just(Token::Control(')')));
-        let fn_call = ident.then(fn_call_args).map(|(identifier, args)| {
+        let fn_call = ident.then(fn_call_args).map_with_span(|(identifier, args), span| {
             Exp::FnCall(FnCall {
                 identifier,
-                args: args.into_iter().map(|arg| {Box::new(arg)}).collect()
+                args: args.into_iter().map(|arg| {Box::new(arg)}).collect(),
+                span
             })
         });
         fn_call
@@ -0,0 +1,144 @@
+use std::collections::HashSet;
+
+/*
+Lays out tables as text for `print` and `to_string`. Each vm describes its values through `Tables`
+and `print` walks them, keeping the tables it's inside of on its own stack rather than recursing, so
+nesting is only limited by memory. Tables look like the literals that build them
+
+    compact     [1, 2, name: "yo", inner: [true]]
+    indented    [
+                    1,
+                    2,
+                    name: "yo",
+                    inner: [
+                        true,
+                    ],
+                ]
+
+A table that contains itself, directly or further down, is written as `<cycle>` the second time
+it's reached on the way down. The same table reached twice from different places isn't a cycle
+and is written out both times.
+ */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Layout {
+    Compact,
+    Indented,
+}
+pub const CYCLE: &str = "<cycle>";
+const INDENT: &str = "    ";
+
+// how a vm's values look to the printer
+pub trait Tables {
+    type Value: Copy;
+    fn shape(&self, value: Self::Value) -> Shape<Self::Value>;
+}
+pub enum Shape<V> {
+    // written as it is
+    Text(String),
+    // the table's handle, to spot cycles, and its entries in the order they're written
+    Table(usize, Vec<(Option<String>, V)>),
+}
+pub fn print<T: Tables>(tables: &T, value: T::Value, layout: Layout) -> String {
+    let mut printer = Printer::new(layout);
+    // the tables we're inside of right now with the entries they have left, innermost last, and
+    // their handles again to look up, meeting one of them again is a cycle
+    let mut open: Vec<(usize, std::vec::IntoIter<(Option<String>, T::Value)>)> = vec![];
+    let mut path = HashSet::new();
+    let mut next = Some(value);
+    loop {
+        if let Some(value) = next.take() {
+            match tables.shape(value) {
+                Shape::Table(handle, entries) if path.insert(handle) => {
+                    printer.begin_table();
+                    open.push((handle, entries.into_iter()));
+                }
+                shape => {
+                    match shape {
+                        Shape::Text(text) => printer.write(text.as_str()),
+                        Shape::Table(..) => printer.write(CYCLE),
+                    }
+                    if !open.is_empty() {
+                        printer.end_entry();
+                    }
+                }
+            }
+        }
+        let Some((_, entries)) = open.last_mut() else {
+            break;
+        };
+        match entries.next() {
+            Some((key, value)) => {
+                printer.entry(key.as_deref());
+                next = Some(value);
+            }
+            None => {
+                if let Some((handle, _)) = open.pop() {
+                    path.remove(&handle);
+                }
+                printer.end_table();
+                if !open.is_empty() {
+                    printer.end_entry();
+                }
+            }
+        }
+    }
+    printer.finish()
+}
+
+pub struct Printer {
+    layout: Layout,
+    buffer: String,
+    // how many entries each open table has written so far, innermost last
+    open: Vec<usize>,
+}
+impl Printer {
+    pub fn new(layout: Layout) -> Self {
+        Self {
+            layout,
+            buffer: String::new(),
+            open: vec![],
+        }
+    }
+    pub fn write(&mut self, text: &str) {
+        self.buffer.push_str(text);
+    }
+    pub fn begin_table(&mut self) {
+        self.buffer.push('[');
+        self.open.push(0);
+    }
+    // starts the next entry of the innermost table, the value is written after this
+    pub fn entry(&mut self, key: Option<&str>) {
+        let depth = self.open.len();
+        let count = self.open.last_mut().expect("entry outside of a table");
+        match self.layout {
+            Layout::Compact if *count > 0 => self.buffer.push_str(", "),
+            Layout::Compact => {}
+            Layout::Indented => {
+                self.buffer.push('\n');
+                self.buffer.push_str(INDENT.repeat(depth).as_str());
+            }
+        }
+        *count += 1;
+        if let Some(key) = key {
+            self.buffer.push_str(key);
+            self.buffer.push_str(": ");
+        }
+    }
+    // the value of an indented entry has been written, so its comma goes after it
+    pub fn end_entry(&mut self) {
+        if self.layout == Layout::Indented {
+            self.buffer.push(',');
+        }
+    }
+    pub fn end_table(&mut self) {
+        let count = self.open.pop().expect("end_table without begin_table");
+        if self.layout == Layout::Indented && count > 0 {
+            self.buffer.push('\n');
+            self.buffer.push_str(INDENT.repeat(self.open.len()).as_str());
+        }
+        self.buffer.push(']');
+    }
+    pub fn finish(self) -> String {
+        self.buffer
+    }
+}
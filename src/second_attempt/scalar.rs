@@ -0,0 +1,27 @@
+use std::cmp::Ordering;
+use crate::second_attempt::numeric::Numeric;
+
+/*
+A value from any of the vms without the contents of the table it might point at. Every vm's `Value`
+converts to this, so the rules that never look inside a table, like equality, are only written once.
+ */
+#[derive(Copy, Clone, Debug)]
+pub enum Scalar {
+    Numeric(Numeric),
+    Boolean(bool),
+    Table(usize),
+    Nil,
+}
+impl Scalar {
+    // numbers are equal by value whether they're integers or floats, tables only to themselves, and
+    // values of different types never are
+    pub fn equals(self, other: Scalar) -> bool {
+        match (self, other) {
+            (Scalar::Numeric(lhs), Scalar::Numeric(rhs)) => lhs.compare(rhs) == Some(Ordering::Equal),
+            (Scalar::Boolean(lhs), Scalar::Boolean(rhs)) => lhs == rhs,
+            (Scalar::Table(lhs), Scalar::Table(rhs)) => lhs == rhs,
+            (Scalar::Nil, Scalar::Nil) => true,
+            _ => false,
+        }
+    }
+}
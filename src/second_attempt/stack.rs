@@ -0,0 +1,119 @@
+/*
+The value stacks the vms run on, growable up to a maximum. Values live in fixed size segments that
+are allocated as the stack grows and kept once it shrinks again, so a push or pop is an index into
+the top segment and nothing ever moves. Going past the maximum makes `push` return `None`, which
+the vms turn into a stack overflow.
+
+Slots above `len` keep whatever was last there, everything below is always a real value.
+ */
+pub const SEGMENT_SIZE: usize = 256;
+// the default maximum, in values
+pub const DEFAULT_MAX_STACK: usize = 1 << 20;
+
+pub struct Stack<V> {
+    segments: Vec<Box<[V; SEGMENT_SIZE]>>,
+    len: usize,
+    max: usize,
+}
+impl<V: Copy + Default> Stack<V> {
+    pub fn with_max(max: usize) -> Self {
+        Self {
+            segments: vec![Self::segment()],
+            len: 0,
+            max,
+        }
+    }
+    fn segment() -> Box<[V; SEGMENT_SIZE]> {
+        Box::new([V::default(); SEGMENT_SIZE])
+    }
+    #[inline(always)]
+    pub fn pop(&mut self) -> Option<V> {
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+        Some(self.slot(self.len))
+    }
+    #[inline(always)]
+    pub fn push(&mut self, value: V) -> Option<()> {
+        if self.len >= self.max {
+            return None;
+        }
+        let segment = self.len / SEGMENT_SIZE;
+        if segment == self.segments.len() {
+            self.segments.push(Self::segment());
+        }
+        self.segments[segment][self.len % SEGMENT_SIZE] = value;
+        self.len += 1;
+        Some(())
+    }
+    // index 0 is the top of the stack
+    pub fn peek(&self, index: usize) -> Option<V> {
+        Some(self.slot(self.compute_distance(index)?))
+    }
+    pub fn set(&mut self, index: usize, value: V) -> Option<()> {
+        let position = self.compute_distance(index)?;
+        self.segments[position / SEGMENT_SIZE][position % SEGMENT_SIZE] = value;
+        Some(())
+    }
+    // index 0 is the bottom of the stack
+    pub fn get(&self, position: usize) -> Option<V> {
+        (position < self.len).then(|| self.slot(position))
+    }
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    pub fn truncate(&mut self, len: usize) {
+        self.len = self.len.min(len);
+    }
+    pub fn get_max(&self) -> usize {
+        self.max
+    }
+    // a stack already past a lower maximum keeps its values but can't grow until it's back under
+    pub fn set_max(&mut self, max: usize) {
+        self.max = max;
+    }
+    // bottom of the stack first
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = V> + ExactSizeIterator + '_ {
+        (0..self.len).map(|position| self.slot(position))
+    }
+    // copies out everything from `start` up, bottom first
+    pub fn to_vec_from(&self, start: usize) -> Vec<V> {
+        (start.min(self.len)..self.len).map(|position| self.slot(position)).collect()
+    }
+    /*
+    The segment holding the top of the stack as one slice, cut short at the maximum, and how much of
+    it is in use. Whatever `f` leaves in the length becomes the stack's new length, so code that
+    wants plain memory to work on (the jit) can push and pop as long as it stays inside the slice.
+     */
+    pub fn with_top_segment<R>(&mut self, f: impl FnOnce(&mut [V], &mut usize) -> R) -> R {
+        let segment = self.len.saturating_sub(1) / SEGMENT_SIZE;
+        let base = segment * SEGMENT_SIZE;
+        let mut len = self.len - base;
+        let end = (self.max.max(base) - base).min(SEGMENT_SIZE).max(len);
+        let result = f(&mut self.segments[segment][..end], &mut len);
+        self.len = base + len.min(end);
+        result
+    }
+    #[inline(always)]
+    fn slot(&self, position: usize) -> V {
+        self.segments[position / SEGMENT_SIZE][position % SEGMENT_SIZE]
+    }
+    fn compute_distance(&self, index: usize) -> Option<usize> {
+        if index >= self.len {
+            return None;
+        }
+        Some(self.len - 1 - index)
+    }
+}
+impl<V: Copy + Default> Default for Stack<V> {
+    fn default() -> Self {
+        Self::with_max(DEFAULT_MAX_STACK)
+    }
+}
@@ -52,7 +52,7 @@ pub(crate) fn test_transpiler() {
     //let generated = ir3::TranslationUnit::gen_from_file(file);
     //println!("{}", generated);
 }
-pub(crate) fn test_parser(src: String) -> String {
+pub(crate) fn test_parser(src: String) -> Result<String, ir3::TranspileError> {
     let (tokens, errors) = lexer().parse_recovery(src.clone());
     let len = src.chars().count();
     let stream = Stream::from_iter(len..len + 1, tokens.unwrap().into_iter());
@@ -60,7 +60,7 @@ pub(crate) fn test_parser(src: String) -> String {
     do_err_messages(errors, parse_errors, src.clone());
     let ast = ast.unwrap();
     //println!("{:#?}", ast);
-    let generated = ir3::TranslationUnit::gen_from_file(ast);
+    let generated = ir3::TranslationUnit::gen_from_file(ast)?;
     println!("{}", generated);
-    generated
+    Ok(generated)
 }
\ No newline at end of file
@@ -1,4 +1,7 @@
+use std::fmt::{Display, Formatter};
 use indexmap::IndexMap;
+use crate::second_attempt::numeric::Numeric;
+use crate::second_attempt::scalar::Scalar;
 use crate::second_attempt::vm::bytecode::Bytecode::{Constant, PopLocal, PushLocal, Return, TestTruthy};
 
 pub type TIdentifier = String;
@@ -27,6 +30,12 @@ pub enum Bytecode {
     Multiply,
     Divide,
     Print,
+    // the operand is a name interned with `Vm::intern`
+    GetGlobal(usize),
+    // pops the value into a global that's already defined
+    SetGlobal(usize),
+    // pops the value, defining the global or replacing what it was
+    DefineGlobal(usize),
 }
 /*#[derive(Clone)]
 pub enum SingleByte {
@@ -61,6 +70,7 @@ pub enum ValueByte {
 #[derive(Copy, Clone, Debug)]
 pub enum Value {
     Number(i64),
+    Float(f64),
     Boolean(bool),
     Table(usize),
     Nil,
@@ -75,6 +85,17 @@ pub enum TableKey {
     Identifier(String, usize),
     NoIdentifier(usize),
 }
+impl Display for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Number(num) => write!(f, "{}", num),
+            Value::Float(num) => write!(f, "{}", Numeric::Float(*num)),
+            Value::Boolean(bool) => write!(f, "{}", bool),
+            Value::Table(index) => write!(f, "Table({})", index),
+            Value::Nil => write!(f, "Nil"),
+        }
+    }
+}
 impl Default for Value {
     fn default() -> Self {
         Value::Nil
@@ -84,63 +105,53 @@ impl Value {
     pub fn get_truthy(self) -> bool {
         match self {
             Value::Number(num) => num != 0,
+            Value::Float(num) => num != 0.0,
             Value::Boolean(bool) => bool,
             Value::Nil => false,
-            Value::Table(_) => unimplemented!(),
+            Value::Table(_) => true,
         }
     }
     pub fn get_number(self) -> Option<i64> {
         match self {
             Value::Number(num) => Some(num),
+            Value::Float(_) => None,
             Value::Boolean(_) => None,
             Value::Nil => None,
-            Value::Table(_) => unimplemented!(),
-        }
-    }
-}
-pub struct Stack<const N: usize> {
-    stack: [Value; N],
-    len: usize,
-}
-impl<const N: usize> Stack<N> {
-    pub fn pop(&mut self) -> Option<Value> {
-        if self.len == 0 {
-            return None;
+            Value::Table(_) => None,
         }
-        let val = self.stack[self.len-1];
-        self.len -= 1;
-        Some(val)
     }
-    pub fn push(&mut self, value: Value) -> Option<()> {
-        if self.len == N {
-            return None;
+    pub fn type_name(self) -> &'static str {
+        match self {
+            Value::Number(_) => "number",
+            Value::Float(_) => "float",
+            Value::Boolean(_) => "boolean",
+            Value::Table(_) => "table",
+            Value::Nil => "nil",
         }
-        self.stack[self.len] = value;
-        self.len += 1;
-        Some(())
-    }
-    pub fn peek(&mut self, index: usize) -> Option<Value> {
-        Some(self.stack[self.compute_distance(index)?])
     }
-    pub fn set(&mut self, index: usize, value: Value) -> Option<()> {
-        self.stack[self.compute_distance(index)?] = value;
-        Some(())
+    pub fn equals(self, other: Value) -> bool {
+        self.scalar().equals(other.scalar())
     }
-    pub fn clear(&mut self) {
-        self.len = 0;
+    pub fn scalar(self) -> Scalar {
+        match self {
+            Value::Number(num) => Scalar::Numeric(Numeric::Integer(num)),
+            Value::Float(num) => Scalar::Numeric(Numeric::Float(num)),
+            Value::Boolean(bool) => Scalar::Boolean(bool),
+            Value::Table(index) => Scalar::Table(index),
+            Value::Nil => Scalar::Nil,
+        }
     }
-    fn compute_distance(&self, index: usize) -> Option<usize> {
-        if self.len - index < 0 {
-            return None;
+    pub fn get_numeric(self) -> Option<Numeric> {
+        match self {
+            Value::Number(num) => Some(Numeric::Integer(num)),
+            Value::Float(num) => Some(Numeric::Float(num)),
+            _ => None,
         }
-        Some(self.len-index)
     }
-}
-impl<const N: usize> Default for Stack<N> {
-    fn default() -> Self {
-        Self {
-            stack: [Default::default(); N],
-            len: 0
+    pub fn from_numeric(numeric: Numeric) -> Self {
+        match numeric {
+            Numeric::Integer(num) => Value::Number(num),
+            Numeric::Float(num) => Value::Float(num),
         }
     }
-}
\ No newline at end of file
+}
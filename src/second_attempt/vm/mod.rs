@@ -1,15 +1,21 @@
+use std::cmp::Ordering;
 use std::collections::VecDeque;
-use crate::second_attempt::vm::bytecode::{Bytecode, Stack, Value};
+use crate::second_attempt::numeric::{ArithmeticOperator, Numeric};
+use crate::second_attempt::vm::bytecode::{Bytecode, Value};
+use crate::second_attempt::stack::Stack;
 use crate::second_attempt::vm::bytecode::Bytecode::{Constant, GetLocal, Jump, JumpIf, Print, Return, SetLocal, PushLocal, PopLocal, TestEqual, TestTruthy};
 use crate::second_attempt::vm::bytecode::Value::{Boolean, Number};
+use crate::second_attempt::vm_error::{VmError, VmErrorKind};
+use crate::second_attempt::globals::Globals;
 
 pub mod bytecode;
 
 pub struct Vm {
     bytecode: Vec<Bytecode>,
     current_index: usize,
-    local_stack: Stack<256>,
-    eval_stack: Stack<256>,
+    local_stack: Stack<Value>,
+    eval_stack: Stack<Value>,
+    globals: Globals<Value>,
 }
 impl Vm {
     pub fn from(bytecode: Vec<Bytecode>) -> Self {
@@ -17,143 +23,161 @@ impl Vm {
             bytecode,
             current_index: 0,
             local_stack: Default::default(),
-            eval_stack: Default::default()
+            eval_stack: Default::default(),
+            globals: Globals::default(),
         }
     }
-    pub fn run(&mut self) {
-        loop {
-            match self.read_byte() {
-                Return => {}
-                Constant(value) => {
-                    self.eval_stack.push(value).unwrap()
-                }
-                Print => {
-                    println!("{:#?}", self.eval_stack.pop().unwrap());
-                }
-                GetLocal(index) => {
-                    let val = self.local_stack.peek(index).unwrap();
-                    self.eval_stack.push(val).unwrap();
-                }
-                SetLocal(index) => {
-                    let val = self.eval_stack.pop().unwrap();
-                    self.local_stack.set(index, val).unwrap();
-                }
-                PushLocal => {
-                    let val = self.eval_stack.pop().unwrap();
-                    self.local_stack.push(val).unwrap();
-                }
-                PopLocal => {
-                    let val = self.local_stack.pop().unwrap();
-                    self.eval_stack.push(val).unwrap();
-                }
-                Jump(position) => {
+    // swaps in new bytecode to run next, the globals stay so it can use what earlier code defined
+    pub fn load(&mut self, bytecode: Vec<Bytecode>) {
+        self.bytecode = bytecode;
+        self.current_index = 0;
+    }
+    // runs until `Return` or the end of the bytecode, the result is whatever is on top of the eval stack
+    pub fn run(&mut self) -> Result<Value, VmError> {
+        while self.current_index < self.bytecode.len() {
+            let ip = self.current_index;
+            match self.step() {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(kind) => return Err(kind.at(0, ip)),
+            }
+        }
+        Ok(self.eval_stack.pop().unwrap_or_default())
+    }
+    // returns false once the bytecode has returned
+    fn step(&mut self) -> Result<bool, VmErrorKind> {
+        match self.read_byte() {
+            Return => return Ok(false),
+            Constant(value) => {
+                self.push(value)?;
+            }
+            Print => {
+                println!("{}", self.pop()?);
+            }
+            GetLocal(index) => {
+                let val = self.local_stack.peek(index).ok_or(VmErrorKind::StackUnderflow)?;
+                self.push(val)?;
+            }
+            SetLocal(index) => {
+                let val = self.pop()?;
+                self.local_stack.set(index, val).ok_or(VmErrorKind::StackUnderflow)?;
+            }
+            PushLocal => {
+                let val = self.pop()?;
+                self.local_stack.push(val).ok_or(VmErrorKind::StackOverflow(vec![]))?;
+            }
+            PopLocal => {
+                let val = self.local_stack.pop().ok_or(VmErrorKind::StackUnderflow)?;
+                self.push(val)?;
+            }
+            Jump(position) => {
+                self.current_index = position;
+            }
+            JumpIf(position) => {
+                if self.pop()?.get_truthy() {
                     self.current_index = position;
                 }
-                JumpIf(position) => {
-                    if self.eval_stack.pop().unwrap().get_truthy() {
-                        self.current_index = position;
-                    }
-                }
-                TestEqual => {
-                    let first = self.eval_stack.pop().unwrap();
-                    let second = self.eval_stack.pop().unwrap();
-                    //TODO allow matching on certain things like boolean with none types
-                    if !matches!(first, second) {
-                        panic!("values aren't of the same type");
-                    }
-                    let ret = match first {
-                        Number(val_1) => {
-                            match second {
-                                Number(val_2) => val_1 == val_2,
-                                _=> panic!("values aren't of the same type: {}", val_1)
-                            }
-                        }
-                        Boolean(val_1) => {
-                            match second {
-                                Boolean(val_2) => val_2 == val_1,
-                                _=> panic!("values aren't of the same type: {}", val_1)
-                            }
-                        }
-                        Value::Nil => {
-                            match second {
-                                Value::Nil => true,
-                                _=>  panic!("values aren't of the same type: Nil")
-                            }
-                        }
-                        Value::Table(_) => unimplemented!(),
-                    };
-                    self.eval_stack.push(Value::Boolean(ret)).unwrap();
-                }
-                TestTruthy => {
-                    let val = self.eval_stack.pop().unwrap().get_truthy();
-                    self.eval_stack.push(Value::Boolean(val));
-                }
-                Bytecode::TestLess => {
-                    let val_1 = self.eval_stack.pop().unwrap().get_number().unwrap();
-                    let val_2 = self.eval_stack.pop().unwrap().get_number().unwrap();
-                    self.eval_stack.push(Boolean(val_2 < val_1));
-                }
-                Bytecode::TestLessEqual => {
-                    let val_1 = self.eval_stack.pop().unwrap().get_number().unwrap();
-                    let val_2 = self.eval_stack.pop().unwrap().get_number().unwrap();
-                    self.eval_stack.push(Boolean(val_2 <= val_1));
-                }
-                Bytecode::TestGreater => {
-                    let val_1 = self.eval_stack.pop().unwrap().get_number().unwrap();
-                    let val_2 = self.eval_stack.pop().unwrap().get_number().unwrap();
-                    self.eval_stack.push(Boolean(val_2 > val_1));
-                }
-                Bytecode::TestGreaterEqual => {
-                    let val_1 = self.eval_stack.pop().unwrap().get_number().unwrap();
-                    let val_2 = self.eval_stack.pop().unwrap().get_number().unwrap();
-                    self.eval_stack.push(Boolean(val_2 >= val_1));
-                }
-                Bytecode::TestNot => {
-                    let val_1 = self.eval_stack.pop().unwrap().get_truthy();
-                    self.eval_stack.push(Boolean(!val_1));
-                }
-                Bytecode::Add => {
-                    let val_1 = self.eval_stack.pop().unwrap().get_number().unwrap();
-                    let val_2 = self.eval_stack.pop().unwrap().get_number().unwrap();
-                    self.eval_stack.push(Number(val_2 + val_1));
-                }
-                Bytecode::Subtract => {
-                    let val_1 = self.eval_stack.pop().unwrap().get_number().unwrap();
-                    let val_2 = self.eval_stack.pop().unwrap().get_number().unwrap();
-                    self.eval_stack.push(Number(val_2 - val_1));
-                }
-                Bytecode::Multiply => {
-                    let val_1 = self.eval_stack.pop().unwrap().get_number().unwrap();
-                    let val_2 = self.eval_stack.pop().unwrap().get_number().unwrap();
-                    self.eval_stack.push(Number(val_2 * val_1));
-                }
-                Bytecode::Divide => {
-                    let val_1 = self.eval_stack.pop().unwrap().get_number().unwrap();
-                    let val_2 = self.eval_stack.pop().unwrap().get_number().unwrap();
-                    self.eval_stack.push(Number(val_2 / val_1));
-                }
-                Bytecode::Copy => {
-                    let val = self.eval_stack.peek(0).unwrap();
-                    self.eval_stack.push(val);
-                }
-                Bytecode::Inject => {
-                    unimplemented!()
-                }
             }
-            if self.current_index == self.bytecode.len() {
-                return;
+            TestEqual => {
+                let first = self.pop()?;
+                let second = self.pop()?;
+                self.push(Value::Boolean(second.equals(first)))?;
+            }
+            TestTruthy => {
+                let val = self.pop()?.get_truthy();
+                self.push(Value::Boolean(val))?;
+            }
+            Bytecode::TestLess => {
+                let ordering = self.compare_numbers()?;
+                self.push(Boolean(ordering == Some(Ordering::Less)))?;
+            }
+            Bytecode::TestLessEqual => {
+                let ordering = self.compare_numbers()?;
+                self.push(Boolean(matches!(ordering, Some(Ordering::Less | Ordering::Equal))))?;
+            }
+            Bytecode::TestGreater => {
+                let ordering = self.compare_numbers()?;
+                self.push(Boolean(ordering == Some(Ordering::Greater)))?;
+            }
+            Bytecode::TestGreaterEqual => {
+                let ordering = self.compare_numbers()?;
+                self.push(Boolean(matches!(ordering, Some(Ordering::Greater | Ordering::Equal))))?;
+            }
+            Bytecode::TestNot => {
+                let val_1 = self.pop()?.get_truthy();
+                self.push(Boolean(!val_1))?;
+            }
+            Bytecode::Add => self.arithmetic(ArithmeticOperator::Add)?,
+            Bytecode::Subtract => self.arithmetic(ArithmeticOperator::Subtract)?,
+            Bytecode::Multiply => self.arithmetic(ArithmeticOperator::Multiply)?,
+            Bytecode::Divide => self.arithmetic(ArithmeticOperator::Divide)?,
+            Bytecode::Copy => {
+                let val = self.eval_stack.peek(0).ok_or(VmErrorKind::StackUnderflow)?;
+                self.push(val)?;
+            }
+            Bytecode::Inject => return Err(VmErrorKind::Unsupported("inject")),
+            Bytecode::GetGlobal(id) => {
+                let val = self.globals.get(id)?;
+                self.push(val)?;
+            }
+            Bytecode::SetGlobal(id) => {
+                let val = self.pop()?;
+                self.globals.set(id, val)?;
+            }
+            Bytecode::DefineGlobal(id) => {
+                let val = self.pop()?;
+                self.globals.define(id, val)?;
             }
         }
+        Ok(true)
+    }
+    fn push(&mut self, value: Value) -> Result<(), VmErrorKind> {
+        self.eval_stack.push(value).ok_or(VmErrorKind::StackOverflow(vec![]))
+    }
+    fn pop(&mut self) -> Result<Value, VmErrorKind> {
+        self.eval_stack.pop().ok_or(VmErrorKind::StackUnderflow)
+    }
+    fn pop_numeric(&mut self) -> Result<Numeric, VmErrorKind> {
+        let value = self.pop()?;
+        value.get_numeric().ok_or(VmErrorKind::TypeError { expected: "number", found: value.type_name() })
+    }
+    fn arithmetic(&mut self, operator: ArithmeticOperator) -> Result<(), VmErrorKind> {
+        let val_1 = self.pop_numeric()?;
+        let val_2 = self.pop_numeric()?;
+        let result = val_2.operate(val_1, operator)?;
+        self.push(Value::from_numeric(result))
+    }
+    // NaN is unordered, so it compares as not less, not equal and not greater than anything
+    fn compare_numbers(&mut self) -> Result<Option<Ordering>, VmErrorKind> {
+        let val_1 = self.pop_numeric()?;
+        let val_2 = self.pop_numeric()?;
+        Ok(val_2.compare(val_1))
     }
     pub fn read_byte(&mut self) -> Bytecode {
-        let bytecode = self.bytecode.get(self.current_index).unwrap();
+        let bytecode = self.bytecode[self.current_index].clone();
         self.current_index += 1;
-        bytecode.clone()
+        bytecode
+    }
+    // for both the locals and the eval stack
+    pub fn set_max_stack(&mut self, max: usize) {
+        self.local_stack.set_max(max);
+        self.eval_stack.set_max(max);
     }
     pub fn clear_stack(&mut self) {
         self.local_stack.clear();
         self.eval_stack.clear();
     }
+    // the id global instructions use for `name`, the same name always gets the same id
+    pub fn intern(&mut self, name: &str) -> usize {
+        self.globals.intern(name)
+    }
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        self.globals.get_by_name(name)
+    }
+    // defines the global if it doesn't exist yet
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        self.globals.set_by_name(name, value);
+    }
 }
 pub fn test_vm() {
     println!("testing vm!");
@@ -164,6 +188,9 @@ pub fn test_vm() {
         TestEqual,
         Print,
     ]);
-    vm.run();
+    match vm.run() {
+        Ok(value) => println!("finished with {}", value),
+        Err(err) => println!("runtime error: {}", err),
+    }
     println!("end of vm test!");
 }
\ No newline at end of file
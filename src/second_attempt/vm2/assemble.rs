@@ -0,0 +1,364 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use crate::second_attempt::vm2::bytecode::{convert_thing, opcode_from_name, opcode_info, OperandKind, OPERAND_SIZE, POP_LOCAL, PUSH_LOCAL};
+use crate::second_attempt::vm2::{Chunk, DebugInfo, LocalInfo, Vm};
+use crate::second_attempt::vm2::misc::Value;
+use crate::second_attempt::vm2::verify::VerifyError;
+use crate::second_attempt::vm_error::VmErrorKind;
+
+/*
+Reads the `.tbla` assembly format back into chunks, the same text `disassemble` writes plus a few
+things that make it nicer to write by hand
+
+    ; a comment, runs to the end of the line
+    .chunk counter              ; starts a chunk, the name is optional and becomes its debug name
+    .constants
+        0
+        10
+        "done"                  ; strings are allocated in the vm, `table(3)` is a raw handle
+    .code
+        CONSTANT 0
+        PUSH_LOCAL i            ; names the local it pushes
+    loop:
+        GET_LOCAL i             ; a local's name or how far down the locals stack it is
+        CONSTANT 1
+        TEST_LESS
+        TEST_NOT
+        JUMP_IF end             ; a label or an absolute address
+        ...
+        JUMP loop
+    end:
+        POP_LOCAL
+        .byte 0xff              ; any byte, as is
+
+Local names follow the code in the order it's written, the same way scopes work in the compiler:
+PUSH_LOCAL with a name puts it on top, POP_LOCAL takes the top one off again, and a name always
+means the closest one pushed before it. Named locals end up in the chunk's debug info.
+
+Nothing is verified here, so broken chunks can be written on purpose, `Vm::load` still checks
+everything before it runs.
+ */
+pub fn assemble(source: &str, vm: &mut Vm) -> Result<Vec<Chunk>, AssembleError> {
+    let mut chunks = vec![];
+    let mut builder: Option<ChunkBuilder> = None;
+    for (index, line) in source.lines().enumerate() {
+        let line_number = index + 1;
+        let error = |kind| AssembleError { line: line_number, kind };
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        // a directive and whatever follows it
+        let (directive, rest) = match line.split_once(char::is_whitespace) {
+            Some((directive, rest)) => (directive, rest.trim()),
+            None => (line, ""),
+        };
+        if directive == ".chunk" {
+            if let Some(builder) = builder.take() {
+                chunks.push(builder.finish()?);
+            }
+            builder = Some(ChunkBuilder::new(rest));
+            continue;
+        }
+        let builder = builder.get_or_insert_with(|| ChunkBuilder::new(""));
+        match directive {
+            ".constants" if rest.is_empty() => builder.section = Section::Constants,
+            ".code" if rest.is_empty() => builder.section = Section::Code,
+            ".byte" => {
+                let byte = parse_byte(rest).ok_or_else(|| error(AssembleErrorKind::BadOperand(rest.to_string())))?;
+                builder.code(line_number)?.push(byte);
+            }
+            _ if directive.starts_with('.') => {
+                return Err(error(AssembleErrorKind::UnknownDirective(line.to_string())));
+            }
+            _ if builder.section == Section::Constants => {
+                let value = parse_constant(line, vm).map_err(error)?;
+                builder.constants.push(value);
+            }
+            _ => match line.strip_suffix(':') {
+                Some(label) => builder.label(label.trim(), line_number)?,
+                None => builder.instruction(line, line_number)?,
+            },
+        }
+    }
+    if let Some(builder) = builder {
+        chunks.push(builder.finish()?);
+    }
+    Ok(chunks)
+}
+// the first chunk is the entry point so it goes on top, the rest run after it returns
+impl Vm {
+    pub fn load_assembly<P: AsRef<Path>>(&mut self, path: P) -> Result<(), AssembleFileError> {
+        let source = std::fs::read_to_string(path)?;
+        let chunks = assemble(source.as_str(), self)?;
+        for chunk in chunks.into_iter().rev() {
+            self.load(chunk)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AssembleError {
+    pub line: usize,
+    pub kind: AssembleErrorKind,
+}
+#[derive(Clone, Debug, PartialEq)]
+pub enum AssembleErrorKind {
+    UnknownDirective(String),
+    UnknownOpcode(String),
+    BadConstant(String),
+    BadOperand(String),
+    MissingOperand(&'static str),
+    UnexpectedOperand(&'static str),
+    UndefinedLabel(String),
+    DuplicateLabel(String),
+    UnknownLocal(String),
+    // POP_LOCAL with nothing pushed in this chunk, so a name can't be taken off
+    NoLocalToPop,
+    // instructions, labels and `.byte` only go after `.code`
+    OutsideCode,
+    // a string constant went over a table limit
+    Vm(VmErrorKind),
+}
+impl Display for AssembleError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: ", self.line)?;
+        match &self.kind {
+            AssembleErrorKind::UnknownDirective(directive) => write!(f, "unknown directive `{}`", directive),
+            AssembleErrorKind::UnknownOpcode(name) => write!(f, "unknown opcode `{}`", name),
+            AssembleErrorKind::BadConstant(literal) => write!(f, "`{}` isn't a constant", literal),
+            AssembleErrorKind::BadOperand(operand) => write!(f, "`{}` isn't a valid operand here", operand),
+            AssembleErrorKind::MissingOperand(name) => write!(f, "{} needs an operand", name),
+            AssembleErrorKind::UnexpectedOperand(name) => write!(f, "{} doesn't take an operand", name),
+            AssembleErrorKind::UndefinedLabel(label) => write!(f, "label `{}` is never defined", label),
+            AssembleErrorKind::DuplicateLabel(label) => write!(f, "label `{}` is defined twice", label),
+            AssembleErrorKind::UnknownLocal(name) => write!(f, "no local named `{}`", name),
+            AssembleErrorKind::NoLocalToPop => write!(f, "POP_LOCAL but this chunk hasn't pushed a local"),
+            AssembleErrorKind::OutsideCode => write!(f, "code before `.code`"),
+            AssembleErrorKind::Vm(kind) => write!(f, "{}", kind),
+        }
+    }
+}
+#[derive(Debug)]
+pub enum AssembleFileError {
+    Io(std::io::Error),
+    Assemble(AssembleError),
+    Invalid(VerifyError),
+}
+impl Display for AssembleFileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AssembleFileError::Io(err) => write!(f, "io error: {}", err),
+            AssembleFileError::Assemble(err) => write!(f, "{}", err),
+            AssembleFileError::Invalid(err) => write!(f, "chunk failed verification: {}", err),
+        }
+    }
+}
+impl From<std::io::Error> for AssembleFileError {
+    fn from(err: std::io::Error) -> Self {
+        AssembleFileError::Io(err)
+    }
+}
+impl From<AssembleError> for AssembleFileError {
+    fn from(err: AssembleError) -> Self {
+        AssembleFileError::Assemble(err)
+    }
+}
+impl From<VerifyError> for AssembleFileError {
+    fn from(err: VerifyError) -> Self {
+        AssembleFileError::Invalid(err)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Section {
+    None,
+    Constants,
+    Code,
+}
+struct ChunkBuilder {
+    name: String,
+    section: Section,
+    constants: Vec<Value>,
+    instructions: Vec<u8>,
+    labels: HashMap<String, usize>,
+    // (where the operand goes, label, line) for jumps to labels that might come later
+    fixups: Vec<(usize, String, usize)>,
+    // the locals this chunk has pushed so far, bottom first, with where each one became live
+    locals: Vec<(Option<String>, usize)>,
+    named_locals: Vec<LocalInfo>,
+}
+impl ChunkBuilder {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            section: Section::None,
+            constants: vec![],
+            instructions: vec![],
+            labels: HashMap::new(),
+            fixups: vec![],
+            locals: vec![],
+            named_locals: vec![],
+        }
+    }
+    fn code(&mut self, line: usize) -> Result<&mut Vec<u8>, AssembleError> {
+        match self.section {
+            Section::Code => Ok(&mut self.instructions),
+            _ => Err(AssembleError { line, kind: AssembleErrorKind::OutsideCode }),
+        }
+    }
+    fn label(&mut self, label: &str, line: usize) -> Result<(), AssembleError> {
+        let ip = self.code(line)?.len();
+        if self.labels.insert(label.to_string(), ip).is_some() {
+            return Err(AssembleError { line, kind: AssembleErrorKind::DuplicateLabel(label.to_string()) });
+        }
+        Ok(())
+    }
+    fn instruction(&mut self, line_text: &str, line: usize) -> Result<(), AssembleError> {
+        let error = |kind| AssembleError { line, kind };
+        let (name, operand) = match line_text.split_once(char::is_whitespace) {
+            Some((name, operand)) => (name, Some(operand.trim())),
+            None => (line_text, None),
+        };
+        let (opcode, kind) = opcode_from_name(name).ok_or_else(|| error(AssembleErrorKind::UnknownOpcode(name.to_string())))?;
+        // the name as it's spelled in the opcode table, for the error messages
+        let name = opcode_info(opcode).unwrap().0;
+        self.code(line)?.push(opcode);
+        let ip = self.instructions.len();
+        match (opcode, kind, operand) {
+            (PUSH_LOCAL, _, operand) => {
+                if let Some(local) = operand.filter(|local| !is_identifier(local)) {
+                    return Err(error(AssembleErrorKind::BadOperand(local.to_string())));
+                }
+                self.locals.push((operand.map(str::to_string), ip));
+            }
+            (POP_LOCAL, _, None) => {
+                let (local, live_start) = self.locals.pop().ok_or_else(|| error(AssembleErrorKind::NoLocalToPop))?;
+                self.end_local(local, live_start, ip - 1);
+            }
+            (_, OperandKind::None, None) => {}
+            (_, OperandKind::None, Some(_)) => return Err(error(AssembleErrorKind::UnexpectedOperand(name))),
+            (_, _, None) => return Err(error(AssembleErrorKind::MissingOperand(name))),
+            (_, kind, Some(operand)) => {
+                let value = match (kind, operand.parse::<usize>()) {
+                    (_, Ok(value)) => value,
+                    (OperandKind::Jump, Err(_)) if is_identifier(operand) => {
+                        self.fixups.push((ip, operand.to_string(), line));
+                        0
+                    }
+                    (OperandKind::Local, Err(_)) if is_identifier(operand) => {
+                        let slot = self.locals.iter()
+                            .rposition(|(local, _)| local.as_deref() == Some(operand))
+                            .ok_or_else(|| error(AssembleErrorKind::UnknownLocal(operand.to_string())))?;
+                        self.locals.len() - 1 - slot
+                    }
+                    _ => return Err(error(AssembleErrorKind::BadOperand(operand.to_string()))),
+                };
+                self.instructions.extend(convert_thing(value));
+            }
+        }
+        Ok(())
+    }
+    // a named local is live from just after its PUSH_LOCAL up to the POP_LOCAL that takes it off
+    fn end_local(&mut self, local: Option<String>, live_start: usize, live_end: usize) {
+        if let Some(name) = local {
+            self.named_locals.push(LocalInfo {
+                name,
+                slot: self.locals.len(),
+                live: live_start..live_end,
+            });
+        }
+    }
+    fn finish(mut self) -> Result<Chunk, AssembleError> {
+        for (position, label, line) in std::mem::take(&mut self.fixups) {
+            let target = *self.labels.get(&label).ok_or(AssembleError { line, kind: AssembleErrorKind::UndefinedLabel(label) })?;
+            self.instructions[position..position + OPERAND_SIZE].copy_from_slice(&convert_thing(target));
+        }
+        // anything still pushed stays live to the end
+        let end = self.instructions.len();
+        while let Some((local, live_start)) = self.locals.pop() {
+            self.end_local(local, live_start, end);
+        }
+        let chunk = Chunk::new(self.instructions, self.constants);
+        if self.name.is_empty() && self.named_locals.is_empty() {
+            return Ok(chunk);
+        }
+        self.named_locals.sort_by_key(|local| local.live.start);
+        Ok(chunk.with_debug(DebugInfo {
+            name: self.name,
+            spans: vec![],
+            locals: self.named_locals,
+        }))
+    }
+}
+
+// everything after a `;` that isn't inside a string
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (index, char) in line.char_indices() {
+        match char {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ';' if !in_string => return &line[..index],
+            _ => {}
+        }
+    }
+    line
+}
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    chars.next().map_or(false, |char| char.is_alphabetic() || char == '_')
+        && chars.all(|char| char.is_alphanumeric() || char == '_')
+}
+fn parse_byte(text: &str) -> Option<u8> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+// every literal `constant_literal` writes, plus strings
+fn parse_constant(text: &str, vm: &mut Vm) -> Result<Value, AssembleErrorKind> {
+    let bad = || AssembleErrorKind::BadConstant(text.to_string());
+    match text {
+        "nil" => return Ok(Value::Nil),
+        "true" => return Ok(Value::Boolean(true)),
+        "false" => return Ok(Value::Boolean(false)),
+        _ => {}
+    }
+    if let Some(handle) = text.strip_prefix("table(").and_then(|text| text.strip_suffix(')')) {
+        return handle.parse().map(Value::Table).map_err(|_| bad());
+    }
+    if let Some(string) = text.strip_prefix('"').and_then(|text| text.strip_suffix('"')) {
+        let string = unescape(string).ok_or_else(bad)?;
+        return vm.alloc_string(string).map_err(AssembleErrorKind::Vm);
+    }
+    if let Ok(num) = text.parse::<i64>() {
+        return Ok(Value::Number(num));
+    }
+    text.parse::<f64>().map(Value::Float).map_err(|_| bad())
+}
+fn unescape(text: &str) -> Option<String> {
+    let mut string = String::new();
+    let mut chars = text.chars();
+    while let Some(char) = chars.next() {
+        string.push(match char {
+            '\\' => match chars.next()? {
+                'n' => '\n',
+                't' => '\t',
+                'r' => '\r',
+                '0' => '\0',
+                '\\' => '\\',
+                '"' => '"',
+                _ => return None,
+            },
+            // an unescaped quote would have ended the string
+            '"' => return None,
+            char => char,
+        });
+    }
+    Some(string)
+}
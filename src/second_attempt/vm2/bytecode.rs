@@ -27,6 +27,122 @@ pub const INSERT_INDEX_TABLE: u8 = 0x18;
 pub const INSERT_STR_TABLE: u8 = 0x19;
 pub const GET_INDEX_TABLE: u8 = 0x1A;
 pub const GET_STR_TABLE: u8 = 0x1B;
+pub const PUSH_HANDLER: u8 = 0x1C;
+pub const POP_HANDLER: u8 = 0x1D;
+pub const THROW: u8 = 0x1E;
+pub const NEW_COROUTINE: u8 = 0x1F;
+pub const RESUME: u8 = 0x20;
+pub const YIELD: u8 = 0x21;
+pub const FOR_NEXT: u8 = 0x22;
+pub const POP: u8 = 0x23;
+pub const CALL: u8 = 0x24;
+pub const GET_GLOBAL: u8 = 0x25;
+pub const SET_GLOBAL: u8 = 0x26;
+pub const DEFINE_GLOBAL: u8 = 0x27;
+pub const TABLE_LEN: u8 = 0x28;
+pub const TABLE_NEXT: u8 = 0x29;
+pub const TABLE_APPEND: u8 = 0x2A;
+pub const TABLE_REMOVE: u8 = 0x2B;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OperandKind {
+    None,
+    Constant,
+    Local,
+    Jump,
+    Index,
+}
+// every opcode with its name and what its inline operand means, if it has one
+pub const OPCODES: &[(u8, &str, OperandKind)] = &[
+    (CONSTANT, "CONSTANT", OperandKind::Constant),
+    (GET_LOCAL, "GET_LOCAL", OperandKind::Local),
+    (SET_LOCAL, "SET_LOCAL", OperandKind::Local),
+    (PEEK_LOCAL, "PEEK_LOCAL", OperandKind::Local),
+    (POP_LOCAL, "POP_LOCAL", OperandKind::None),
+    (PUSH_LOCAL, "PUSH_LOCAL", OperandKind::None),
+    (TEST_TRUTHY, "TEST_TRUTHY", OperandKind::None),
+    (TEST_EQUAL, "TEST_EQUAL", OperandKind::None),
+    (TEST_LESS, "TEST_LESS", OperandKind::None),
+    (TEST_LESS_EQUAL, "TEST_LESS_EQUAL", OperandKind::None),
+    (TEST_GREATER, "TEST_GREATER", OperandKind::None),
+    (TEST_GREATER_EQUAL, "TEST_GREATER_EQUAL", OperandKind::None),
+    (TEST_NOT, "TEST_NOT", OperandKind::None),
+    (INJECT, "INJECT", OperandKind::Index),
+    (JUMP, "JUMP", OperandKind::Jump),
+    (JUMP_IF, "JUMP_IF", OperandKind::Jump),
+    (ADD, "ADD", OperandKind::None),
+    (SUBTRACT, "SUBTRACT", OperandKind::None),
+    (MULTIPLY, "MULTIPLY", OperandKind::None),
+    (DIVIDE, "DIVIDE", OperandKind::None),
+    (PRINT, "PRINT", OperandKind::None),
+    (RETURN, "RETURN", OperandKind::None),
+    (ALLOC_TABLE, "ALLOC_TABLE", OperandKind::None),
+    (INSERT_INDEX_TABLE, "INSERT_INDEX_TABLE", OperandKind::None),
+    (INSERT_STR_TABLE, "INSERT_STR_TABLE", OperandKind::None),
+    (GET_INDEX_TABLE, "GET_INDEX_TABLE", OperandKind::None),
+    (GET_STR_TABLE, "GET_STR_TABLE", OperandKind::None),
+    (PUSH_HANDLER, "PUSH_HANDLER", OperandKind::Jump),
+    (POP_HANDLER, "POP_HANDLER", OperandKind::None),
+    (THROW, "THROW", OperandKind::None),
+    (NEW_COROUTINE, "NEW_COROUTINE", OperandKind::None),
+    (RESUME, "RESUME", OperandKind::None),
+    (YIELD, "YIELD", OperandKind::None),
+    (FOR_NEXT, "FOR_NEXT", OperandKind::Jump),
+    (POP, "POP", OperandKind::None),
+    (CALL, "CALL", OperandKind::Index),
+    (GET_GLOBAL, "GET_GLOBAL", OperandKind::Constant),
+    (SET_GLOBAL, "SET_GLOBAL", OperandKind::Constant),
+    (DEFINE_GLOBAL, "DEFINE_GLOBAL", OperandKind::Constant),
+    (TABLE_LEN, "TABLE_LEN", OperandKind::None),
+    (TABLE_NEXT, "TABLE_NEXT", OperandKind::Jump),
+    (TABLE_APPEND, "TABLE_APPEND", OperandKind::None),
+    (TABLE_REMOVE, "TABLE_REMOVE", OperandKind::None),
+];
+pub fn opcode_info(opcode: u8) -> Option<(&'static str, OperandKind)> {
+    OPCODES.iter().find(|(code, _, _)| *code == opcode).map(|(_, name, kind)| (*name, *kind))
+}
+pub fn opcode_from_name(name: &str) -> Option<(u8, OperandKind)> {
+    OPCODES.iter().find(|(_, code_name, _)| *code_name == name).map(|(code, _, kind)| (*code, *kind))
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Instruction {
+    pub ip: usize,
+    pub opcode: u8,
+    pub operand: Option<usize>,
+}
+impl Instruction {
+    pub fn len(&self) -> usize {
+        match self.operand {
+            None => 1,
+            Some(_) => 1 + OPERAND_SIZE,
+        }
+    }
+    pub fn next_ip(&self) -> usize {
+        self.ip + self.len()
+    }
+}
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    InvalidOpcode(u8),
+    TruncatedOperand,
+}
+pub fn decode(instructions: &[u8], ip: usize) -> Result<Instruction, DecodeError> {
+    let opcode = *instructions.get(ip).ok_or(DecodeError::TruncatedOperand)?;
+    let (_, kind) = opcode_info(opcode).ok_or(DecodeError::InvalidOpcode(opcode))?;
+    let operand = match kind {
+        OperandKind::None => None,
+        _ => {
+            let bytes = instructions.get(ip + 1..ip + 1 + OPERAND_SIZE).ok_or(DecodeError::TruncatedOperand)?;
+            Some(convert_back(bytes))
+        }
+    };
+    Ok(Instruction {
+        ip,
+        opcode,
+        operand
+    })
+}
 
 pub enum Bytecode {
     Constant(usize),
@@ -56,21 +172,35 @@ pub enum Bytecode {
     InsertStringTable, // pops the index into the String table for the key, and then pops value to put *into* the table at that index.
     GetIndexTable, // pops the table index, and the index *into* the table and pushes the value found.
     GetStringTable, // pops the table index and the index into the table string that indexes into the table for the value found.
+    PushHandler(usize), // starts a `try`, a throw before the matching PopHandler unwinds to here and jumps to the operand with the thrown value pushed.
+    PopHandler, // ends a `try` that finished without throwing.
+    Throw, // pops a value and unwinds to the innermost handler, it's a runtime error if there isn't one.
+    NewCoroutine, // pops a function, or a table with `constants` and `instructions` like Inject does, and pushes a coroutine running that chunk. A function gets copies of the top locals as its arguments.
+    Resume, // pops the value to send and the coroutine, once it yields or finishes pushes the value it gave and whether it finished.
+    Yield, // pops a value and hands it to whoever resumed the current coroutine, pushes the value the next resume sends.
+    ForNext(usize), // resumes the coroutine on top of the stack without popping it, pushes what it yields or jumps to the operand once it's finished.
+    Pop,
+    Call(usize), // pops the operand's worth of arguments and then the function, pushes what the function returns.
+    GetGlobal(usize), // the operand is the constant holding the global's name.
+    SetGlobal(usize), // pops the value into a global that's already defined.
+    DefineGlobal(usize), // pops the value, defining the global or replacing what it was.
+    TableLen, // pops a table and pushes how many entries it has.
+    TableNext(usize), // steps the table and cursor on top of the stack, pushing the next key and value or jumping to the operand once there are none left.
+    TableAppend, // pops a table and then a value, which goes one past the table's highest index.
+    TableRemove, // pops a table and then a key, pushes the value that was removed or nil.
 }
 fn represent(a: u8, val: usize) -> Vec<u8> {
     let mut vec = vec![a];
     vec.append(&mut convert_thing(val));
     vec
 }
+// operands are always 8 byte little endian so instruction streams are portable between machines
+pub const OPERAND_SIZE: usize = size_of::<u64>();
 pub fn convert_thing(val: usize) -> Vec<u8> {
-    unsafe {
-        std::mem::transmute_copy::<usize, [u8; size_of::<usize>()]>(&val).to_vec()
-    }
+    (val as u64).to_le_bytes().to_vec()
 }
 pub fn convert_back(val: &[u8]) -> usize {
-    unsafe {
-        std::mem::transmute_copy::<[u8; size_of::<usize>()], usize>(&val.try_into().unwrap())
-    }
+    u64::from_le_bytes(val.try_into().unwrap()) as usize
 }
 pub fn convert_bytecode_array(bytecode: Vec<Bytecode>) -> Vec<u8> {
     let mut ret_vec = vec![];
@@ -80,6 +210,15 @@ pub fn convert_bytecode_array(bytecode: Vec<Bytecode>) -> Vec<u8> {
     ret_vec
 }
 impl Bytecode {
+    // how many bytes `to_bytes` will produce, for working out jump targets before encoding
+    pub fn size(&self) -> usize {
+        match self {
+            Bytecode::Constant(_) | Bytecode::GetLocal(_) | Bytecode::SetLocal(_) | Bytecode::PeekLocal(_)
+            | Bytecode::Inject(_) | Bytecode::Jump(_) | Bytecode::JumpIf(_) | Bytecode::PushHandler(_) | Bytecode::ForNext(_)
+            | Bytecode::Call(_) | Bytecode::GetGlobal(_) | Bytecode::SetGlobal(_) | Bytecode::DefineGlobal(_) | Bytecode::TableNext(_) => 1 + OPERAND_SIZE,
+            _ => 1,
+        }
+    }
     pub fn to_bytes(self) -> Vec<u8> {
         match self {
             Bytecode::Constant(val) => represent(CONSTANT, val),
@@ -109,6 +248,22 @@ impl Bytecode {
             Bytecode::InsertStringTable => vec![INSERT_STR_TABLE],
             Bytecode::GetIndexTable => vec![GET_INDEX_TABLE],
             Bytecode::GetStringTable => vec![GET_STR_TABLE],
+            Bytecode::PushHandler(val) => represent(PUSH_HANDLER, val),
+            Bytecode::PopHandler => vec![POP_HANDLER],
+            Bytecode::Throw => vec![THROW],
+            Bytecode::NewCoroutine => vec![NEW_COROUTINE],
+            Bytecode::Resume => vec![RESUME],
+            Bytecode::Yield => vec![YIELD],
+            Bytecode::ForNext(val) => represent(FOR_NEXT, val),
+            Bytecode::Pop => vec![POP],
+            Bytecode::Call(val) => represent(CALL, val),
+            Bytecode::GetGlobal(val) => represent(GET_GLOBAL, val),
+            Bytecode::SetGlobal(val) => represent(SET_GLOBAL, val),
+            Bytecode::DefineGlobal(val) => represent(DEFINE_GLOBAL, val),
+            Bytecode::TableLen => vec![TABLE_LEN],
+            Bytecode::TableNext(val) => represent(TABLE_NEXT, val),
+            Bytecode::TableAppend => vec![TABLE_APPEND],
+            Bytecode::TableRemove => vec![TABLE_REMOVE],
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,83 @@
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use crate::second_attempt::vm2::Vm;
+use crate::second_attempt::vm2::misc::{Table, TableKey, Value};
+use crate::second_attempt::vm_error::VmErrorKind;
+
+/*
+Equality and ordering. TEST_EQUAL is defined for every pair of values and never fails: numbers
+compare by value (so `1 == 1.0`), strings by their contents, any other table only to itself, and
+values of different types are never equal. Ordering works on numbers and on strings, which sort by
+bytes, anything else is a type error.
+
+`deep_equal` is the structural version for the `deep_equal` builtin, it compares what's inside
+tables too. A map and an array are equal when the map's keys are exactly the array's positions.
+ */
+impl Vm {
+    pub fn values_equal(&self, lhs: Value, rhs: Value) -> bool {
+        if let (Some(lhs), Some(rhs)) = (self.string(lhs).ok(), self.string(rhs).ok()) {
+            return lhs == rhs;
+        }
+        lhs.equals(rhs)
+    }
+    // None when neither is less, like NaN against anything
+    pub fn compare_values(&self, lhs: Value, rhs: Value) -> Result<Option<Ordering>, VmErrorKind> {
+        if let (Some(lhs), Some(rhs)) = (lhs.get_numeric(), rhs.get_numeric()) {
+            return Ok(lhs.compare(rhs));
+        }
+        match (self.string(lhs), self.string(rhs)) {
+            (Ok(lhs), Ok(rhs)) => Ok(Some(lhs.cmp(rhs))),
+            (Ok(_), Err(_)) => Err(VmErrorKind::TypeError { expected: "string", found: self.type_name(rhs) }),
+            _ if lhs.get_numeric().is_some() => Err(VmErrorKind::TypeError { expected: "number", found: self.type_name(rhs) }),
+            _ => Err(VmErrorKind::TypeError { expected: "number or string", found: self.type_name(lhs) }),
+        }
+    }
+    // pairs of values still to compare go on `work` instead of the native stack, so nesting can go as
+    // deep as memory allows. `seen` holds the pairs of tables already compared or waiting to be, so a
+    // cycle compares equal to a cycle of the same shape instead of going round forever
+    pub fn deep_equal(&self, lhs: Value, rhs: Value) -> bool {
+        let mut work = vec![(lhs, rhs)];
+        let mut seen = HashSet::new();
+        while let Some((lhs, rhs)) = work.pop() {
+            let (Value::Table(lhs_handle), Value::Table(rhs_handle)) = (lhs, rhs) else {
+                if !lhs.equals(rhs) {
+                    return false;
+                }
+                continue;
+            };
+            if lhs_handle == rhs_handle || !seen.insert((lhs_handle, rhs_handle)) {
+                continue;
+            }
+            let equal = match (self.tables.get(lhs_handle), self.tables.get(rhs_handle)) {
+                (Some(Table::String(lhs)), Some(Table::String(rhs))) => lhs == rhs,
+                (Some(Table::Array(lhs)), Some(Table::Array(rhs))) => {
+                    work.extend(lhs.iter().copied().zip(rhs.iter().copied()));
+                    lhs.len() == rhs.len()
+                }
+                (Some(Table::Map(lhs)), Some(Table::Map(rhs))) => {
+                    lhs.len() == rhs.len() && lhs.iter().all(|(key, lhs)| {
+                        rhs.get(key).map(|rhs| work.push((*lhs, *rhs))).is_some()
+                    })
+                }
+                (Some(Table::Map(map)), Some(Table::Array(values))) | (Some(Table::Array(values)), Some(Table::Map(map))) => {
+                    map.len() == values.len() && values.iter().enumerate().all(|(index, value)| {
+                        map.get(&TableKey::NoIdentifier(index)).map(|entry| work.push((*entry, *value))).is_some()
+                    })
+                }
+                // functions, natives and coroutines are only equal to themselves
+                _ => false,
+            };
+            if !equal {
+                return false;
+            }
+        }
+        true
+    }
+    // tables by what kind they are, so a string isn't reported as just a table
+    fn type_name(&self, value: Value) -> &'static str {
+        match value {
+            Value::Table(handle) => self.tables.get(handle).map_or("table", Table::type_name),
+            value => value.type_name(),
+        }
+    }
+}
@@ -0,0 +1,481 @@
+use std::fmt::{Display, Formatter};
+use crate::second_attempt::ir::{BinaryOperation, BinaryOperator, Block, Exp, File, FnCall, FnDef, ForLoop, Identifier, LetStatement, NormalFnDef, Statement, TableKey, TryCatch};
+use crate::second_attempt::ir;
+use crate::second_attempt::lexer::Span;
+use crate::second_attempt::vm2::{Chunk, DebugInfo, LocalInfo, Vm};
+use crate::second_attempt::vm2::bytecode::{convert_bytecode_array, Bytecode};
+use crate::second_attempt::vm2::function::Function;
+use crate::second_attempt::vm2::misc::Value;
+use crate::second_attempt::vm2::verify::VerifyError;
+use crate::second_attempt::vm_error::VmErrorKind;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CompileError {
+    // functions can only see their own locals and globals
+    CapturedLocal(Identifier),
+    // the top level isn't a function, so there's no coroutine to yield from
+    TopLevelYield,
+    Invalid(VerifyError),
+    // the vm refused a function or string, it didn't verify or went over a table limit
+    Vm(VmErrorKind),
+}
+impl Display for CompileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompileError::CapturedLocal(identifier) => write!(f, "`{}` is a local of an enclosing function, only globals can be captured", identifier),
+            CompileError::TopLevelYield => write!(f, "`yield` can only be used inside a function"),
+            CompileError::Invalid(err) => write!(f, "compiled a chunk that doesn't verify: {}", err),
+            CompileError::Vm(kind) => write!(f, "{}", kind),
+        }
+    }
+}
+impl From<VerifyError> for CompileError {
+    fn from(err: VerifyError) -> Self {
+        CompileError::Invalid(err)
+    }
+}
+impl From<VmErrorKind> for CompileError {
+    fn from(kind: VmErrorKind) -> Self {
+        CompileError::Vm(kind)
+    }
+}
+
+/*
+Compiles a file into the chunk that runs its top level. Functions and string constants are
+allocated in `vm` as they're compiled, so the chunk only makes sense on that vm.
+
+`let` and `fn` at the top level of the file define globals, anywhere else they're locals.
+A name that isn't a local is looked up as a global when it runs, so functions can call each
+other in any order and host functions only need to be defined before they're called.
+`import fn` is just a declaration, the host defines the function.
+
+Locals are addressed from the top of the locals stack, so the compiler keeps track of every value
+it has pushed there, including temporaries that don't have a name.
+
+A function with a `yield` in its own body is a generator: calling it starts a coroutine running
+the body with the arguments it was called with, which `for` then resumes until it finishes.
+
+Every chunk gets debug info: each `let` and call marks where its instructions start with its span,
+and every named local, parameters included, is recorded with the instructions it's live for.
+ */
+pub fn compile(file: File, vm: &mut Vm) -> Result<Chunk, CompileError> {
+    let mut compiler = Compiler::new(vm, "main", vec![], true);
+    match file {
+        File::Block(block) => compiler.block(block)?,
+        File::None => compiler.constant(Value::Nil),
+    }
+    Ok(compiler.finish())
+}
+
+struct Compiler<'a> {
+    vm: &'a mut Vm,
+    name: String,
+    code: Vec<Bytecode>,
+    // byte offset of the end of `code`, which is where the next instruction lands
+    offset: usize,
+    constants: Vec<Value>,
+    // a slot for every value this function has on the locals stack, bottom first
+    locals: Vec<Option<Identifier>>,
+    // where each of `locals` became live, in step with it
+    live_from: Vec<usize>,
+    spans: Vec<(usize, Span)>,
+    // named locals that have been dropped again
+    local_infos: Vec<LocalInfo>,
+    // where each scope's locals start
+    scopes: Vec<usize>,
+    // locals of the functions this one is nested in
+    enclosing: Vec<Identifier>,
+    top_level: bool,
+}
+impl<'a> Compiler<'a> {
+    fn new(vm: &'a mut Vm, name: &str, enclosing: Vec<Identifier>, top_level: bool) -> Self {
+        Self {
+            vm,
+            name: name.to_string(),
+            code: vec![],
+            offset: 0,
+            constants: vec![],
+            locals: vec![],
+            live_from: vec![],
+            spans: vec![],
+            local_infos: vec![],
+            scopes: vec![0],
+            enclosing,
+            top_level,
+        }
+    }
+    fn finish(mut self) -> Chunk {
+        // whatever is still on the locals stack lives to the end of the chunk
+        while !self.locals.is_empty() {
+            self.forget_local();
+        }
+        self.local_infos.sort_by_key(|local| (local.slot, local.live.start));
+        Chunk::new(convert_bytecode_array(self.code), self.constants).with_debug(DebugInfo {
+            name: self.name,
+            spans: self.spans,
+            locals: self.local_infos,
+        })
+    }
+    // the instructions from here on belong to `span`, until something else is marked
+    fn mark(&mut self, span: Span) {
+        match self.spans.last_mut() {
+            Some((ip, last)) if *ip == self.offset => *last = span,
+            _ => self.spans.push((self.offset, span)),
+        }
+    }
+    fn emit(&mut self, bytecode: Bytecode) -> usize {
+        self.offset += bytecode.size();
+        self.code.push(bytecode);
+        self.code.len() - 1
+    }
+    // jumps are emitted with a zero target and patched once we know where they land
+    fn patch(&mut self, index: usize) {
+        let target = self.offset;
+        self.code[index] = match self.code[index] {
+            Bytecode::Jump(_) => Bytecode::Jump(target),
+            Bytecode::JumpIf(_) => Bytecode::JumpIf(target),
+            Bytecode::PushHandler(_) => Bytecode::PushHandler(target),
+            Bytecode::ForNext(_) => Bytecode::ForNext(target),
+            _ => unreachable!(),
+        };
+    }
+    fn add_constant(&mut self, value: Value) -> usize {
+        self.constants.push(value);
+        self.constants.len() - 1
+    }
+    fn constant(&mut self, value: Value) {
+        let index = self.add_constant(value);
+        self.emit(Bytecode::Constant(index));
+    }
+    fn name_constant(&mut self, name: &str) -> Result<usize, CompileError> {
+        let name = self.vm.alloc_string(name.to_string())?;
+        Ok(self.add_constant(name))
+    }
+    // moves the value on top of the eval stack onto the locals stack
+    fn push_local(&mut self, identifier: Option<Identifier>) {
+        self.emit(Bytecode::PushLocal);
+        self.locals.push(identifier);
+        self.live_from.push(self.offset);
+    }
+    // takes the top local off the compiler's books, the caller emits whatever removes it
+    fn forget_local(&mut self) {
+        let identifier = self.locals.pop().unwrap();
+        let start = self.live_from.pop().unwrap();
+        if let Some(name) = identifier {
+            self.local_infos.push(LocalInfo {
+                name,
+                slot: self.locals.len(),
+                live: start..self.offset,
+            });
+        }
+    }
+    fn get_local(&mut self, slot: usize) {
+        self.emit(Bytecode::GetLocal(self.locals.len() - 1 - slot));
+    }
+    // takes the top local back off and throws it away
+    fn drop_local(&mut self) {
+        self.forget_local();
+        self.emit(Bytecode::PopLocal);
+        self.emit(Bytecode::Pop);
+    }
+    fn push_scope(&mut self) {
+        self.scopes.push(self.locals.len());
+    }
+    fn pop_scope(&mut self) {
+        let start = self.scopes.pop().unwrap();
+        while self.locals.len() > start {
+            self.drop_local();
+        }
+    }
+    fn is_global_scope(&self) -> bool {
+        self.top_level && self.scopes.len() == 1
+    }
+    fn variable(&mut self, identifier: Identifier) -> Result<(), CompileError> {
+        if let Some(slot) = self.locals.iter().rposition(|local| local.as_ref() == Some(&identifier)) {
+            self.get_local(slot);
+            return Ok(());
+        }
+        if self.enclosing.contains(&identifier) {
+            return Err(CompileError::CapturedLocal(identifier));
+        }
+        let name = self.name_constant(identifier.as_str())?;
+        self.emit(Bytecode::GetGlobal(name));
+        Ok(())
+    }
+    // binds the value on top of the eval stack to `identifier`
+    fn declare(&mut self, identifier: Identifier) -> Result<(), CompileError> {
+        if self.is_global_scope() {
+            let name = self.name_constant(identifier.as_str())?;
+            self.emit(Bytecode::DefineGlobal(name));
+        } else {
+            self.push_local(Some(identifier));
+        }
+        Ok(())
+    }
+    // leaves the block's value on the eval stack
+    fn block(&mut self, block: Block) -> Result<(), CompileError> {
+        match block {
+            Block::WithExp(statements, exp) => {
+                for statement in statements {
+                    self.statement(*statement)?;
+                }
+                self.expression(*exp)
+            }
+            Block::WithoutExp(statements) => {
+                for statement in statements {
+                    self.statement(*statement)?;
+                }
+                self.constant(Value::Nil);
+                Ok(())
+            }
+        }
+    }
+    fn statements(&mut self, statements: Vec<Box<Statement>>) -> Result<(), CompileError> {
+        self.push_scope();
+        for statement in statements {
+            self.statement(*statement)?;
+        }
+        self.pop_scope();
+        Ok(())
+    }
+    fn statement(&mut self, statement: Statement) -> Result<(), CompileError> {
+        match statement {
+            Statement::LetStatement(LetStatement { identifier, exp, span }) => {
+                self.mark(span.clone());
+                self.expression(*exp)?;
+                self.mark(span);
+                self.declare(identifier)?;
+            }
+            Statement::ExpStatement(exp) => {
+                self.expression(*exp)?;
+                self.emit(Bytecode::Pop);
+            }
+            Statement::Block(statements) => self.statements(statements)?,
+            Statement::FnDef(FnDef::FnDef(fn_def)) => {
+                let identifier = fn_def.identifier.clone();
+                let function = self.function(fn_def)?;
+                self.constant(function);
+                self.declare(identifier)?;
+            }
+            Statement::FnDef(FnDef::Imported(_)) => {}
+            Statement::TryCatch(try_catch) => self.try_catch(try_catch)?,
+            Statement::Throw(exp) => {
+                self.expression(*exp)?;
+                self.emit(Bytecode::Throw);
+            }
+            Statement::For(for_loop) => self.for_loop(for_loop)?,
+        }
+        Ok(())
+    }
+    fn function(&mut self, fn_def: NormalFnDef) -> Result<Value, CompileError> {
+        let NormalFnDef { identifier, args, body, closure_idents: _, exported } = fn_def;
+        let mut enclosing = self.enclosing.clone();
+        enclosing.extend(self.locals.iter().flatten().cloned());
+        // a local function isn't a global, so it can't call itself by name either
+        if !self.is_global_scope() {
+            enclosing.push(identifier.clone());
+        }
+        let arity = args.len();
+        let generator = block_yields(&body);
+        let chunk = {
+            let mut compiler = Compiler::new(self.vm, identifier.as_str(), enclosing, false);
+            // the arguments are already on the locals stack when the chunk starts
+            compiler.live_from = vec![0; args.len()];
+            compiler.locals = args.into_iter().map(Some).collect();
+            compiler.block(body)?;
+            compiler.emit(Bytecode::Return);
+            compiler.finish()
+        };
+        if !generator {
+            return Ok(self.vm.new_function(Function {
+                name: identifier,
+                arity,
+                exported,
+                chunk,
+            })?);
+        }
+        // the body becomes the coroutine's function, what's called only starts it
+        let body = self.vm.new_function(Function {
+            name: identifier.clone(),
+            arity,
+            exported: false,
+            chunk,
+        })?;
+        let start = Chunk::new(convert_bytecode_array(vec![Bytecode::Constant(0), Bytecode::NewCoroutine, Bytecode::Return]), vec![body])
+            .with_debug(DebugInfo {
+                name: identifier.clone(),
+                ..Default::default()
+            });
+        Ok(self.vm.new_function(Function {
+            name: identifier,
+            arity,
+            exported,
+            chunk: start,
+        })?)
+    }
+    // the coroutine stays on the eval stack under each value it yields until it's finished
+    fn for_loop(&mut self, for_loop: ForLoop) -> Result<(), CompileError> {
+        let ForLoop { identifier, exp, body } = for_loop;
+        self.expression(*exp)?;
+        let next = self.offset;
+        let for_next = self.emit(Bytecode::ForNext(0));
+        self.push_scope();
+        self.push_local(Some(identifier));
+        for statement in body {
+            self.statement(*statement)?;
+        }
+        self.pop_scope();
+        self.emit(Bytecode::Jump(next));
+        self.patch(for_next);
+        self.emit(Bytecode::Pop);
+        Ok(())
+    }
+    fn try_catch(&mut self, try_catch: TryCatch) -> Result<(), CompileError> {
+        let TryCatch { body, identifier, handler } = try_catch;
+        let push_handler = self.emit(Bytecode::PushHandler(0));
+        self.statements(body)?;
+        self.emit(Bytecode::PopHandler);
+        let jump = self.emit(Bytecode::Jump(0));
+        // a throw drops everything the body pushed, so the locals are back to what they were at the `try`
+        self.patch(push_handler);
+        self.push_scope();
+        self.push_local(Some(identifier));
+        for statement in handler {
+            self.statement(*statement)?;
+        }
+        self.pop_scope();
+        self.patch(jump);
+        Ok(())
+    }
+    fn expression(&mut self, exp: Exp) -> Result<(), CompileError> {
+        match exp {
+            Exp::Variable(identifier) => self.variable(identifier)?,
+            Exp::Value(value) => self.value(value)?,
+            Exp::BinaryOperation(binary_operation) => self.binary_operation(binary_operation)?,
+            Exp::FnCall(FnCall { identifier, args, span }) => {
+                self.mark(span.clone());
+                self.variable(identifier)?;
+                let argument_count = args.len();
+                for arg in args {
+                    self.expression(*arg)?;
+                }
+                // back to the call itself once the arguments have marked their own spans
+                self.mark(span);
+                self.emit(Bytecode::Call(argument_count));
+            }
+            Exp::Yield(exp) => {
+                if self.top_level {
+                    return Err(CompileError::TopLevelYield);
+                }
+                self.expression(*exp)?;
+                self.emit(Bytecode::Yield);
+            }
+            Exp::Block(statements, exp) => {
+                self.push_scope();
+                for statement in statements {
+                    self.statement(*statement)?;
+                }
+                self.expression(*exp)?;
+                self.pop_scope();
+            }
+        }
+        Ok(())
+    }
+    fn value(&mut self, value: ir::Value) -> Result<(), CompileError> {
+        match value {
+            ir::Value::Integer(number) => self.constant(Value::Number(number)),
+            ir::Value::Float(number) => self.constant(Value::Float(number)),
+            ir::Value::String(string) => {
+                let string = self.vm.alloc_string(string)?;
+                self.constant(string);
+            }
+            ir::Value::Table(ir::Table(entries)) => {
+                // the table sits in a local while its entries are worked out
+                self.emit(Bytecode::AllocTable);
+                self.push_local(None);
+                let slot = self.locals.len() - 1;
+                let mut position = 0;
+                for (key, exp) in entries {
+                    self.expression(*exp)?;
+                    match key {
+                        TableKey::HasString(name) => {
+                            let name = self.vm.alloc_string(name)?;
+                            self.constant(name);
+                            self.get_local(slot);
+                            self.emit(Bytecode::InsertStringTable);
+                        }
+                        TableKey::NoString => {
+                            self.constant(Value::Number(position));
+                            position += 1;
+                            self.get_local(slot);
+                            self.emit(Bytecode::InsertIndexTable);
+                        }
+                    }
+                }
+                self.forget_local();
+                self.emit(Bytecode::PopLocal);
+            }
+        }
+        Ok(())
+    }
+    fn binary_operation(&mut self, binary_operation: BinaryOperation) -> Result<(), CompileError> {
+        let BinaryOperation { left_hand_side, operator, right_hand_side } = binary_operation;
+        // `and` and `or` only evaluate the right hand side if they have to, otherwise they're the left hand side
+        if let BinaryOperator::And | BinaryOperator::Or = operator {
+            self.expression(*left_hand_side)?;
+            self.push_local(None);
+            self.get_local(self.locals.len() - 1);
+            if let BinaryOperator::And = operator {
+                self.emit(Bytecode::TestNot);
+            }
+            let short_circuit = self.emit(Bytecode::JumpIf(0));
+            self.forget_local();
+            self.emit(Bytecode::PopLocal);
+            self.emit(Bytecode::Pop);
+            self.expression(*right_hand_side)?;
+            let end = self.emit(Bytecode::Jump(0));
+            self.patch(short_circuit);
+            self.emit(Bytecode::PopLocal);
+            self.patch(end);
+            return Ok(());
+        }
+        self.expression(*left_hand_side)?;
+        self.expression(*right_hand_side)?;
+        self.emit(match operator {
+            BinaryOperator::Add => Bytecode::Add,
+            BinaryOperator::Subtract => Bytecode::Subtract,
+            BinaryOperator::Multiply => Bytecode::Multiply,
+            BinaryOperator::Divide => Bytecode::Divide,
+            BinaryOperator::EqualsEquals => Bytecode::TestEqual,
+            BinaryOperator::And | BinaryOperator::Or => unreachable!(),
+        });
+        Ok(())
+    }
+}
+
+// whether `yield` is used in a function's own body, functions defined inside it are their own
+fn block_yields(block: &Block) -> bool {
+    match block {
+        Block::WithExp(statements, exp) => statements.iter().any(|statement| statement_yields(statement)) || exp_yields(exp),
+        Block::WithoutExp(statements) => statements.iter().any(|statement| statement_yields(statement)),
+    }
+}
+fn statement_yields(statement: &Statement) -> bool {
+    match statement {
+        Statement::LetStatement(LetStatement { exp, .. }) | Statement::ExpStatement(exp) | Statement::Throw(exp) => exp_yields(exp),
+        Statement::Block(statements) => statements.iter().any(|statement| statement_yields(statement)),
+        Statement::TryCatch(TryCatch { body, handler, .. }) => body.iter().chain(handler).any(|statement| statement_yields(statement)),
+        Statement::For(ForLoop { exp, body, .. }) => exp_yields(exp) || body.iter().any(|statement| statement_yields(statement)),
+        Statement::FnDef(_) => false,
+    }
+}
+fn exp_yields(exp: &Exp) -> bool {
+    match exp {
+        Exp::Yield(_) => true,
+        Exp::FnCall(FnCall { args, .. }) => args.iter().any(|arg| exp_yields(arg)),
+        Exp::BinaryOperation(BinaryOperation { left_hand_side, right_hand_side, .. }) => exp_yields(left_hand_side) || exp_yields(right_hand_side),
+        Exp::Value(ir::Value::Table(ir::Table(entries))) => entries.iter().any(|(_, exp)| exp_yields(exp)),
+        Exp::Value(_) | Exp::Variable(_) => false,
+        Exp::Block(statements, exp) => statements.iter().any(|statement| statement_yields(statement)) || exp_yields(exp),
+    }
+}
@@ -0,0 +1,226 @@
+use crate::second_attempt::vm2::{Chunk, Handler, Vm};
+use crate::second_attempt::vm2::function::CallFrame;
+use crate::second_attempt::vm2::misc::{Table, Value};
+use crate::second_attempt::vm2::verify::verify;
+use crate::second_attempt::vm_error::{VmError, VmErrorKind};
+
+/*
+A coroutine is a chunk that can stop halfway with YIELD and carry on later from RESUME.
+
+While it runs its chunks sit on the vm's chunk stack like any other, with a `CoroutineFrame` marking
+where it starts. Yielding moves everything above that mark (chunks, eval and locals values, handlers
+and calls) into the `Coroutine` on the heap, resuming moves it all back on top of whoever resumed it.
+
+The first resume starts the chunk and the value sent with it is dropped, after that the value sent
+with each resume is what the YIELD it's stopped at pushes.
+
+A host resumes one with `resume_coroutine`, which only returns once it has yielded or finished.
+
+NEW_COROUTINE takes a table describing a chunk or a function. A function's coroutine starts with
+copies of the top `arity` locals as its arguments, which is where CALL left the arguments of the
+function running NEW_COROUTINE, so a generator compiles to a function that hands its arguments on.
+ */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CoroutineState {
+    Suspended,
+    Running,
+    Finished,
+}
+pub struct Coroutine {
+    pub(super) state: CoroutineState,
+    pub(super) started: bool,
+    pub(super) chunks: Vec<Chunk>,
+    pub(super) eval: Vec<Value>,
+    pub(super) locals: Vec<Value>,
+    // depths and lengths relative to the coroutine's own bottom
+    pub(super) handlers: Vec<Handler>,
+    pub(super) calls: Vec<CallFrame>,
+}
+impl Coroutine {
+    pub fn new(chunk: Chunk) -> Self {
+        Self {
+            state: CoroutineState::Suspended,
+            started: false,
+            chunks: vec![chunk],
+            eval: vec![],
+            locals: vec![],
+            handlers: vec![],
+            calls: vec![],
+        }
+    }
+    pub fn get_state(&self) -> CoroutineState {
+        self.state
+    }
+}
+// what the resumer gets back
+#[derive(Copy, Clone)]
+pub(super) enum ResumeKind {
+    // RESUME pushes the value and then whether the coroutine finished
+    Value,
+    // FOR_NEXT pushes each yielded value and jumps to the target once it's finished
+    ForLoop(usize),
+}
+pub(super) struct CoroutineFrame {
+    pub(super) handle: usize,
+    pub(super) chunk_depth: usize,
+    pub(super) eval_base: usize,
+    pub(super) locals_base: usize,
+    pub(super) kind: ResumeKind,
+}
+impl Vm {
+    // the same as NEW_COROUTINE, for hosts that want to hand a generator to the code they run
+    pub fn new_coroutine(&mut self, chunk: Chunk) -> Result<Value, VmErrorKind> {
+        verify(&chunk).map_err(|err| VmErrorKind::InvalidChunk(err.to_string()))?;
+        self.push_table(Table::Coroutine(Coroutine::new(chunk)))
+    }
+    // resumes a coroutine from outside the vm and runs it until it yields or finishes, the vm can be
+    // idle or paused inside a native function. Gives back the value and whether it finished
+    pub fn resume_coroutine(&mut self, coroutine: Value, sent: Value) -> Result<(Value, bool), VmError> {
+        let depth = self.chunks.len();
+        if depth == 0 {
+            self.start_clock();
+        }
+        let at = |kind: VmErrorKind| kind.at(depth, 0);
+        let handle = Self::table_handle(coroutine).map_err(at)?;
+        self.resume(handle, sent, ResumeKind::Value).map_err(at)?;
+        // RESUME's two values, the flag ends up on top
+        let finished = self.run_until(depth, &mut ())?;
+        let value = self.pop().map_err(at)?;
+        Ok((value, finished.get_truthy()))
+    }
+    pub(super) fn coroutine_from(&mut self, handle: usize) -> Result<Value, VmErrorKind> {
+        let Some(Table::Function(function)) = self.tables.get(handle) else {
+            let chunk = self.table_to_chunk(handle)?;
+            return self.new_coroutine(chunk);
+        };
+        // a function's chunk was verified when it was made
+        let mut coroutine = Coroutine::new(function.chunk.clone());
+        let base = self.locals.len().checked_sub(function.arity).ok_or(VmErrorKind::StackUnderflow)?;
+        coroutine.locals = self.locals.to_vec_from(base);
+        self.push_table(Table::Coroutine(coroutine))
+    }
+    pub(super) fn resume(&mut self, handle: usize, sent: Value, kind: ResumeKind) -> Result<(), VmErrorKind> {
+        let coroutine = match self.tables.get_mut(handle) {
+            Some(Table::Coroutine(coroutine)) => coroutine,
+            Some(table) => return Err(VmErrorKind::TypeError { expected: "coroutine", found: table.type_name() }),
+            None => return Err(VmErrorKind::BadTableHandle(handle)),
+        };
+        match coroutine.state {
+            CoroutineState::Suspended => {}
+            CoroutineState::Running => return Err(VmErrorKind::CannotResume("running")),
+            CoroutineState::Finished => return Err(VmErrorKind::CannotResume("finished")),
+        }
+        coroutine.state = CoroutineState::Running;
+        let started = std::mem::replace(&mut coroutine.started, true);
+        let chunks = std::mem::take(&mut coroutine.chunks);
+        let eval = std::mem::take(&mut coroutine.eval);
+        let locals = std::mem::take(&mut coroutine.locals);
+        let handlers = std::mem::take(&mut coroutine.handlers);
+        let calls = std::mem::take(&mut coroutine.calls);
+        let frame = CoroutineFrame {
+            handle,
+            chunk_depth: self.chunks.len(),
+            eval_base: self.eval.len(),
+            locals_base: self.locals.len(),
+            kind,
+        };
+        for value in eval {
+            self.push(value)?;
+        }
+        for value in locals {
+            self.locals.push(value).ok_or_else(|| self.stack_overflow())?;
+        }
+        self.handlers.extend(handlers.into_iter().map(|handler| Handler {
+            chunk_depth: handler.chunk_depth + frame.chunk_depth,
+            target: handler.target,
+            eval_len: handler.eval_len + frame.eval_base,
+            locals_len: handler.locals_len + frame.locals_base,
+        }));
+        self.calls.extend(calls.into_iter().map(|call| CallFrame {
+            chunk_depth: call.chunk_depth + frame.chunk_depth,
+            eval_len: call.eval_len + frame.eval_base,
+            locals_len: call.locals_len + frame.locals_base,
+        }));
+        self.chunks.extend(chunks);
+        if started {
+            self.push(sent)?;
+        }
+        self.coroutines.push(frame);
+        Ok(())
+    }
+    pub(super) fn yield_value(&mut self, value: Value) -> Result<(), VmErrorKind> {
+        let frame = self.coroutines.pop().ok_or(VmErrorKind::Unsupported("yield outside of a coroutine"))?;
+        let chunks = self.chunks.split_off(frame.chunk_depth);
+        let eval = self.eval.to_vec_from(frame.eval_base);
+        let locals = self.locals.to_vec_from(frame.locals_base);
+        self.eval.truncate(frame.eval_base);
+        self.locals.truncate(frame.locals_base);
+        let first_handler = self.handlers.partition_point(|handler| handler.chunk_depth <= frame.chunk_depth);
+        let handlers = self.handlers.split_off(first_handler).into_iter().map(|handler| Handler {
+            chunk_depth: handler.chunk_depth - frame.chunk_depth,
+            target: handler.target,
+            eval_len: handler.eval_len - frame.eval_base,
+            locals_len: handler.locals_len - frame.locals_base,
+        }).collect();
+        let first_call = self.calls.partition_point(|call| call.chunk_depth < frame.chunk_depth);
+        let calls = self.calls.split_off(first_call).into_iter().map(|call| CallFrame {
+            chunk_depth: call.chunk_depth - frame.chunk_depth,
+            eval_len: call.eval_len - frame.eval_base,
+            locals_len: call.locals_len - frame.locals_base,
+        }).collect();
+        if let Some(Table::Coroutine(coroutine)) = self.tables.get_mut(frame.handle) {
+            coroutine.state = CoroutineState::Suspended;
+            coroutine.chunks = chunks;
+            coroutine.eval = eval;
+            coroutine.locals = locals;
+            coroutine.handlers = handlers;
+            coroutine.calls = calls;
+        }
+        self.deliver(frame.kind, value, false)
+    }
+    // called after a chunk is popped, finishes the coroutine if that was its bottom chunk
+    pub(super) fn finish_coroutine(&mut self) -> Result<(), VmErrorKind> {
+        if self.coroutines.last().map_or(true, |frame| frame.chunk_depth != self.chunks.len()) {
+            return Ok(());
+        }
+        let frame = self.coroutines.pop().unwrap();
+        // like the vm itself, a coroutine's result is whatever it left on top of the eval stack
+        let value = match self.eval.len() > frame.eval_base {
+            true => self.eval.pop().unwrap(),
+            false => Value::Nil,
+        };
+        self.eval.truncate(frame.eval_base);
+        self.locals.truncate(frame.locals_base);
+        self.set_state(frame.handle, CoroutineState::Finished);
+        self.deliver(frame.kind, value, true)
+    }
+    // a throw that unwinds past a coroutine's bottom chunk finishes it for good
+    pub(super) fn unwind_coroutines(&mut self) {
+        while let Some(frame) = self.coroutines.last() {
+            if frame.chunk_depth < self.chunks.len() {
+                break;
+            }
+            let handle = frame.handle;
+            self.coroutines.pop();
+            self.set_state(handle, CoroutineState::Finished);
+        }
+    }
+    fn set_state(&mut self, handle: usize, state: CoroutineState) {
+        if let Some(Table::Coroutine(coroutine)) = self.tables.get_mut(handle) {
+            coroutine.state = state;
+        }
+    }
+    fn deliver(&mut self, kind: ResumeKind, value: Value, finished: bool) -> Result<(), VmErrorKind> {
+        match kind {
+            ResumeKind::Value => {
+                self.push(value)?;
+                self.push(Value::Boolean(finished))
+            }
+            ResumeKind::ForLoop(target) if finished => {
+                self.set_ip(target);
+                Ok(())
+            }
+            ResumeKind::ForLoop(_) => self.push(value),
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use crate::second_attempt::engine::Engine;
+use crate::second_attempt::vm2::misc::Value;
+
+// yields what it was started with, then each value sent in, and returns the last one
+const ECHO: &str = r#"
+    export fn echo(first) capture {
+        let second = yield first;
+        let third = yield second;
+        third
+    }
+"#;
+
+#[test]
+fn host_sends_values_into_a_coroutine() {
+    let mut engine = Engine::new();
+    engine.eval(ECHO).unwrap();
+    let coroutine: Value = engine.call("echo", (1,)).unwrap();
+    // the first resume only starts it, what's sent with it is dropped
+    assert_eq!(engine.resume::<_, i64>(coroutine, 0).unwrap(), (1, false));
+    assert_eq!(engine.resume::<_, String>(coroutine, "two").unwrap(), ("two".to_string(), false));
+    assert_eq!(engine.resume::<_, i64>(coroutine, 3).unwrap(), (3, true));
+    assert!(engine.resume::<_, Value>(coroutine, 4).is_err());
+}
+#[test]
+fn resume_builtin_sends_values_into_a_coroutine() {
+    let mut engine = Engine::new();
+    engine.eval(ECHO).unwrap();
+    engine.eval(r#"
+        let running = echo(1);
+        let first = resume(running, 0);
+        let second = resume(running, "two");
+        let before = finished(running);
+        let third = resume(running, 3);
+        let after = finished(running);
+    "#).unwrap();
+    assert_eq!(engine.get_global::<i64>("first").unwrap(), 1);
+    assert_eq!(engine.get_global::<String>("second").unwrap(), "two");
+    assert_eq!(engine.get_global::<i64>("third").unwrap(), 3);
+    assert!(!engine.get_global::<bool>("before").unwrap());
+    assert!(engine.get_global::<bool>("after").unwrap());
+}
+#[test]
+fn resuming_a_finished_coroutine_is_an_error() {
+    let mut engine = Engine::new();
+    engine.eval(ECHO).unwrap();
+    let result = engine.eval(r#"
+        let running = echo(1);
+        resume(running, 0);
+        resume(running, 0);
+        resume(running, 0);
+        resume(running, 0);
+    "#);
+    assert!(result.unwrap_err().to_string().contains("can't resume a finished coroutine"));
+}
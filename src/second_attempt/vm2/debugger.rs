@@ -0,0 +1,308 @@
+use std::collections::BTreeSet;
+use std::io::{BufRead, Write};
+use std::ops::Range;
+use crate::second_attempt::vm2::{Hook, Vm};
+use crate::second_attempt::vm2::bytecode::{decode, opcode_info};
+use crate::second_attempt::vm2::disassemble::constant_literal;
+use crate::second_attempt::vm2::misc::{Table, TableKey, Value};
+use crate::second_attempt::vm_error::VmErrorKind;
+use crate::second_attempt::stack::Stack;
+
+/*
+A debugger is a `Hook`, attach it with `vm.run_with(&mut debugger)`.
+Before every instruction it works out where in the source we are from the chunk's debug info
+and decides whether to pause. While paused the front end gets a `Paused` to look around with
+and answers with a `Command`.
+
+Lines are 1 based. Chunks without debug info, or a debugger without the source, step one
+instruction at a time and can't have line breakpoints.
+ */
+pub enum Command {
+    Continue,
+    StepInto,
+    StepOver,
+    StepOut,
+    Break(usize),
+    Clear(usize),
+    Quit,
+}
+pub trait FrontEnd {
+    fn paused(&mut self, paused: &Paused) -> Command;
+}
+#[derive(Copy, Clone, PartialEq)]
+enum Mode {
+    Continue,
+    StepInto,
+    StepOver,
+    StepOut,
+}
+#[derive(Copy, Clone, PartialEq)]
+struct Location {
+    depth: usize,
+    line: Option<usize>,
+    ip: usize,
+}
+impl Location {
+    // instructions on the same line of the same chunk are one step
+    fn same_step(self, other: Location) -> bool {
+        self.depth == other.depth && match (self.line, other.line) {
+            (Some(line), Some(other_line)) => line == other_line,
+            _ => self.ip == other.ip,
+        }
+    }
+}
+pub struct Debugger<F: FrontEnd> {
+    front_end: F,
+    source: Option<String>,
+    line_starts: Vec<usize>,
+    breakpoints: BTreeSet<usize>,
+    mode: Mode,
+    paused_at: Option<Location>,
+    last: Option<Location>,
+    // where each chunk on the chunk stack has its locals, filled in the first time it runs
+    locals_bases: Vec<Option<usize>>,
+}
+impl<F: FrontEnd> Debugger<F> {
+    // starts paused on the first instruction
+    pub fn new(front_end: F, source: Option<String>) -> Self {
+        let line_starts = match &source {
+            Some(source) => std::iter::once(0).chain(source.match_indices('\n').map(|(index, _)| index + 1)).collect(),
+            None => vec![],
+        };
+        Self {
+            front_end,
+            source,
+            line_starts,
+            breakpoints: BTreeSet::new(),
+            mode: Mode::StepInto,
+            paused_at: None,
+            last: None,
+            locals_bases: vec![],
+        }
+    }
+    pub fn add_breakpoint(&mut self, line: usize) {
+        self.breakpoints.insert(line);
+    }
+    pub fn remove_breakpoint(&mut self, line: usize) {
+        self.breakpoints.remove(&line);
+    }
+    pub fn get_breakpoints(&self) -> &BTreeSet<usize> {
+        &self.breakpoints
+    }
+    fn line_of(&self, offset: usize) -> Option<usize> {
+        if self.line_starts.is_empty() {
+            return None;
+        }
+        Some(self.line_starts.partition_point(|start| *start <= offset))
+    }
+    fn should_pause(&self, location: Location) -> bool {
+        let stepped = match (self.mode, self.paused_at) {
+            (Mode::Continue, _) => false,
+            (_, None) => true,
+            (Mode::StepInto, Some(paused_at)) => !location.same_step(paused_at),
+            (Mode::StepOver, Some(paused_at)) => location.depth < paused_at.depth
+                || (location.depth == paused_at.depth && !location.same_step(paused_at)),
+            (Mode::StepOut, Some(paused_at)) => location.depth < paused_at.depth,
+        };
+        // a breakpoint fires when we arrive on its line, not for every instruction on it
+        let arrived = self.last.map_or(true, |last| !location.same_step(last));
+        stepped || (arrived && location.line.map_or(false, |line| self.breakpoints.contains(&line)))
+    }
+}
+impl<F: FrontEnd> Hook for Debugger<F> {
+    fn before_step(&mut self, vm: &Vm) -> Result<(), VmErrorKind> {
+        let depth = vm.get_chunks().len();
+        let chunk = vm.get_chunk();
+        self.locals_bases.resize(depth, None);
+        let locals_base = *self.locals_bases[depth - 1].get_or_insert_with(|| vm.call_locals_base().unwrap_or(vm.get_locals().len()));
+        let span = chunk.get_debug().and_then(|debug| debug.span_at(chunk.get_ip()));
+        let location = Location {
+            depth,
+            line: span.as_ref().and_then(|span| self.line_of(span.start)),
+            ip: chunk.get_ip(),
+        };
+        let pause = self.should_pause(location);
+        self.last = Some(location);
+        if !pause {
+            return Ok(());
+        }
+        self.paused_at = Some(location);
+        loop {
+            let paused = Paused {
+                vm,
+                source: self.source.as_deref(),
+                line: location.line,
+                span: span.clone(),
+                locals_base,
+                breakpoints: &self.breakpoints,
+            };
+            match self.front_end.paused(&paused) {
+                Command::Continue => self.mode = Mode::Continue,
+                Command::StepInto => self.mode = Mode::StepInto,
+                Command::StepOver => self.mode = Mode::StepOver,
+                Command::StepOut => self.mode = Mode::StepOut,
+                Command::Break(line) => {
+                    self.breakpoints.insert(line);
+                    continue;
+                }
+                Command::Clear(line) => {
+                    self.breakpoints.remove(&line);
+                    continue;
+                }
+                Command::Quit => return Err(VmErrorKind::Interrupted),
+            }
+            return Ok(());
+        }
+    }
+}
+
+// what the front end can see while the vm is paused
+pub struct Paused<'a> {
+    vm: &'a Vm,
+    source: Option<&'a str>,
+    line: Option<usize>,
+    span: Option<Range<usize>>,
+    locals_base: usize,
+    breakpoints: &'a BTreeSet<usize>,
+}
+impl<'a> Paused<'a> {
+    pub fn chunk_name(&self) -> Option<&str> {
+        self.vm.get_chunk().get_debug().map(|debug| debug.name.as_str())
+    }
+    // how many chunks are on the chunk stack, injected chunks sit above the one that injected them
+    pub fn depth(&self) -> usize {
+        self.vm.get_chunks().len()
+    }
+    // the instruction about to run
+    pub fn ip(&self) -> usize {
+        self.vm.get_chunk().get_ip()
+    }
+    pub fn line(&self) -> Option<usize> {
+        self.line
+    }
+    pub fn span(&self) -> Option<Range<usize>> {
+        self.span.clone()
+    }
+    pub fn source_line(&self) -> Option<&'a str> {
+        self.source?.lines().nth(self.line? - 1)
+    }
+    pub fn instruction(&self) -> String {
+        let chunk = self.vm.get_chunk();
+        match decode(chunk.get_instructions(), chunk.get_ip()) {
+            Ok(instruction) => {
+                let name = opcode_info(instruction.opcode).map_or("?", |(name, _)| name);
+                match instruction.operand {
+                    Some(operand) => format!("{} {}", name, operand),
+                    None => name.to_string(),
+                }
+            }
+            Err(_) => "?".to_string(),
+        }
+    }
+    // the current chunk's locals from the first one it pushed, named where the debug info knows them
+    pub fn locals(&self) -> Vec<(usize, Option<&'a str>, Value)> {
+        let debug = self.vm.get_chunk().get_debug();
+        let ip = self.ip();
+        self.vm.get_locals().iter().skip(self.locals_base).enumerate().map(|(slot, value)| {
+            (slot, debug.and_then(|debug| debug.local_name(slot, ip)), value)
+        }).collect()
+    }
+    // bottom of the stack first
+    pub fn eval_stack(&self) -> &'a Stack<Value> {
+        self.vm.get_eval()
+    }
+    pub fn table(&self, handle: usize) -> Option<&'a Table> {
+        self.vm.get_tables().get(handle)
+    }
+    pub fn breakpoints(&self) -> &BTreeSet<usize> {
+        self.breakpoints
+    }
+}
+
+// a line based front end on stdin and stdout
+pub struct Terminal;
+const HELP: &str = "\
+c, continue      run until the next breakpoint
+s, step          step into
+n, next          step over
+o, out           step out of the current chunk
+b, break <line>  set a breakpoint
+d, delete <line> clear a breakpoint
+l, locals        show the current chunk's locals
+e, stack         show the eval stack
+t, table <n>     show the table with handle n
+i, info          show where we are and the breakpoints
+q, quit          stop the program";
+impl FrontEnd for Terminal {
+    fn paused(&mut self, paused: &Paused) -> Command {
+        print_location(paused);
+        let stdin = std::io::stdin();
+        loop {
+            print!("(tdb) ");
+            std::io::stdout().flush().unwrap();
+            let mut input = String::new();
+            if stdin.lock().read_line(&mut input).unwrap() == 0 {
+                return Command::Quit;
+            }
+            let mut words = input.split_whitespace();
+            let command = words.next().unwrap_or("");
+            let argument = words.next().and_then(|word| word.parse::<usize>().ok());
+            match (command, argument) {
+                ("c" | "continue", _) => return Command::Continue,
+                ("s" | "step", _) => return Command::StepInto,
+                ("n" | "next", _) => return Command::StepOver,
+                ("o" | "out", _) => return Command::StepOut,
+                ("b" | "break", Some(line)) => return Command::Break(line),
+                ("d" | "delete", Some(line)) => return Command::Clear(line),
+                ("q" | "quit", _) => return Command::Quit,
+                ("l" | "locals", _) => {
+                    for (slot, name, value) in paused.locals() {
+                        println!("    {:<3} {:<12} {}", slot, name.unwrap_or("?"), constant_literal(value, Some(paused.vm)));
+                    }
+                }
+                ("e" | "stack", _) => {
+                    for value in paused.eval_stack().iter().rev() {
+                        println!("    {}", constant_literal(value, Some(paused.vm)));
+                    }
+                }
+                ("t" | "table", Some(handle)) => match paused.table(handle) {
+                    Some(table) => print_table(table, paused.vm),
+                    None => println!("no table with handle {}", handle),
+                },
+                ("i" | "info", _) => {
+                    print_location(paused);
+                    println!("breakpoints: {:?}", paused.breakpoints());
+                }
+                _ => println!("{}", HELP),
+            }
+        }
+    }
+}
+fn print_location(paused: &Paused) {
+    let name = paused.chunk_name().unwrap_or("<chunk>");
+    match (paused.line(), paused.source_line()) {
+        (Some(line), Some(source_line)) => println!("{} ip {:04} line {}: {}", name, paused.ip(), line, source_line.trim()),
+        _ => println!("{} ip {:04}: {}", name, paused.ip(), paused.instruction()),
+    }
+}
+fn print_table(table: &Table, vm: &Vm) {
+    match table {
+        Table::Map(map) => {
+            for (key, value) in map {
+                match key {
+                    TableKey::Identifier(name, _) => println!("    {}: {}", name, constant_literal(*value, Some(vm))),
+                    TableKey::NoIdentifier(index) => println!("    [{}] {}", index, constant_literal(*value, Some(vm))),
+                }
+            }
+        }
+        Table::String(string) => println!("    {:?}", string),
+        Table::Coroutine(coroutine) => println!("    coroutine ({:?})", coroutine.get_state()),
+        Table::Function(function) => println!("    fn {}({} arguments)", function.name, function.arity),
+        Table::Native(native) => println!("    native fn {}", native.name),
+        Table::Array(values) => {
+            for (index, value) in values.iter().enumerate() {
+                println!("    [{}] {}", index, constant_literal(*value, Some(vm)));
+            }
+        }
+    }
+}
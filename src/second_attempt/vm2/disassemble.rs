@@ -0,0 +1,176 @@
+use std::collections::BTreeMap;
+use crate::second_attempt::numeric::Numeric;
+use crate::second_attempt::vm2::bytecode::{decode, DecodeError, Instruction, opcode_info, OperandKind, PUSH_LOCAL};
+use crate::second_attempt::vm2::{Chunk, Vm};
+use crate::second_attempt::vm2::file::{BytecodeFile, FileTable};
+use crate::second_attempt::vm2::misc::{Table, Value};
+
+/*
+Turns a chunk back into text, looks like
+
+    .chunk main
+    .constants
+        1
+        21
+    .code
+    L0:
+        CONSTANT 0              ; 0000  1
+        JUMP_IF L0              ; 0009
+        .byte 0xff              ; 0018  invalid opcode
+
+Every jump target gets a label, constant operands are resolved to their value in the comment.
+Given the vm a chunk was loaded into, or the file it came from, constants that are strings are
+written as `"..."`, otherwise as the `table(N)` they are to the vm.
+Bytes that don't decode are written out with `.byte` so nothing is ever lost. A PUSH_LOCAL that
+starts a named local in the debug info gets the name after it. `assemble` reads all of it back.
+ */
+pub fn disassemble(chunk: &Chunk, vm: Option<&Vm>) -> String {
+    disassemble_with(chunk, &|handle| vm_string(vm?, handle))
+}
+// a file's table constants are indices into its own tables, its entry chunk comes first and then
+// the chunk of every function in it
+pub fn disassemble_file(file: &BytecodeFile) -> String {
+    let string = |index| match file.tables.get(index)? {
+        FileTable::String(string) => Some(string.as_str()),
+        FileTable::Function { .. } => None,
+    };
+    file.chunks().map(|chunk| disassemble_with(chunk, &string)).collect::<Vec<_>>().join("\n")
+}
+// the literal form of a constant, the assembler reads these back in
+pub fn constant_literal(value: Value, vm: Option<&Vm>) -> String {
+    literal(value, &|handle| vm_string(vm?, handle))
+}
+fn vm_string(vm: &Vm, handle: usize) -> Option<&str> {
+    match vm.get_tables().get(handle)? {
+        Table::String(string) => Some(string.as_str()),
+        _ => None,
+    }
+}
+fn disassemble_with<'a>(chunk: &Chunk, string: &impl Fn(usize) -> Option<&'a str>) -> String {
+    let instructions = chunk.get_instructions().as_slice();
+    let decoded = decode_all(instructions);
+    let labels = collect_labels(&decoded, instructions.len());
+    let mut buffer = String::new();
+    match chunk.get_debug() {
+        Some(debug) => buffer.push_str(format!(".chunk {}\n", debug.name).as_str()),
+        None => buffer.push_str(".chunk\n"),
+    }
+    buffer.push_str(".constants\n");
+    for constant in chunk.get_constants() {
+        buffer.push_str(format!("    {}\n", literal(*constant, string)).as_str());
+    }
+    buffer.push_str(".code\n");
+    for decoded in decoded {
+        match decoded {
+            Ok(instruction) => {
+                if let Some(label) = labels.get(&instruction.ip) {
+                    buffer.push_str(format!("L{}:\n", label).as_str());
+                }
+                let (name, kind) = opcode_info(instruction.opcode).unwrap();
+                let (text, comment) = match (kind, instruction.operand) {
+                    (OperandKind::Constant, Some(index)) => {
+                        let comment = match chunk.get_constants().get(index) {
+                            Some(constant) => literal(*constant, string),
+                            None => "constant out of range".to_string(),
+                        };
+                        (format!("{} {}", name, index), comment)
+                    }
+                    (OperandKind::Jump, Some(target)) => match labels.get(&target) {
+                        Some(label) => (format!("{} L{}", name, label), String::new()),
+                        None => (format!("{} {}", name, target), "invalid jump target".to_string()),
+                    },
+                    (_, Some(operand)) => (format!("{} {}", name, operand), String::new()),
+                    (_, None) => match local_pushed_by(chunk, &instruction) {
+                        Some(local) => (format!("{} {}", name, local), String::new()),
+                        None => (name.to_string(), String::new()),
+                    },
+                };
+                buffer.push_str(format!("    {:<24}; {:04}  {}\n", text, instruction.ip, comment).trim_end());
+                buffer.push('\n');
+            }
+            Err((ip, byte, err)) => {
+                let reason = match err {
+                    DecodeError::InvalidOpcode(_) => "invalid opcode",
+                    DecodeError::TruncatedOperand => "truncated operand",
+                };
+                buffer.push_str(format!("    {:<24}; {:04}  {}\n", format!(".byte {:#04x}", byte), ip, reason).as_str());
+            }
+        }
+    }
+    if let Some(label) = labels.get(&instructions.len()) {
+        buffer.push_str(format!("L{}:\n", label).as_str());
+    }
+    buffer
+}
+fn literal<'a>(value: Value, string: &impl Fn(usize) -> Option<&'a str>) -> String {
+    match value {
+        Value::Number(num) => format!("{}", num),
+        Value::Float(num) => format!("{}", Numeric::Float(num)),
+        Value::Boolean(bool) => format!("{}", bool),
+        Value::Table(handle) => match string(handle) {
+            Some(string) => escape(string),
+            None => format!("table({})", handle),
+        },
+        Value::Nil => "nil".to_string(),
+    }
+}
+// quoted, with the escapes the assembler's `unescape` takes back out
+fn escape(string: &str) -> String {
+    let mut escaped = String::from('"');
+    for char in string.chars() {
+        match char {
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '\r' => escaped.push_str("\\r"),
+            '\0' => escaped.push_str("\\0"),
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            char => escaped.push(char),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+// the name of the local a PUSH_LOCAL starts, named locals are live from the instruction after it
+fn local_pushed_by(chunk: &Chunk, instruction: &Instruction) -> Option<String> {
+    if instruction.opcode != PUSH_LOCAL {
+        return None;
+    }
+    let debug = chunk.get_debug()?;
+    let local = debug.locals.iter().find(|local| local.live.start == instruction.next_ip())?;
+    Some(local.name.clone())
+}
+// decodes every instruction, a byte that doesn't decode is skipped on its own so we can carry on after it
+fn decode_all(instructions: &[u8]) -> Vec<Result<Instruction, (usize, u8, DecodeError)>> {
+    let mut decoded = vec![];
+    let mut ip = 0;
+    while ip < instructions.len() {
+        match decode(instructions, ip) {
+            Ok(instruction) => {
+                ip = instruction.next_ip();
+                decoded.push(Ok(instruction));
+            }
+            Err(err) => {
+                decoded.push(Err((ip, instructions[ip], err)));
+                ip += 1;
+            }
+        }
+    }
+    decoded
+}
+// label numbers are handed out in address order, only for targets that land on an instruction
+// jumping to the very end of the chunk is allowed, it just finishes the chunk
+fn collect_labels(decoded: &[Result<Instruction, (usize, u8, DecodeError)>], end: usize) -> BTreeMap<usize, usize> {
+    let mut boundaries: Vec<usize> = decoded.iter().filter_map(|decoded| decoded.as_ref().ok().map(|instruction| instruction.ip)).collect();
+    boundaries.push(end);
+    let mut targets: Vec<usize> = decoded.iter().filter_map(|decoded| match decoded {
+        Ok(instruction) => match opcode_info(instruction.opcode) {
+            Some((_, OperandKind::Jump)) => instruction.operand,
+            _ => None,
+        },
+        Err(_) => None,
+    }).filter(|target| boundaries.binary_search(target).is_ok()).collect();
+    targets.sort();
+    targets.dedup();
+    targets.into_iter().enumerate().map(|(label, target)| (target, label)).collect()
+}
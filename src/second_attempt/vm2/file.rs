@@ -0,0 +1,509 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use crate::second_attempt::vm2::{Chunk, DebugInfo, LocalInfo, Vm};
+use crate::second_attempt::vm2::function::Function;
+use crate::second_attempt::vm2::misc::{Table, Value};
+use crate::second_attempt::vm2::verify::{verify, VerifyError};
+use crate::second_attempt::vm_error::VmErrorKind;
+
+/*
+The `.tblc` compiled file format, every integer is little endian no matter the machine.
+
+    magic           b"TBLC"
+    version         u16
+    flags           u16, bit 0 set if chunks carry debug info
+    constant pool   u64 count, then each constant as a u8 tag followed by its payload
+    tables          u64 count, then each table as a u8 tag (0 = string, 1 = function) followed by
+                        a string: u64 length + utf8 bytes
+                        a function: its name like a string, u64 arity, u8 exported, then its
+                            chunk the same way as the entry chunk
+    entry chunk     the chunk loading the file runs:
+                        u64 count of constants, then a u64 index into the constant pool for each
+                        u64 length of the instructions, then the instruction bytes
+                        if the debug flag is set: u8 (0 = none, 1 = present), then
+                            u64 length + utf8 bytes of the chunk name
+                            u64 count of spans, then (ip, start, end) as u64s for each
+                            u64 count of locals, then for each the name like the chunk name
+                                followed by (slot, live start, live end) as u64s
+
+Instruction operands are already fixed width little endian (see `bytecode::convert_thing`)
+so the instruction bytes are written as is.
+
+A table constant is the index of a table in the file's own table section rather than a handle into
+some vm's arena. Only strings and functions can be constants, loading a file allocates them in the
+vm it's loaded into and points the constants at the new handles. Function bodies only ever live in
+the table section, so loading a file runs nothing but the entry chunk.
+ */
+pub const MAGIC: [u8; 4] = *b"TBLC";
+pub const VERSION: u16 = 3;
+const FLAG_DEBUG_INFO: u16 = 1;
+
+const TAG_NIL: u8 = 0x00;
+const TAG_NUMBER: u8 = 0x01;
+const TAG_FLOAT: u8 = 0x02;
+const TAG_BOOLEAN: u8 = 0x03;
+const TAG_TABLE: u8 = 0x04;
+
+const TABLE_STRING: u8 = 0x00;
+const TABLE_FUNCTION: u8 = 0x01;
+
+#[derive(Debug)]
+pub enum BytecodeFileError {
+    Io(std::io::Error),
+    BadMagic,
+    UnsupportedVersion(u16),
+    UnexpectedEnd,
+    UnknownValueTag(u8),
+    ConstantOutOfRange(usize),
+    TableOutOfRange(usize),
+    UnknownTableTag(u8),
+    // a constant points at a table that can't be written out, like a map or a native
+    UnsupportedConstant(&'static str),
+    InvalidUtf8,
+    Invalid(VerifyError),
+    // the file's tables go over a limit of the vm it's loaded into
+    Vm(VmErrorKind),
+}
+impl Display for BytecodeFileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BytecodeFileError::Io(err) => write!(f, "io error: {}", err),
+            BytecodeFileError::BadMagic => write!(f, "not a compiled table file"),
+            BytecodeFileError::UnsupportedVersion(version) => write!(f, "unsupported compiled file version {}, expected {}", version, VERSION),
+            BytecodeFileError::UnexpectedEnd => write!(f, "compiled file ended unexpectedly"),
+            BytecodeFileError::UnknownValueTag(tag) => write!(f, "unknown constant tag {:#04x}", tag),
+            BytecodeFileError::ConstantOutOfRange(index) => write!(f, "constant pool index {} out of range", index),
+            BytecodeFileError::TableOutOfRange(index) => write!(f, "table index {} out of range", index),
+            BytecodeFileError::UnknownTableTag(tag) => write!(f, "unknown table tag {:#04x}", tag),
+            BytecodeFileError::UnsupportedConstant(kind) => write!(f, "a {} can't be a constant in a compiled file", kind),
+            BytecodeFileError::InvalidUtf8 => write!(f, "chunk name isn't valid utf8"),
+            BytecodeFileError::Invalid(err) => write!(f, "chunk failed verification: {}", err),
+            BytecodeFileError::Vm(kind) => write!(f, "{}", kind),
+        }
+    }
+}
+impl From<VerifyError> for BytecodeFileError {
+    fn from(err: VerifyError) -> Self {
+        BytecodeFileError::Invalid(err)
+    }
+}
+impl From<std::io::Error> for BytecodeFileError {
+    fn from(err: std::io::Error) -> Self {
+        BytecodeFileError::Io(err)
+    }
+}
+
+// a table a constant in the file refers to
+#[derive(Clone)]
+pub enum FileTable {
+    String(String),
+    Function {
+        name: String,
+        arity: usize,
+        exported: bool,
+        chunk: Chunk,
+    },
+}
+
+pub struct BytecodeFile {
+    pub entry: Chunk,
+    // constants in `entry` and the functions index into this, not into a vm
+    pub tables: Vec<FileTable>,
+}
+impl BytecodeFile {
+    // for a chunk without table constants, otherwise see `from_vm`
+    pub fn new(entry: Chunk) -> Self {
+        Self {
+            entry,
+            tables: vec![],
+        }
+    }
+    // a chunk compiled against `vm`, the strings and functions its constants reach are copied in
+    pub fn from_vm(entry: Chunk, vm: &Vm) -> Result<Self, BytecodeFileError> {
+        let mut collector = Collector {
+            vm,
+            tables: vec![],
+            indices: HashMap::new(),
+        };
+        let entry = collector.chunk(entry)?;
+        Ok(Self {
+            entry,
+            tables: collector.tables,
+        })
+    }
+    pub fn write_to<P: AsRef<Path>>(&self, path: P) -> Result<(), BytecodeFileError> {
+        std::fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+    pub fn read_from<P: AsRef<Path>>(path: P) -> Result<Self, BytecodeFileError> {
+        Self::from_bytes(std::fs::read(path)?.as_slice())
+    }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let has_debug = self.chunks().any(|chunk| chunk.get_debug().is_some());
+        let mut pool = Pool::default();
+        let mut tables = Writer::default();
+        for table in &self.tables {
+            match table {
+                FileTable::String(string) => {
+                    tables.write_u8(TABLE_STRING);
+                    tables.write_str(string.as_str());
+                }
+                FileTable::Function { name, arity, exported, chunk } => {
+                    tables.write_u8(TABLE_FUNCTION);
+                    tables.write_str(name.as_str());
+                    tables.write_u64(*arity as u64);
+                    tables.write_u8(*exported as u8);
+                    write_chunk(&mut tables, &mut pool, chunk, has_debug);
+                }
+            }
+        }
+        let mut entry = Writer::default();
+        write_chunk(&mut entry, &mut pool, &self.entry, has_debug);
+        let mut writer = Writer::default();
+        writer.write_bytes(&MAGIC);
+        writer.write_u16(VERSION);
+        writer.write_u16(if has_debug { FLAG_DEBUG_INFO } else { 0 });
+        writer.write_u64(pool.len as u64);
+        writer.write_bytes(pool.writer.buffer.as_slice());
+        writer.write_u64(self.tables.len() as u64);
+        writer.write_bytes(tables.buffer.as_slice());
+        writer.write_bytes(entry.buffer.as_slice());
+        writer.buffer
+    }
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, BytecodeFileError> {
+        let mut reader = Reader::new(bytes);
+        if reader.read_bytes(MAGIC.len())? != MAGIC {
+            return Err(BytecodeFileError::BadMagic);
+        }
+        let version = reader.read_u16()?;
+        if version != VERSION {
+            return Err(BytecodeFileError::UnsupportedVersion(version));
+        }
+        let has_debug = reader.read_u16()? & FLAG_DEBUG_INFO != 0;
+        let pool_len = reader.read_len()?;
+        let mut pool = Vec::with_capacity(pool_len);
+        for _ in 0..pool_len {
+            pool.push(reader.read_value()?);
+        }
+        let table_len = reader.read_len()?;
+        let mut tables = Vec::with_capacity(table_len);
+        for _ in 0..table_len {
+            tables.push(match reader.read_u8()? {
+                TABLE_STRING => FileTable::String(reader.read_str()?),
+                TABLE_FUNCTION => FileTable::Function {
+                    name: reader.read_str()?,
+                    arity: reader.read_u64()? as usize,
+                    exported: reader.read_u8()? != 0,
+                    chunk: read_chunk(&mut reader, pool.as_slice(), has_debug)?,
+                },
+                tag => return Err(BytecodeFileError::UnknownTableTag(tag)),
+            });
+        }
+        let entry = read_chunk(&mut reader, pool.as_slice(), has_debug)?;
+        Ok(Self {
+            entry,
+            tables
+        })
+    }
+    // the entry chunk and then every function's
+    pub fn chunks(&self) -> impl Iterator<Item = &Chunk> {
+        let functions = self.tables.iter().filter_map(|table| match table {
+            FileTable::Function { chunk, .. } => Some(chunk),
+            FileTable::String(_) => None,
+        });
+        std::iter::once(&self.entry).chain(functions)
+    }
+}
+impl Vm {
+    // loads a file's entry chunk like `load` would, its tables are allocated first and every
+    // chunk's constants pointed at them. Nothing is allocated or loaded unless every chunk verifies
+    // and the tables fit in the vm's limits.
+    pub fn load_bytecode(&mut self, file: BytecodeFile) -> Result<(), BytecodeFileError> {
+        // tables are pushed in file order, so a file index becomes this far into the arena
+        let base = self.tables.len();
+        let table_len = file.tables.len();
+        let mut tables = Vec::with_capacity(table_len);
+        for table in file.tables {
+            tables.push(match table {
+                FileTable::String(string) => Table::String(string),
+                FileTable::Function { name, arity, exported, mut chunk } => {
+                    relocate(&mut chunk, base, table_len)?;
+                    verify(&chunk)?;
+                    Table::Function(Function { name, arity, exported, chunk })
+                }
+            });
+        }
+        let mut entry = file.entry;
+        relocate(&mut entry, base, table_len)?;
+        verify(&entry)?;
+        self.push_tables(tables).map_err(BytecodeFileError::Vm)?;
+        self.chunks.push(entry);
+        Ok(())
+    }
+}
+// points a loaded chunk's table constants at where the file's tables end up in the arena
+fn relocate(chunk: &mut Chunk, base: usize, table_len: usize) -> Result<(), BytecodeFileError> {
+    for constant in chunk.get_constants_mut() {
+        if let Value::Table(index) = constant {
+            if *index >= table_len {
+                return Err(BytecodeFileError::TableOutOfRange(*index));
+            }
+            *index += base;
+        }
+    }
+    Ok(())
+}
+
+// copies the tables chunks reach out of a vm, each one once no matter how many constants share it
+struct Collector<'a> {
+    vm: &'a Vm,
+    tables: Vec<FileTable>,
+    // vm handle to file index
+    indices: HashMap<usize, usize>,
+}
+impl Collector<'_> {
+    fn chunk(&mut self, mut chunk: Chunk) -> Result<Chunk, BytecodeFileError> {
+        for constant in chunk.get_constants_mut() {
+            if let Value::Table(handle) = constant {
+                *handle = self.table(*handle)?;
+            }
+        }
+        Ok(chunk)
+    }
+    fn table(&mut self, handle: usize) -> Result<usize, BytecodeFileError> {
+        if let Some(index) = self.indices.get(&handle) {
+            return Ok(*index);
+        }
+        let table = match self.vm.tables.get(handle) {
+            Some(Table::String(string)) => FileTable::String(string.clone()),
+            Some(Table::Function(function)) => {
+                // the index is taken before the chunk is, so a function whose constants lead
+                // back to itself ends up pointing at this entry
+                let index = self.tables.len();
+                self.indices.insert(handle, index);
+                self.tables.push(FileTable::String(String::new()));
+                let chunk = self.chunk(function.chunk.clone())?;
+                self.tables[index] = FileTable::Function {
+                    name: function.name.clone(),
+                    arity: function.arity,
+                    exported: function.exported,
+                    chunk,
+                };
+                return Ok(index);
+            }
+            Some(Table::Map(_)) | Some(Table::Array(_)) => return Err(BytecodeFileError::UnsupportedConstant("table")),
+            Some(Table::Coroutine(_)) => return Err(BytecodeFileError::UnsupportedConstant("coroutine")),
+            Some(Table::Native(_)) => return Err(BytecodeFileError::UnsupportedConstant("native")),
+            None => return Err(BytecodeFileError::TableOutOfRange(handle)),
+        };
+        self.indices.insert(handle, self.tables.len());
+        self.tables.push(table);
+        Ok(self.tables.len() - 1)
+    }
+}
+
+// constants are deduplicated by their encoding, so the pool is shared between every chunk
+#[derive(Default)]
+struct Pool {
+    writer: Writer,
+    len: usize,
+    indices: HashMap<Vec<u8>, usize>,
+}
+impl Pool {
+    fn index(&mut self, value: Value) -> usize {
+        let encoded = encode_value(value);
+        *self.indices.entry(encoded.clone()).or_insert_with(|| {
+            self.writer.write_bytes(encoded.as_slice());
+            self.len += 1;
+            self.len - 1
+        })
+    }
+}
+fn write_chunk(writer: &mut Writer, pool: &mut Pool, chunk: &Chunk, has_debug: bool) {
+    writer.write_u64(chunk.get_constants().len() as u64);
+    for constant in chunk.get_constants() {
+        writer.write_u64(pool.index(*constant) as u64);
+    }
+    writer.write_u64(chunk.get_instructions().len() as u64);
+    writer.write_bytes(chunk.get_instructions().as_slice());
+    if has_debug {
+        writer.write_debug(chunk.get_debug());
+    }
+}
+fn read_chunk(reader: &mut Reader, pool: &[Value], has_debug: bool) -> Result<Chunk, BytecodeFileError> {
+    let constant_len = reader.read_len()?;
+    let mut constants = Vec::with_capacity(constant_len);
+    for _ in 0..constant_len {
+        let index = reader.read_u64()? as usize;
+        constants.push(*pool.get(index).ok_or(BytecodeFileError::ConstantOutOfRange(index))?);
+    }
+    let instruction_len = reader.read_len()?;
+    let instructions = reader.read_bytes(instruction_len)?.to_vec();
+    let mut chunk = Chunk::new(instructions, constants);
+    if has_debug {
+        if let Some(debug) = reader.read_debug()? {
+            chunk = chunk.with_debug(debug);
+        }
+    }
+    Ok(chunk)
+}
+
+fn encode_value(value: Value) -> Vec<u8> {
+    let mut writer = Writer::default();
+    match value {
+        Value::Nil => writer.write_u8(TAG_NIL),
+        Value::Number(num) => {
+            writer.write_u8(TAG_NUMBER);
+            writer.write_bytes(&num.to_le_bytes());
+        }
+        Value::Float(num) => {
+            writer.write_u8(TAG_FLOAT);
+            writer.write_bytes(&num.to_bits().to_le_bytes());
+        }
+        Value::Boolean(bool) => {
+            writer.write_u8(TAG_BOOLEAN);
+            writer.write_u8(bool as u8);
+        }
+        Value::Table(index) => {
+            writer.write_u8(TAG_TABLE);
+            writer.write_u64(index as u64);
+        }
+    }
+    writer.buffer
+}
+
+// shared with the snapshot format
+#[derive(Default)]
+pub(super) struct Writer {
+    pub(super) buffer: Vec<u8>,
+}
+impl Writer {
+    pub(super) fn write_u8(&mut self, val: u8) {
+        self.buffer.push(val);
+    }
+    pub(super) fn write_u16(&mut self, val: u16) {
+        self.write_bytes(&val.to_le_bytes());
+    }
+    pub(super) fn write_u64(&mut self, val: u64) {
+        self.write_bytes(&val.to_le_bytes());
+    }
+    pub(super) fn write_bytes(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+    pub(super) fn write_str(&mut self, str: &str) {
+        self.write_u64(str.len() as u64);
+        self.write_bytes(str.as_bytes());
+    }
+    pub(super) fn write_value(&mut self, value: Value) {
+        self.write_bytes(encode_value(value).as_slice());
+    }
+    // a u8 saying whether there is any, then the debug info
+    pub(super) fn write_debug(&mut self, debug: Option<&DebugInfo>) {
+        let Some(debug) = debug else {
+            self.write_u8(0);
+            return;
+        };
+        self.write_u8(1);
+        self.write_str(debug.name.as_str());
+        self.write_u64(debug.spans.len() as u64);
+        for (ip, span) in &debug.spans {
+            self.write_u64(*ip as u64);
+            self.write_u64(span.start as u64);
+            self.write_u64(span.end as u64);
+        }
+        self.write_u64(debug.locals.len() as u64);
+        for local in &debug.locals {
+            self.write_str(local.name.as_str());
+            self.write_u64(local.slot as u64);
+            self.write_u64(local.live.start as u64);
+            self.write_u64(local.live.end as u64);
+        }
+    }
+}
+
+pub(super) struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+impl<'a> Reader<'a> {
+    pub(super) fn new(bytes: &'a [u8]) -> Self {
+        Self {
+            bytes,
+            position: 0
+        }
+    }
+    pub(super) fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], BytecodeFileError> {
+        let end = self.position.checked_add(len).ok_or(BytecodeFileError::UnexpectedEnd)?;
+        let bytes = self.bytes.get(self.position..end).ok_or(BytecodeFileError::UnexpectedEnd)?;
+        self.position = end;
+        Ok(bytes)
+    }
+    pub(super) fn read_u8(&mut self) -> Result<u8, BytecodeFileError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+    pub(super) fn read_u16(&mut self) -> Result<u16, BytecodeFileError> {
+        Ok(u16::from_le_bytes(self.read_bytes(2)?.try_into().unwrap()))
+    }
+    pub(super) fn read_u64(&mut self) -> Result<u64, BytecodeFileError> {
+        Ok(u64::from_le_bytes(self.read_bytes(8)?.try_into().unwrap()))
+    }
+    // a length can never be more than the bytes left, so checking here keeps a corrupt
+    // length from making us allocate a huge vec
+    pub(super) fn read_len(&mut self) -> Result<usize, BytecodeFileError> {
+        let len = self.read_u64()?;
+        if len > (self.bytes.len() - self.position) as u64 {
+            return Err(BytecodeFileError::UnexpectedEnd);
+        }
+        Ok(len as usize)
+    }
+    pub(super) fn read_str(&mut self) -> Result<String, BytecodeFileError> {
+        let len = self.read_len()?;
+        String::from_utf8(self.read_bytes(len)?.to_vec()).map_err(|_| BytecodeFileError::InvalidUtf8)
+    }
+    pub(super) fn read_value(&mut self) -> Result<Value, BytecodeFileError> {
+        Ok(match self.read_u8()? {
+            TAG_NIL => Value::Nil,
+            TAG_NUMBER => Value::Number(i64::from_le_bytes(self.read_bytes(8)?.try_into().unwrap())),
+            TAG_FLOAT => Value::Float(f64::from_bits(self.read_u64()?)),
+            TAG_BOOLEAN => Value::Boolean(self.read_u8()? != 0),
+            TAG_TABLE => Value::Table(self.read_u64()? as usize),
+            tag => return Err(BytecodeFileError::UnknownValueTag(tag)),
+        })
+    }
+    pub(super) fn read_debug(&mut self) -> Result<Option<DebugInfo>, BytecodeFileError> {
+        if self.read_u8()? == 0 {
+            return Ok(None);
+        }
+        let name = self.read_str()?;
+        let span_len = self.read_len()?;
+        let mut spans = Vec::with_capacity(span_len);
+        for _ in 0..span_len {
+            let ip = self.read_u64()? as usize;
+            let start = self.read_u64()? as usize;
+            let end = self.read_u64()? as usize;
+            spans.push((ip, start..end));
+        }
+        let local_len = self.read_len()?;
+        let mut locals = Vec::with_capacity(local_len);
+        for _ in 0..local_len {
+            let name = self.read_str()?;
+            let slot = self.read_u64()? as usize;
+            let start = self.read_u64()? as usize;
+            let end = self.read_u64()? as usize;
+            locals.push(LocalInfo {
+                name,
+                slot,
+                live: start..end
+            });
+        }
+        Ok(Some(DebugInfo {
+            name,
+            spans,
+            locals
+        }))
+    }
+    pub(super) fn is_empty(&self) -> bool {
+        self.position == self.bytes.len()
+    }
+}
@@ -0,0 +1,31 @@
+use crate::second_attempt::engine::Engine;
+use crate::second_attempt::vm2::Vm;
+use crate::second_attempt::vm2::file::{BytecodeFile, FileTable};
+use crate::second_attempt::vm2::misc::Value;
+
+// `pick` only ever runs when the entry chunk calls it
+const PROGRAM: &str = r#"
+    fn pick(first, second) capture {
+        second
+    }
+    pick("unused", 42)
+"#;
+
+#[test]
+fn functions_are_written_as_tables() {
+    let mut engine = Engine::new();
+    let chunk = engine.compile(PROGRAM).unwrap();
+    let file = BytecodeFile::from_vm(chunk, engine.vm()).unwrap();
+    assert!(file.tables.iter().any(|table| matches!(table, FileTable::Function { name, .. } if name == "pick")));
+    assert_eq!(file.chunks().count(), 2);
+}
+#[test]
+fn loading_a_file_runs_only_its_entry_chunk() {
+    let mut engine = Engine::new();
+    let chunk = engine.compile(PROGRAM).unwrap();
+    let bytes = BytecodeFile::from_vm(chunk, engine.vm()).unwrap().to_bytes();
+    let mut vm = Vm::default();
+    vm.load_bytecode(BytecodeFile::from_bytes(bytes.as_slice()).unwrap()).unwrap();
+    assert_eq!(vm.get_chunks().len(), 1);
+    assert!(matches!(vm.run().unwrap(), Value::Number(42)));
+}
@@ -0,0 +1,56 @@
+use crate::second_attempt::pretty::{self, Layout, Shape, Tables};
+use crate::second_attempt::vm2::Vm;
+use crate::second_attempt::vm2::disassemble::constant_literal;
+use crate::second_attempt::vm2::misc::{Table, TableKey, Value};
+
+/*
+Turns values into text through the tables they point at, for PRINT, `print` and `to_string`.
+A string on its own is just its contents, inside a table it's quoted so `["1"]` and `[1]` can be
+told apart. Positional entries are written bare as long as they count up from 0 in order, one that
+doesn't (say after something before it was removed) gets its position as a key so nothing is
+misread. Functions, natives and coroutines can't be written out, so they're only named.
+ */
+impl Vm {
+    pub fn format_value(&self, value: Value, layout: Layout) -> String {
+        if let Value::Table(handle) = value {
+            if let Some(Table::String(string)) = self.tables.get(handle) {
+                return string.clone();
+            }
+        }
+        pretty::print(self, value, layout)
+    }
+}
+impl Tables for Vm {
+    type Value = Value;
+    fn shape(&self, value: Value) -> Shape<Value> {
+        let Value::Table(handle) = value else {
+            return Shape::Text(constant_literal(value, None));
+        };
+        match self.tables.get(handle) {
+            Some(Table::String(string)) => Shape::Text(format!("{:?}", string)),
+            Some(Table::Function(function)) => Shape::Text(format!("<function {}>", function.name)),
+            Some(Table::Native(native)) => Shape::Text(format!("<native {}>", native.name)),
+            Some(Table::Coroutine(_)) => Shape::Text("<coroutine>".to_string()),
+            Some(Table::Array(values)) => Shape::Table(handle, values.iter().map(|value| (None, *value)).collect()),
+            Some(Table::Map(map)) => {
+                let mut next_position = 0;
+                let entries = map.iter().map(|(key, value)| {
+                    let key = match key {
+                        TableKey::Identifier(name, _) => Some(name.clone()),
+                        TableKey::NoIdentifier(position) if *position == next_position => {
+                            next_position += 1;
+                            None
+                        }
+                        TableKey::NoIdentifier(position) => {
+                            next_position = position + 1;
+                            Some(position.to_string())
+                        }
+                    };
+                    (key, *value)
+                }).collect();
+                Shape::Table(handle, entries)
+            }
+            None => Shape::Text(format!("<bad table {}>", handle)),
+        }
+    }
+}
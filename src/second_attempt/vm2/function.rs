@@ -0,0 +1,143 @@
+use std::sync::Arc;
+use crate::second_attempt::vm2::{Chunk, Vm};
+use crate::second_attempt::vm2::misc::{Table, Value};
+use crate::second_attempt::vm2::verify::verify;
+use crate::second_attempt::vm_error::{VmError, VmErrorKind};
+
+/*
+Functions live in the table arena like everything else, a function value is a handle to one.
+
+CALL pops the arguments and then the function. A Table function gets a clone of its chunk pushed
+onto the chunk stack, with its own ip over the body every call shares, and the arguments moved onto
+the locals stack, first argument deepest. When that chunk returns whatever it left on top of the
+eval stack is its result, everything else it pushed onto either stack is dropped. A native function is called straight away with the arguments.
+ */
+pub type NativeFunction = Arc<dyn Fn(&mut Vm, &[Value]) -> Result<Value, VmErrorKind> + Send + Sync>;
+
+#[derive(Clone)]
+pub struct Function {
+    pub name: String,
+    pub arity: usize,
+    pub exported: bool,
+    // cloned for every call so each one starts at ip 0, the clones share the instructions
+    pub chunk: Chunk,
+}
+#[derive(Clone)]
+pub struct Native {
+    pub name: String,
+    pub function: NativeFunction,
+}
+#[derive(Copy, Clone)]
+pub(super) struct CallFrame {
+    pub(super) chunk_depth: usize,
+    pub(super) eval_len: usize,
+    pub(super) locals_len: usize,
+}
+impl Vm {
+    // every function's chunk is verified once here rather than on every call
+    pub fn new_function(&mut self, function: Function) -> Result<Value, VmErrorKind> {
+        verify(&function.chunk).map_err(|err| VmErrorKind::InvalidChunk(err.to_string()))?;
+        self.push_table(Table::Function(function))
+    }
+    // natives come from the host, so a table limit never refuses one, they still count towards
+    // `max_tables` though
+    pub fn new_native(&mut self, name: &str, function: NativeFunction) -> Value {
+        self.tables.push(Table::Native(Native {
+            name: name.to_string(),
+            function,
+        }));
+        Value::Table(self.tables.len() - 1)
+    }
+    // where the running chunk's locals start if CALL pushed it, its arguments are the first of them
+    pub fn call_locals_base(&self) -> Option<usize> {
+        let frame = self.calls.last()?;
+        (frame.chunk_depth + 1 == self.chunks.len()).then_some(frame.locals_len)
+    }
+    // the function and `argument_count` arguments are on top of the eval stack
+    pub(super) fn call(&mut self, argument_count: usize) -> Result<(), VmErrorKind> {
+        let eval_len = self.eval.len().checked_sub(argument_count + 1).ok_or(VmErrorKind::StackUnderflow)?;
+        let callee = self.eval.get(eval_len).ok_or(VmErrorKind::StackUnderflow)?;
+        let handle = Self::table_handle(callee)?;
+        match self.tables.get(handle) {
+            Some(Table::Function(function)) => {
+                self.check_call_depth()?;
+                if function.arity != argument_count {
+                    return Err(VmErrorKind::ArityMismatch { expected: function.arity, found: argument_count });
+                }
+                let chunk = function.chunk.clone();
+                let frame = CallFrame {
+                    chunk_depth: self.chunks.len(),
+                    eval_len,
+                    locals_len: self.locals.len(),
+                };
+                for index in 0..argument_count {
+                    let argument = self.eval.get(eval_len + 1 + index).ok_or(VmErrorKind::StackUnderflow)?;
+                    self.locals.push(argument).ok_or_else(|| self.stack_overflow())?;
+                }
+                self.eval.truncate(eval_len);
+                self.calls.push(frame);
+                self.chunks.push(chunk);
+                Ok(())
+            }
+            Some(Table::Native(native)) => {
+                let function = native.function.clone();
+                let arguments = self.eval.to_vec_from(eval_len + 1);
+                self.eval.truncate(eval_len);
+                let result = function(self, &arguments)?;
+                self.push(result)
+            }
+            Some(table) => Err(VmErrorKind::TypeError { expected: "function", found: table.type_name() }),
+            None => Err(VmErrorKind::BadTableHandle(handle)),
+        }
+    }
+    // called after a chunk is popped, hands the result back if that chunk was a call
+    pub(super) fn finish_call(&mut self) -> Result<(), VmErrorKind> {
+        if self.calls.last().map_or(true, |frame| frame.chunk_depth != self.chunks.len()) {
+            return Ok(());
+        }
+        let frame = self.calls.pop().unwrap();
+        let result = match self.eval.len() > frame.eval_len {
+            true => self.eval.pop().unwrap(),
+            false => Value::Nil,
+        };
+        self.eval.truncate(frame.eval_len);
+        self.locals.truncate(frame.locals_len);
+        self.push(result)
+    }
+    // a throw that unwinds past a call's chunk ends the call
+    pub(super) fn unwind_calls(&mut self) {
+        while self.calls.last().map_or(false, |frame| frame.chunk_depth >= self.chunks.len()) {
+            self.calls.pop();
+        }
+    }
+    // calls a function from outside the vm, the vm can be idle or paused inside a native function
+    pub fn call_function(&mut self, function: Value, arguments: &[Value]) -> Result<Value, VmError> {
+        let depth = self.chunks.len();
+        if depth == 0 {
+            self.start_clock();
+        }
+        let at = |kind: VmErrorKind| kind.at(depth, 0);
+        self.push(function).map_err(at)?;
+        for argument in arguments {
+            self.push(*argument).map_err(at)?;
+        }
+        self.call(arguments.len()).map_err(at)?;
+        self.run_until(depth, &mut ())
+    }
+    // runs a chunk as if it were a function without arguments, INJECT instead leaves its values behind
+    pub fn run_chunk(&mut self, chunk: Chunk) -> Result<Value, VmError> {
+        let depth = self.chunks.len();
+        if depth == 0 {
+            self.start_clock();
+        }
+        verify(&chunk).map_err(|err| VmErrorKind::InvalidChunk(err.to_string()).at(depth, 0))?;
+        self.check_call_depth().map_err(|kind| kind.at(depth, 0))?;
+        self.calls.push(CallFrame {
+            chunk_depth: depth,
+            eval_len: self.eval.len(),
+            locals_len: self.locals.len(),
+        });
+        self.chunks.push(chunk);
+        self.run_until(depth, &mut ())
+    }
+}
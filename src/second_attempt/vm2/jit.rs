@@ -0,0 +1,507 @@
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicU32, Ordering};
+use cranelift_codegen::entity::EntityRef;
+use cranelift_codegen::ir::{types, AbiParam, Block, InstBuilder, MemFlags};
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::Value as IrValue;
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Switch, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Linkage, Module};
+use crate::second_attempt::vm2::{bytecode, Chunk, Vm};
+use crate::second_attempt::vm2::bytecode::{decode, Instruction};
+use crate::second_attempt::vm2::misc::Value;
+
+/*
+A baseline jit, built with the `jit` feature. Once a chunk is hot, counted by how often it's entered
+and how often it jumps backwards, its instructions are translated one for one into native code with
+Cranelift.
+
+Compiled code works on the vm's own eval and locals stacks, so at any instruction boundary the vm is
+exactly where the interpreter would have left it. Only the cheap and common cases are compiled:
+constants, locals, jumps, and arithmetic and comparisons on integers. Every one of those first checks
+its operands' tags and the stack bounds, and if anything isn't what it expects (a float, an overflow,
+a division by zero, a full stack) it stops before touching anything and hands the same instruction
+back to the interpreter. Everything else (tables, calls, handlers, coroutines) does the same, so the
+run loop interprets one instruction and then goes back into the compiled code.
+
+The jit stays out of the way while limits are set or a hook other than `()` is attached, since
+neither would see the instructions compiled code runs.
+ */
+
+// entries and backward jumps before a chunk is compiled
+const HOT_THRESHOLD: u32 = 1000;
+
+// `Value` is `#[repr(C, u8)]`, so the tag is its first byte in declaration order and the payload
+// starts at the second word
+const TAG_NUMBER: i64 = 0;
+const TAG_FLOAT: i64 = 1;
+const TAG_BOOLEAN: i64 = 2;
+const TAG_TABLE: i64 = 3;
+const TAG_NIL: i64 = 4;
+const VALUE_SIZE: i64 = 16;
+const PAYLOAD: i32 = 8;
+
+// what compiled code gets to work with, written back to the vm when it returns
+#[repr(C)]
+struct JitState {
+    eval: *mut Value,
+    eval_len: usize,
+    locals: *mut Value,
+    locals_len: usize,
+    // of both stacks
+    capacity: usize,
+    // where to start, and on the way out where the interpreter should carry on
+    ip: usize,
+}
+const EVAL: i32 = 0;
+const EVAL_LEN: i32 = 8;
+const LOCALS: i32 = 16;
+const LOCALS_LEN: i32 = 24;
+const CAPACITY: i32 = 32;
+const IP: i32 = 40;
+
+// shared by every copy of a chunk, so calls to the same function count towards the same code
+#[derive(Clone, Default)]
+pub(super) struct JitProfile(Arc<Profile>);
+#[derive(Default)]
+struct Profile {
+    hotness: AtomicU32,
+    // `None` once compiling has been tried and the chunk had nothing worth compiling
+    compiled: OnceLock<Option<CompiledChunk>>,
+}
+impl JitProfile {
+    pub(super) fn tick(&self) {
+        self.0.hotness.fetch_add(1, Ordering::Relaxed);
+    }
+    #[cfg(test)]
+    pub(super) fn is_compiled(&self) -> bool {
+        matches!(self.0.compiled.get(), Some(Some(_)))
+    }
+}
+struct CompiledChunk {
+    module: Option<JITModule>,
+    entry: unsafe extern "C" fn(*mut JitState),
+}
+// the code is never written to again once it's finalized
+unsafe impl Send for CompiledChunk {}
+unsafe impl Sync for CompiledChunk {}
+impl Drop for CompiledChunk {
+    fn drop(&mut self) {
+        if let Some(module) = self.module.take() {
+            module.free_memory();
+        }
+    }
+}
+
+impl Vm {
+    // on by default when the vm is built with the `jit` feature
+    pub fn set_jit(&mut self, enabled: bool) {
+        self.jit = enabled;
+    }
+    // runs the top chunk's compiled code from its ip, false if there's none or it couldn't get past the first instruction
+    pub(super) fn enter_jit(&mut self) -> bool {
+        let Some(chunk) = self.chunks.last() else {
+            return false;
+        };
+        let profile = &chunk.jit.0;
+        let compiled = match profile.compiled.get() {
+            Some(compiled) => compiled,
+            None => {
+                if chunk.ip == 0 {
+                    profile.hotness.fetch_add(1, Ordering::Relaxed);
+                }
+                if profile.hotness.load(Ordering::Relaxed) < HOT_THRESHOLD {
+                    return false;
+                }
+                profile.compiled.get_or_init(|| compile(chunk).ok())
+            }
+        };
+        let Some(compiled) = compiled else {
+            return false;
+        };
+        let entry = compiled.entry;
+        let start = chunk.ip;
+        // compiled code only sees the top segment of each stack, reaching below it or past its end is
+        // one more thing it hands back to the interpreter
+        let (eval, locals) = (&mut self.eval, &mut self.locals);
+        let ip = eval.with_top_segment(|eval, eval_len| {
+            locals.with_top_segment(|locals, locals_len| {
+                let mut state = JitState {
+                    eval: eval.as_mut_ptr(),
+                    eval_len: *eval_len,
+                    locals: locals.as_mut_ptr(),
+                    locals_len: *locals_len,
+                    capacity: eval.len().min(locals.len()),
+                    ip: start,
+                };
+                unsafe { entry(&mut state) };
+                *eval_len = state.eval_len;
+                *locals_len = state.locals_len;
+                state.ip
+            })
+        });
+        self.set_ip(ip);
+        ip != start
+    }
+}
+
+fn compile(chunk: &Chunk) -> Result<CompiledChunk, String> {
+    let instructions = decode_all(chunk)?;
+    if !instructions.iter().any(|instruction| supported(instruction.opcode)) {
+        return Err("nothing to compile".to_string());
+    }
+    let mut flags = settings::builder();
+    flags.set("opt_level", "speed").map_err(|err| err.to_string())?;
+    flags.set("use_colocated_libcalls", "false").map_err(|err| err.to_string())?;
+    flags.set("is_pic", "false").map_err(|err| err.to_string())?;
+    let isa = cranelift_native::builder()
+        .map_err(|err| err.to_string())?
+        .finish(settings::Flags::new(flags))
+        .map_err(|err| err.to_string())?;
+    // `JitState` and `Value` are laid out for 64 bit
+    if isa.pointer_type() != types::I64 {
+        return Err("the jit only supports 64 bit targets".to_string());
+    }
+    let mut module = JITModule::new(JITBuilder::with_isa(isa, default_libcall_names()));
+    let mut context = module.make_context();
+    context.func.signature.params.push(AbiParam::new(types::I64));
+    let mut builder_context = FunctionBuilderContext::new();
+    let builder = FunctionBuilder::new(&mut context.func, &mut builder_context);
+    Translator::new(builder, &instructions, chunk.get_instructions().len()).translate(chunk, &instructions);
+    let id = module.declare_function("chunk", Linkage::Local, &context.func.signature).map_err(|err| err.to_string())?;
+    module.define_function(id, &mut context).map_err(|err| err.to_string())?;
+    module.clear_context(&mut context);
+    module.finalize_definitions().map_err(|err| err.to_string())?;
+    let code = module.get_finalized_function(id);
+    let entry = unsafe { std::mem::transmute::<*const u8, unsafe extern "C" fn(*mut JitState)>(code) };
+    Ok(CompiledChunk {
+        module: Some(module),
+        entry,
+    })
+}
+fn decode_all(chunk: &Chunk) -> Result<Vec<Instruction>, String> {
+    let mut instructions = vec![];
+    let mut ip = 0;
+    while ip < chunk.get_instructions().len() {
+        let instruction = decode(chunk.get_instructions(), ip).map_err(|err| format!("{:?}", err))?;
+        ip = instruction.next_ip();
+        instructions.push(instruction);
+    }
+    Ok(instructions)
+}
+fn supported(opcode: u8) -> bool {
+    matches!(opcode, bytecode::CONSTANT | bytecode::POP | bytecode::GET_LOCAL | bytecode::PEEK_LOCAL
+        | bytecode::SET_LOCAL | bytecode::PUSH_LOCAL | bytecode::POP_LOCAL | bytecode::JUMP | bytecode::JUMP_IF
+        | bytecode::TEST_TRUTHY | bytecode::TEST_NOT | bytecode::TEST_EQUAL | bytecode::TEST_LESS
+        | bytecode::TEST_LESS_EQUAL | bytecode::TEST_GREATER | bytecode::TEST_GREATER_EQUAL
+        | bytecode::ADD | bytecode::SUBTRACT | bytecode::MULTIPLY | bytecode::DIVIDE)
+}
+
+struct Translator<'a> {
+    builder: FunctionBuilder<'a>,
+    // the block for every instruction, and one for the end of the chunk
+    blocks: HashMap<usize, Block>,
+    // takes the ip to hand back to the interpreter
+    exit: Block,
+    eval_len: Variable,
+    locals_len: Variable,
+    eval: IrValue,
+    locals: IrValue,
+    capacity: IrValue,
+}
+impl<'a> Translator<'a> {
+    fn new(mut builder: FunctionBuilder<'a>, instructions: &[Instruction], end: usize) -> Self {
+        let entry = builder.create_block();
+        builder.append_block_params_for_function_params(entry);
+        let mut blocks = HashMap::new();
+        for instruction in instructions {
+            blocks.insert(instruction.ip, builder.create_block());
+        }
+        blocks.insert(end, builder.create_block());
+        let exit = builder.create_block();
+        builder.append_block_param(exit, types::I64);
+        builder.switch_to_block(entry);
+        let state = builder.block_params(entry)[0];
+        let flags = MemFlags::trusted();
+        let eval = builder.ins().load(types::I64, flags, state, EVAL);
+        let locals = builder.ins().load(types::I64, flags, state, LOCALS);
+        let capacity = builder.ins().load(types::I64, flags, state, CAPACITY);
+        let (eval_len, locals_len) = (Variable::new(0), Variable::new(1));
+        builder.declare_var(eval_len, types::I64);
+        builder.declare_var(locals_len, types::I64);
+        let value = builder.ins().load(types::I64, flags, state, EVAL_LEN);
+        builder.def_var(eval_len, value);
+        let value = builder.ins().load(types::I64, flags, state, LOCALS_LEN);
+        builder.def_var(locals_len, value);
+        let ip = builder.ins().load(types::I64, flags, state, IP);
+        let mut translator = Self {
+            builder,
+            blocks,
+            exit,
+            eval_len,
+            locals_len,
+            eval,
+            locals,
+            capacity,
+        };
+        // start wherever the interpreter is, an ip that isn't an instruction goes straight back
+        let unknown = translator.builder.create_block();
+        let mut switch = Switch::new();
+        for (ip, block) in &translator.blocks {
+            switch.set_entry(*ip as u128, *block);
+        }
+        switch.emit(&mut translator.builder, ip, unknown);
+        translator.builder.switch_to_block(unknown);
+        translator.builder.ins().jump(exit, &[ip]);
+        translator.builder.switch_to_block(exit);
+        let ip = translator.builder.block_params(exit)[0];
+        let eval_len = translator.builder.use_var(eval_len);
+        let locals_len = translator.builder.use_var(locals_len);
+        translator.builder.ins().store(flags, eval_len, state, EVAL_LEN);
+        translator.builder.ins().store(flags, locals_len, state, LOCALS_LEN);
+        translator.builder.ins().store(flags, ip, state, IP);
+        translator.builder.ins().return_(&[]);
+        translator
+    }
+    fn translate(mut self, chunk: &Chunk, instructions: &[Instruction]) {
+        for instruction in instructions {
+            self.builder.switch_to_block(self.blocks[&instruction.ip]);
+            self.instruction(chunk, instruction);
+        }
+        let end = chunk.get_instructions().len();
+        self.builder.switch_to_block(self.blocks[&end]);
+        self.bail(end);
+        self.builder.seal_all_blocks();
+        self.builder.finalize();
+    }
+    // every instruction checks everything it needs before it changes anything, so a failed check can
+    // hand the same instruction to the interpreter
+    fn instruction(&mut self, chunk: &Chunk, instruction: &Instruction) {
+        let ip = instruction.ip;
+        let next = self.blocks[&instruction.next_ip()];
+        let operand = instruction.operand.unwrap_or_default();
+        match instruction.opcode {
+            bytecode::CONSTANT => {
+                self.guard_room(ip, self.eval_len);
+                let len = self.builder.use_var(self.eval_len);
+                let slot = self.slot(self.eval, len);
+                self.write_constant(slot, chunk.get_constants()[operand]);
+                self.add_len(self.eval_len, 1);
+            }
+            bytecode::POP => {
+                self.guard_depth(ip, self.eval_len, 1);
+                self.add_len(self.eval_len, -1);
+            }
+            bytecode::GET_LOCAL | bytecode::PEEK_LOCAL => {
+                self.guard_depth(ip, self.locals_len, operand as i64 + 1);
+                self.guard_room(ip, self.eval_len);
+                let from = self.from_top(self.locals, self.locals_len, operand as i64);
+                let len = self.builder.use_var(self.eval_len);
+                let to = self.slot(self.eval, len);
+                self.copy(from, to);
+                self.add_len(self.eval_len, 1);
+            }
+            bytecode::SET_LOCAL => {
+                self.guard_depth(ip, self.eval_len, 1);
+                self.guard_depth(ip, self.locals_len, operand as i64 + 1);
+                let from = self.from_top(self.eval, self.eval_len, 0);
+                let to = self.from_top(self.locals, self.locals_len, operand as i64);
+                self.copy(from, to);
+                self.add_len(self.eval_len, -1);
+            }
+            bytecode::PUSH_LOCAL => {
+                self.guard_depth(ip, self.eval_len, 1);
+                self.guard_room(ip, self.locals_len);
+                let from = self.from_top(self.eval, self.eval_len, 0);
+                let len = self.builder.use_var(self.locals_len);
+                let to = self.slot(self.locals, len);
+                self.copy(from, to);
+                self.add_len(self.eval_len, -1);
+                self.add_len(self.locals_len, 1);
+            }
+            bytecode::POP_LOCAL => {
+                self.guard_depth(ip, self.locals_len, 1);
+                self.guard_room(ip, self.eval_len);
+                let from = self.from_top(self.locals, self.locals_len, 0);
+                let len = self.builder.use_var(self.eval_len);
+                let to = self.slot(self.eval, len);
+                self.copy(from, to);
+                self.add_len(self.locals_len, -1);
+                self.add_len(self.eval_len, 1);
+            }
+            bytecode::JUMP => {
+                let target = self.blocks[&operand];
+                self.builder.ins().jump(target, &[]);
+                return;
+            }
+            bytecode::JUMP_IF => {
+                self.guard_depth(ip, self.eval_len, 1);
+                let top = self.from_top(self.eval, self.eval_len, 0);
+                self.guard_tag(ip, top, TAG_BOOLEAN);
+                let condition = self.builder.ins().load(types::I8, MemFlags::trusted(), top, PAYLOAD);
+                self.add_len(self.eval_len, -1);
+                let target = self.blocks[&operand];
+                self.builder.ins().brnz(condition, target, &[]);
+                self.builder.ins().jump(next, &[]);
+                return;
+            }
+            bytecode::TEST_TRUTHY | bytecode::TEST_NOT => {
+                self.guard_depth(ip, self.eval_len, 1);
+                let top = self.from_top(self.eval, self.eval_len, 0);
+                self.guard_tag(ip, top, TAG_BOOLEAN);
+                if instruction.opcode == bytecode::TEST_NOT {
+                    let value = self.builder.ins().load(types::I8, MemFlags::trusted(), top, PAYLOAD);
+                    let value = self.builder.ins().icmp_imm(IntCC::Equal, value, 0);
+                    self.builder.ins().store(MemFlags::trusted(), value, top, PAYLOAD);
+                }
+            }
+            bytecode::ADD | bytecode::SUBTRACT | bytecode::MULTIPLY | bytecode::DIVIDE => {
+                let (lhs_slot, lhs, rhs) = self.integer_operands(ip);
+                let (result, failed) = match instruction.opcode {
+                    bytecode::ADD => {
+                        let result = self.builder.ins().iadd(lhs, rhs);
+                        // overflowed if the result's sign differs from both operands'
+                        let lhs_sign = self.builder.ins().bxor(lhs, result);
+                        let rhs_sign = self.builder.ins().bxor(rhs, result);
+                        let overflow = self.builder.ins().band(lhs_sign, rhs_sign);
+                        (result, self.builder.ins().icmp_imm(IntCC::SignedLessThan, overflow, 0))
+                    }
+                    bytecode::SUBTRACT => {
+                        let result = self.builder.ins().isub(lhs, rhs);
+                        let operand_sign = self.builder.ins().bxor(lhs, rhs);
+                        let result_sign = self.builder.ins().bxor(lhs, result);
+                        let overflow = self.builder.ins().band(operand_sign, result_sign);
+                        (result, self.builder.ins().icmp_imm(IntCC::SignedLessThan, overflow, 0))
+                    }
+                    bytecode::MULTIPLY => {
+                        let result = self.builder.ins().imul(lhs, rhs);
+                        // the high half of the full product is only the sign of the low half if it fit
+                        let high = self.builder.ins().smulhi(lhs, rhs);
+                        let sign = self.builder.ins().sshr_imm(result, 63);
+                        (result, self.builder.ins().icmp(IntCC::NotEqual, high, sign))
+                    }
+                    _ => {
+                        let zero = self.builder.ins().icmp_imm(IntCC::Equal, rhs, 0);
+                        let min = self.builder.ins().icmp_imm(IntCC::Equal, lhs, i64::MIN);
+                        let minus_one = self.builder.ins().icmp_imm(IntCC::Equal, rhs, -1);
+                        let overflow = self.builder.ins().band(min, minus_one);
+                        let failed = self.builder.ins().bor(zero, overflow);
+                        // checked before dividing, sdiv traps on both
+                        self.guard(ip, failed, false);
+                        let result = self.builder.ins().sdiv(lhs, rhs);
+                        let failed = self.builder.ins().iconst(types::I8, 0);
+                        (result, failed)
+                    }
+                };
+                // promoting to a float on overflow is the interpreter's job
+                self.guard(ip, failed, false);
+                self.builder.ins().store(MemFlags::trusted(), result, lhs_slot, PAYLOAD);
+                self.add_len(self.eval_len, -1);
+            }
+            bytecode::TEST_EQUAL | bytecode::TEST_LESS | bytecode::TEST_LESS_EQUAL
+            | bytecode::TEST_GREATER | bytecode::TEST_GREATER_EQUAL => {
+                let (lhs_slot, lhs, rhs) = self.integer_operands(ip);
+                let condition = match instruction.opcode {
+                    bytecode::TEST_EQUAL => IntCC::Equal,
+                    bytecode::TEST_LESS => IntCC::SignedLessThan,
+                    bytecode::TEST_LESS_EQUAL => IntCC::SignedLessThanOrEqual,
+                    bytecode::TEST_GREATER => IntCC::SignedGreaterThan,
+                    _ => IntCC::SignedGreaterThanOrEqual,
+                };
+                let result = self.builder.ins().icmp(condition, lhs, rhs);
+                let tag = self.builder.ins().iconst(types::I8, TAG_BOOLEAN);
+                self.builder.ins().store(MemFlags::trusted(), tag, lhs_slot, 0);
+                self.builder.ins().store(MemFlags::trusted(), result, lhs_slot, PAYLOAD);
+                self.add_len(self.eval_len, -1);
+            }
+            _ => {
+                self.bail(ip);
+                return;
+            }
+        }
+        self.builder.ins().jump(next, &[]);
+    }
+    // the top two values, both checked to be integers, and where the result goes
+    fn integer_operands(&mut self, ip: usize) -> (IrValue, IrValue, IrValue) {
+        self.guard_depth(ip, self.eval_len, 2);
+        let rhs_slot = self.from_top(self.eval, self.eval_len, 0);
+        let lhs_slot = self.from_top(self.eval, self.eval_len, 1);
+        self.guard_tag(ip, rhs_slot, TAG_NUMBER);
+        self.guard_tag(ip, lhs_slot, TAG_NUMBER);
+        let lhs = self.builder.ins().load(types::I64, MemFlags::trusted(), lhs_slot, PAYLOAD);
+        let rhs = self.builder.ins().load(types::I64, MemFlags::trusted(), rhs_slot, PAYLOAD);
+        (lhs_slot, lhs, rhs)
+    }
+    fn bail(&mut self, ip: usize) {
+        let ip = self.builder.ins().iconst(types::I64, ip as i64);
+        self.builder.ins().jump(self.exit, &[ip]);
+    }
+    // goes back to the interpreter at `ip` unless `condition` is `expected`
+    fn guard(&mut self, ip: usize, condition: IrValue, expected: bool) {
+        let carry_on = self.builder.create_block();
+        let ip = self.builder.ins().iconst(types::I64, ip as i64);
+        match expected {
+            true => self.builder.ins().brz(condition, self.exit, &[ip]),
+            false => self.builder.ins().brnz(condition, self.exit, &[ip]),
+        };
+        self.builder.ins().jump(carry_on, &[]);
+        self.builder.switch_to_block(carry_on);
+    }
+    fn guard_depth(&mut self, ip: usize, len: Variable, depth: i64) {
+        let len = self.builder.use_var(len);
+        let deep_enough = self.builder.ins().icmp_imm(IntCC::UnsignedGreaterThanOrEqual, len, depth);
+        self.guard(ip, deep_enough, true);
+    }
+    fn guard_room(&mut self, ip: usize, len: Variable) {
+        let len = self.builder.use_var(len);
+        let room = self.builder.ins().icmp(IntCC::UnsignedLessThan, len, self.capacity);
+        self.guard(ip, room, true);
+    }
+    fn guard_tag(&mut self, ip: usize, slot: IrValue, tag: i64) {
+        let found = self.builder.ins().load(types::I8, MemFlags::trusted(), slot, 0);
+        let matches = self.builder.ins().icmp_imm(IntCC::Equal, found, tag);
+        self.guard(ip, matches, true);
+    }
+    fn add_len(&mut self, len: Variable, amount: i64) {
+        let value = self.builder.use_var(len);
+        let value = self.builder.ins().iadd_imm(value, amount);
+        self.builder.def_var(len, value);
+    }
+    fn slot(&mut self, stack: IrValue, index: IrValue) -> IrValue {
+        let offset = self.builder.ins().imul_imm(index, VALUE_SIZE);
+        self.builder.ins().iadd(stack, offset)
+    }
+    // `index` 0 is the top, like `Stack::peek`
+    fn from_top(&mut self, stack: IrValue, len: Variable, index: i64) -> IrValue {
+        let len = self.builder.use_var(len);
+        let position = self.builder.ins().iadd_imm(len, -1 - index);
+        self.slot(stack, position)
+    }
+    fn copy(&mut self, from: IrValue, to: IrValue) {
+        for offset in [0, PAYLOAD] {
+            let word = self.builder.ins().load(types::I64, MemFlags::trusted(), from, offset);
+            self.builder.ins().store(MemFlags::trusted(), word, to, offset);
+        }
+    }
+    fn write_constant(&mut self, slot: IrValue, value: Value) {
+        let (tag, payload) = match value {
+            Value::Number(num) => (TAG_NUMBER, Some(num)),
+            Value::Float(num) => (TAG_FLOAT, Some(num.to_bits() as i64)),
+            Value::Boolean(_) => (TAG_BOOLEAN, None),
+            Value::Table(handle) => (TAG_TABLE, Some(handle as i64)),
+            Value::Nil => (TAG_NIL, None),
+        };
+        let tag = self.builder.ins().iconst(types::I8, tag);
+        self.builder.ins().store(MemFlags::trusted(), tag, slot, 0);
+        if let Value::Boolean(bool) = value {
+            let bool = self.builder.ins().iconst(types::I8, bool as i64);
+            self.builder.ins().store(MemFlags::trusted(), bool, slot, PAYLOAD);
+        }
+        if let Some(payload) = payload {
+            let payload = self.builder.ins().iconst(types::I64, payload);
+            self.builder.ins().store(MemFlags::trusted(), payload, slot, PAYLOAD);
+        }
+    }
+}
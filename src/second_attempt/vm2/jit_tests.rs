@@ -0,0 +1,124 @@
+use crate::second_attempt::vm2::{Chunk, Vm};
+use crate::second_attempt::vm2::bytecode::{convert_bytecode_array, Bytecode, Bytecode::*};
+use crate::second_attempt::vm2::misc::Value;
+
+/*
+Every program runs once interpreted and once with the jit, and has to end the same way, errors
+included. They all loop well past the point where the jit compiles them, so the later iterations
+run compiled right up to whatever the test is about.
+ */
+const ITERATIONS: usize = 3000;
+
+// `setup`, then `body` for as long as the local `counter` down from the top is less than the
+// constant at `limit`, then `finish`. Locals are numbered from the top, as GET_LOCAL counts them
+fn counted_loop(setup: Vec<Bytecode>, counter: usize, limit: usize, body: Vec<Bytecode>, finish: Vec<Bytecode>) -> Vec<u8> {
+    let size = |code: &[Bytecode]| code.iter().map(Bytecode::size).sum::<usize>();
+    let start = size(&setup);
+    let test_size = size(&[GetLocal(counter), Constant(limit), TestLess, TestNot, JumpIf(0)]);
+    let end = start + test_size + size(&body) + Jump(0).size();
+    let mut code = setup;
+    code.extend([GetLocal(counter), Constant(limit), TestLess, TestNot, JumpIf(end)]);
+    code.extend(body);
+    code.push(Jump(start));
+    code.extend(finish);
+    convert_bytecode_array(code)
+}
+// how the program ended and whether the jit compiled its chunk
+fn run(code: &[u8], constants: &[Value], jit: bool) -> (String, bool) {
+    let mut vm = Vm::default();
+    vm.set_jit(jit);
+    let chunk = Chunk::new(code.to_vec(), constants.to_vec());
+    // copies share the profile, so this one sees the code compiled for the one that runs
+    let main = chunk.clone();
+    vm.load(chunk).unwrap();
+    let result = match vm.run() {
+        Ok(value) => value.to_string(),
+        Err(err) => err.to_string(),
+    };
+    (result, main.jit.is_compiled())
+}
+fn run_both(code: Vec<u8>, constants: Vec<Value>) -> String {
+    let (interpreted, _) = run(&code, &constants, false);
+    let (jit, compiled) = run(&code, &constants, true);
+    assert!(compiled, "the jit never compiled the program");
+    assert_eq!(interpreted, jit);
+    interpreted
+}
+
+#[test]
+fn overflow_promotes_to_float() {
+    // sum starts 2_000_000 below i64::MAX and overflows around i = 2000
+    let constants = vec![Value::Number(0), Value::Number(ITERATIONS as i64), Value::Number(1), Value::Number(9223372036852775807)];
+    let code = counted_loop(
+        vec![Constant(3), PushLocal, Constant(0), PushLocal],
+        0, 1,
+        vec![GetLocal(1), GetLocal(0), Add, SetLocal(1), GetLocal(0), Constant(2), Add, SetLocal(0)],
+        vec![GetLocal(1)],
+    );
+    assert_eq!(run_both(code, constants), "9223372036856821760.0");
+}
+#[test]
+fn division_by_zero() {
+    // 100 / (2000 - i) fails once i gets to 2000
+    let constants = vec![Value::Number(0), Value::Number(2000), Value::Number(1), Value::Number(100), Value::Number(ITERATIONS as i64)];
+    let code = counted_loop(
+        vec![Constant(0), PushLocal],
+        0, 4,
+        vec![GetLocal(0), Constant(2), Add, SetLocal(0), Constant(3), Constant(1), GetLocal(0), Subtract, Divide, Pop],
+        vec![GetLocal(0)],
+    );
+    assert!(run_both(code, constants).starts_with("integer division by zero"));
+}
+#[test]
+fn floats_fail_the_tag_guards() {
+    // x counts up in floats and is compared with a float, n counts the iterations as an integer
+    let constants = vec![Value::Float(0.0), Value::Float(4500.0), Value::Float(1.5), Value::Number(0), Value::Number(1)];
+    let code = counted_loop(
+        vec![Constant(0), PushLocal, Constant(3), PushLocal],
+        1, 1,
+        vec![GetLocal(1), Constant(2), Add, SetLocal(1), GetLocal(0), Constant(4), Add, SetLocal(0)],
+        vec![GetLocal(1), GetLocal(0), Multiply],
+    );
+    assert_eq!(run_both(code, constants), "13500000.0");
+}
+#[test]
+fn table_op_inside_the_loop() {
+    // t[i] = i; sum = sum + t[i]
+    let constants = vec![Value::Number(0), Value::Number(ITERATIONS as i64), Value::Number(1)];
+    let code = counted_loop(
+        vec![AllocTable, PushLocal, Constant(0), PushLocal, Constant(0), PushLocal],
+        0, 1,
+        vec![
+            GetLocal(0), GetLocal(0), GetLocal(2), InsertIndexTable,
+            GetLocal(1), GetLocal(0), GetLocal(2), GetIndexTable, Add, SetLocal(1),
+            GetLocal(0), Constant(2), Add, SetLocal(0),
+        ],
+        vec![GetLocal(1)],
+    );
+    assert_eq!(run_both(code, constants), "4498500");
+}
+#[test]
+fn stacks_cross_a_segment_boundary() {
+    // every iteration puts 300 values on the eval stack and adds them up, and pushes the locals
+    // stack from just under a segment boundary to just over it and back
+    let constants = vec![Value::Number(0), Value::Number(ITERATIONS as i64), Value::Number(1)];
+    let total = 251;
+    let mut setup = vec![Constant(0), PushLocal];
+    for _ in 0..250 {
+        setup.extend([Constant(0), PushLocal]);
+    }
+    setup.extend([Constant(0), PushLocal]);
+    let mut body: Vec<Bytecode> = (0..300).map(|_| Constant(2)).collect();
+    body.extend((0..299).map(|_| Add));
+    body.extend([GetLocal(total), Add, SetLocal(total)]);
+    // i sinks one further down with every copy pushed on top of it
+    for depth in 0..10 {
+        body.extend([GetLocal(depth), PushLocal]);
+    }
+    for _ in 0..10 {
+        body.extend([PopLocal, Pop]);
+    }
+    body.extend([GetLocal(0), Constant(2), Add, SetLocal(0)]);
+    let code = counted_loop(setup, 0, 1, body, vec![GetLocal(total)]);
+    assert_eq!(run_both(code, constants), "900000");
+}
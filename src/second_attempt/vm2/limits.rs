@@ -0,0 +1,125 @@
+use std::mem::size_of;
+use std::time::{Duration, Instant};
+use crate::second_attempt::vm2::Vm;
+use crate::second_attempt::vm2::misc::{Table, TableKey, Value};
+use crate::second_attempt::vm_error::{Limit, VmErrorKind};
+
+/*
+Limits for running code you don't trust. Every limit is off by default and a vm without any set
+doesn't pay for them beyond one branch per instruction.
+
+Hitting a limit stops the vm with `VmErrorKind::LimitExceeded`. Unlike other runtime errors it
+skips every handler, so a script can't catch it and carry on.
+
+Table memory is an estimate: each table, entry and string byte is counted when it's allocated.
+Nothing is ever freed so it only goes up. Both table limits are checked before anything is added
+to the arena, so a native building a big table fails before it goes past them.
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Limits {
+    // instructions the vm can run before it stops, what's left carries over between runs
+    pub fuel: Option<u64>,
+    pub max_call_depth: Option<usize>,
+    pub max_tables: Option<usize>,
+    // in bytes
+    pub max_table_memory: Option<usize>,
+    // wall clock time for each run, starting when `run` or `call_function` is called from outside
+    pub timeout: Option<Duration>,
+}
+impl Limits {
+    fn any(&self) -> bool {
+        self.fuel.is_some() || self.max_call_depth.is_some() || self.max_tables.is_some()
+            || self.max_table_memory.is_some() || self.timeout.is_some()
+    }
+}
+// every call clones the callee's chunk onto the chunk stack, so even without limits recursion stops here
+// with a stack overflow instead of running the process out of memory
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 1 << 14;
+// reading the clock is slow next to an instruction, so the deadline is only checked this often
+const DEADLINE_INTERVAL: u64 = 1024;
+
+#[derive(Default)]
+pub(super) struct Usage {
+    pub(super) limited: bool,
+    pub(super) fuel: Option<u64>,
+    pub(super) deadline: Option<Instant>,
+    pub(super) steps: u64,
+    pub(super) table_memory: usize,
+}
+impl Vm {
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.usage.limited = limits.any();
+        self.usage.fuel = limits.fuel;
+        self.limits = limits;
+    }
+    pub fn get_limits(&self) -> &Limits {
+        &self.limits
+    }
+    // what's left of the instruction budget
+    pub fn get_fuel(&self) -> Option<u64> {
+        self.usage.fuel
+    }
+    pub fn add_fuel(&mut self, fuel: u64) {
+        if let Some(remaining) = &mut self.usage.fuel {
+            *remaining = remaining.saturating_add(fuel);
+        }
+    }
+    pub fn get_table_memory(&self) -> usize {
+        self.usage.table_memory
+    }
+    pub(super) fn start_clock(&mut self) {
+        self.usage.deadline = self.limits.timeout.map(|timeout| Instant::now() + timeout);
+    }
+    // called before every instruction while any limit is set
+    pub(super) fn check_limits(&mut self) -> Result<(), VmErrorKind> {
+        if let Some(fuel) = &mut self.usage.fuel {
+            if *fuel == 0 {
+                return Err(VmErrorKind::LimitExceeded(Limit::Fuel));
+            }
+            *fuel -= 1;
+        }
+        self.usage.steps += 1;
+        if self.usage.steps % DEADLINE_INTERVAL == 0 && self.usage.deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+            return Err(VmErrorKind::LimitExceeded(Limit::Deadline));
+        }
+        Ok(())
+    }
+    pub(super) fn check_call_depth(&self) -> Result<(), VmErrorKind> {
+        match self.limits.max_call_depth {
+            Some(max) if self.calls.len() >= max => Err(VmErrorKind::LimitExceeded(Limit::CallDepth)),
+            _ if self.chunks.len() >= DEFAULT_MAX_CALL_DEPTH => Err(self.stack_overflow()),
+            _ => Ok(()),
+        }
+    }
+    // called before a table goes into the arena
+    pub(super) fn track_table(&mut self, table: &Table) -> Result<(), VmErrorKind> {
+        if self.limits.max_tables.map_or(false, |max| self.tables.len() >= max) {
+            return Err(VmErrorKind::LimitExceeded(Limit::Tables));
+        }
+        self.track_memory(size_of::<Table>() + match table {
+            Table::Map(map) => map.keys().map(entry_size).sum(),
+            Table::String(string) => string.len(),
+            Table::Array(values) => values.len() * size_of::<Value>(),
+            Table::Function(function) => function.chunk.get_instructions().len() + function.chunk.get_constants().len() * size_of::<Value>(),
+            Table::Coroutine(_) | Table::Native(_) => 0,
+        })
+    }
+    // called before an entry goes into a table
+    pub(super) fn track_entry(&mut self, key: &TableKey) -> Result<(), VmErrorKind> {
+        self.track_memory(entry_size(key))
+    }
+    fn track_memory(&mut self, size: usize) -> Result<(), VmErrorKind> {
+        let table_memory = self.usage.table_memory.saturating_add(size);
+        if self.limits.max_table_memory.map_or(false, |max| table_memory > max) {
+            return Err(VmErrorKind::LimitExceeded(Limit::TableMemory));
+        }
+        self.usage.table_memory = table_memory;
+        Ok(())
+    }
+}
+fn entry_size(key: &TableKey) -> usize {
+    size_of::<(TableKey, Value)>() + match key {
+        TableKey::Identifier(name, _) => name.len(),
+        TableKey::NoIdentifier(_) => 0,
+    }
+}
@@ -0,0 +1,37 @@
+use crate::second_attempt::engine::{Engine, EngineError};
+use crate::second_attempt::vm2::compiler::CompileError;
+use crate::second_attempt::vm2::limits::Limits;
+use crate::second_attempt::vm_error::{Limit, VmErrorKind};
+
+#[test]
+fn a_native_stops_at_the_table_limit() {
+    let mut engine = Engine::new();
+    let chunk = engine.compile(r#"compile("1")"#).unwrap();
+    // `compile` needs three tables for the chunk it returns
+    let max = engine.vm().get_tables().len() + 1;
+    engine.set_limits(Limits { max_tables: Some(max), ..Default::default() });
+    let err = engine.run(chunk).unwrap_err();
+    assert!(matches!(err, EngineError::Runtime(err) if err.kind == VmErrorKind::LimitExceeded(Limit::Tables)));
+    assert!(engine.vm().get_tables().len() <= max);
+}
+#[test]
+fn a_native_stops_at_the_memory_limit() {
+    let mut engine = Engine::new();
+    engine.set_global("numbers", vec![0i64; 1000]).unwrap();
+    let chunk = engine.compile("to_string(numbers)").unwrap();
+    // the string `to_string` builds is far bigger than this
+    let max = engine.vm().get_table_memory() + 256;
+    engine.set_limits(Limits { max_table_memory: Some(max), ..Default::default() });
+    let err = engine.run(chunk).unwrap_err();
+    assert!(matches!(err, EngineError::Runtime(err) if err.kind == VmErrorKind::LimitExceeded(Limit::TableMemory)));
+    assert!(engine.vm().get_table_memory() <= max);
+}
+#[test]
+fn compiling_stops_at_the_table_limit() {
+    let mut engine = Engine::new();
+    let max = engine.vm().get_tables().len();
+    engine.set_limits(Limits { max_tables: Some(max), ..Default::default() });
+    let result = engine.compile(r#"let greeting = "hello";"#);
+    assert!(matches!(result, Err(EngineError::Compile(CompileError::Vm(VmErrorKind::LimitExceeded(Limit::Tables))))));
+    assert_eq!(engine.vm().get_tables().len(), max);
+}
@@ -1,9 +1,17 @@
 use std::fmt::{Display, Formatter};
+use std::ops::Deref;
 use indexmap::IndexMap;
+use crate::second_attempt::numeric::Numeric;
+use crate::second_attempt::scalar::Scalar;
+use crate::second_attempt::vm2::coroutine::Coroutine;
+use crate::second_attempt::vm2::function::{Function, Native};
 
+// the jit reads values straight out of the stacks, so their layout is fixed
 #[derive(Copy, Clone, Debug)]
+#[repr(C, u8)]
 pub enum Value {
     Number(i64),
+    Float(f64),
     Boolean(bool),
     Table(usize),
     Nil,
@@ -12,22 +20,103 @@ impl Display for Value {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             Value::Number(num) => write!(f, "{}", num),
+            Value::Float(num) => write!(f, "{}", Numeric::Float(*num)),
             Value::Boolean(bool) => write!(f, "{}", bool),
-            Value::Table(_) => unimplemented!(),
+            Value::Table(index) => write!(f, "Table({})", index),
             Value::Nil => write!(f, "Nil"),
         }
     }
 }
 pub enum Table {
-    Map(IndexMap<TableKey, Value>),
+    Map(Map),
     String(String),
     Array(Vec<Value>),
+    Coroutine(Coroutine),
+    Function(Function),
+    Native(Native),
+}
+impl Table {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Table::Map(_) | Table::Array(_) => "table",
+            Table::String(_) => "string",
+            Table::Coroutine(_) => "coroutine",
+            Table::Function(_) | Table::Native(_) => "function",
+        }
+    }
 }
 #[derive(Debug, Hash, Eq, PartialEq, Clone)]
 pub enum TableKey {
     Identifier(String, Option<usize>),
     NoIdentifier(usize),
 }
+// a map table's entries, which keeps one past its highest index up to date so appending doesn't have
+// to look at every key. Reading goes straight to the `IndexMap`, changing it goes through here
+#[derive(Clone, Debug, Default)]
+pub struct Map {
+    entries: IndexMap<TableKey, Value>,
+    next_index: usize,
+}
+impl Map {
+    pub fn next_index(&self) -> usize {
+        self.next_index
+    }
+    pub fn insert(&mut self, key: TableKey, value: Value) -> Option<Value> {
+        if let TableKey::NoIdentifier(index) = key {
+            self.next_index = self.next_index.max(index + 1);
+        }
+        self.entries.insert(key, value)
+    }
+    // shifts everything after it down one, so the rest stay in insertion order
+    pub fn shift_remove(&mut self, key: &TableKey) -> Option<Value> {
+        let removed = self.entries.shift_remove(key)?;
+        if *key == TableKey::NoIdentifier(self.next_index - 1) {
+            self.next_index = self.after_highest_below(self.next_index - 1);
+        }
+        Some(removed)
+    }
+    pub fn values_mut(&mut self) -> indexmap::map::ValuesMut<'_, TableKey, Value> {
+        self.entries.values_mut()
+    }
+    // counts down from `index` unless there are fewer keys to look through than that
+    fn after_highest_below(&self, index: usize) -> usize {
+        if index <= self.entries.len() {
+            return (0..index).rev()
+                .find(|index| self.entries.contains_key(&TableKey::NoIdentifier(*index)))
+                .map_or(0, |index| index + 1);
+        }
+        self.entries.keys()
+            .filter_map(|key| match key {
+                TableKey::NoIdentifier(index) => Some(index + 1),
+                TableKey::Identifier(..) => None,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+}
+impl Deref for Map {
+    type Target = IndexMap<TableKey, Value>;
+    fn deref(&self) -> &Self::Target {
+        &self.entries
+    }
+}
+impl<'a> IntoIterator for &'a Map {
+    type Item = (&'a TableKey, &'a Value);
+    type IntoIter = indexmap::map::Iter<'a, TableKey, Value>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.iter()
+    }
+}
+impl From<IndexMap<TableKey, Value>> for Map {
+    fn from(entries: IndexMap<TableKey, Value>) -> Self {
+        let mut map = Map {
+            entries,
+            next_index: 0,
+        };
+        map.next_index = map.after_highest_below(usize::MAX);
+        map
+    }
+}
 impl Default for Value {
     fn default() -> Self {
         Value::Nil
@@ -37,17 +126,53 @@ impl Value {
     pub fn get_truthy(self) -> bool {
         match self {
             Value::Number(num) => num != 0,
+            Value::Float(num) => num != 0.0,
             Value::Boolean(bool) => bool,
             Value::Nil => false,
-            Value::Table(_) => unimplemented!(),
+            Value::Table(_) => true,
         }
     }
     pub fn get_number(self) -> Option<i64> {
         match self {
             Value::Number(num) => Some(num),
+            Value::Float(_) => None,
             Value::Boolean(_) => None,
             Value::Nil => None,
-            Value::Table(_) => unimplemented!(),
+            Value::Table(_) => None,
+        }
+    }
+    pub fn type_name(self) -> &'static str {
+        match self {
+            Value::Number(_) => "number",
+            Value::Float(_) => "float",
+            Value::Boolean(_) => "boolean",
+            Value::Table(_) => "table",
+            Value::Nil => "nil",
+        }
+    }
+    pub fn equals(self, other: Value) -> bool {
+        self.scalar().equals(other.scalar())
+    }
+    pub fn scalar(self) -> Scalar {
+        match self {
+            Value::Number(num) => Scalar::Numeric(Numeric::Integer(num)),
+            Value::Float(num) => Scalar::Numeric(Numeric::Float(num)),
+            Value::Boolean(bool) => Scalar::Boolean(bool),
+            Value::Table(index) => Scalar::Table(index),
+            Value::Nil => Scalar::Nil,
+        }
+    }
+    pub fn get_numeric(self) -> Option<Numeric> {
+        match self {
+            Value::Number(num) => Some(Numeric::Integer(num)),
+            Value::Float(num) => Some(Numeric::Float(num)),
+            _ => None,
+        }
+    }
+    pub fn from_numeric(numeric: Numeric) -> Self {
+        match numeric {
+            Numeric::Integer(num) => Value::Number(num),
+            Numeric::Float(num) => Value::Float(num),
         }
     }
 }
\ No newline at end of file
@@ -1,13 +1,54 @@
-mod misc;
-mod bytecode;
+pub mod misc;
+pub mod bytecode;
+pub mod file;
+pub mod disassemble;
+pub mod verify;
+pub mod debugger;
+pub mod profiler;
+pub mod coroutine;
+pub mod function;
+pub mod compiler;
+pub mod limits;
+pub mod snapshot;
+pub mod transfer;
+pub mod reload;
+pub mod table_ops;
+pub mod compare;
+pub mod assemble;
+pub mod format;
+#[cfg(feature = "jit")]
+mod jit;
+#[cfg(all(test, feature = "jit"))]
+mod jit_tests;
+#[cfg(test)]
+mod file_tests;
+#[cfg(test)]
+mod coroutine_tests;
+#[cfg(test)]
+mod limits_tests;
+#[cfg(test)]
+mod table_ops_tests;
 
-use std::mem::size_of;
-use std::ops::Add;
+use std::cmp::Ordering;
+use std::ops::{Add, Range};
+use std::path::Path;
+use std::sync::Arc;
 use indexmap::IndexMap;
 use misc::Value;
+use crate::second_attempt::numeric::{ArithmeticOperator, Numeric};
+use crate::second_attempt::vm_error::{VmError, VmErrorKind};
+use crate::second_attempt::globals::Globals;
+use crate::second_attempt::stack::Stack;
+use crate::second_attempt::pretty::Layout;
 use crate::second_attempt::vm2::bytecode::Bytecode::{AllocTable, Constant, InsertIndexTable, PeekLocal, PopLocal, Print, PushLocal};
-use crate::second_attempt::vm2::bytecode::{CONSTANT, convert_back, convert_bytecode_array, convert_thing, PUSH_LOCAL};
-use crate::second_attempt::vm2::misc::{Table, TableKey};
+use crate::second_attempt::vm2::bytecode::{CONSTANT, convert_back, convert_bytecode_array, convert_thing, OPERAND_SIZE, PUSH_LOCAL};
+use crate::second_attempt::vm2::file::{BytecodeFile, BytecodeFileError};
+use crate::second_attempt::vm2::misc::{Map, Table, TableKey};
+use crate::second_attempt::vm2::verify::{verify, VerifyError};
+use crate::second_attempt::vm2::coroutine::{CoroutineFrame, ResumeKind};
+use crate::second_attempt::vm2::function::{CallFrame, NativeFunction};
+use crate::second_attempt::vm2::limits::{Limits, Usage};
+use crate::second_attempt::vm2::table_ops::Iterations;
 use crate::second_attempt::vm2::misc::Value::Number;
 
 pub fn test_vm() {
@@ -27,175 +68,471 @@ pub fn test_vm() {
         Number(21),
     ];
     let mut vm = Vm::default();
-    vm.load(Chunk {
-        ip: 0,
-        instructions,
-        constants
-    });
-    vm.run();
+    vm.load(Chunk::new(instructions, constants)).unwrap();
+    match vm.run() {
+        Ok(value) => println!("finished with {}", value),
+        Err(err) => println!("runtime error: {}", err),
+    }
     //let val = 2;
     //let val = convert_thing(val);
     //println!("{:#?}", convert_back(val.as_slice()));
 }
-struct Vm {
-    locals: Stack<256>,
-    eval: Stack<256>,
+// what call stacks and profiles call a chunk without debug info
+pub(super) const ANONYMOUS: &str = "<anonymous>";
+
+pub struct Vm {
+    locals: Stack<Value>,
+    eval: Stack<Value>,
     tables: Vec<Table>,
     chunks: Vec<Chunk>,
+    handlers: Vec<Handler>,
+    coroutines: Vec<CoroutineFrame>,
+    calls: Vec<CallFrame>,
+    globals: Globals<Value>,
+    // global ids by the handle of the string naming them, so a global instruction only hashes its name the first time
+    global_ids: Vec<Option<usize>>,
+    // the snapshots TABLE_NEXT loops are working through
+    iterations: Iterations,
+    limits: Limits,
+    usage: Usage,
+    #[cfg(feature = "jit")]
+    jit: bool,
+}
+// pushed by PUSH_HANDLER, everything the vm needs to go back to when something is thrown
+struct Handler {
+    chunk_depth: usize,
+    target: usize,
+    eval_len: usize,
+    locals_len: usize,
 }
-struct Chunk {
+// cloning a chunk is cheap, each call of a function gets its own ip over the same body
+#[derive(Clone)]
+pub struct Chunk {
     ip: usize,
+    body: Arc<ChunkBody>,
+    #[cfg(feature = "jit")]
+    jit: jit::JitProfile,
+}
+// changing a body that's shared copies it first, so the other chunks never see it change
+#[derive(Clone)]
+struct ChunkBody {
     instructions: Vec<u8>,
     constants: Vec<Value>,
+    debug: Option<DebugInfo>,
+}
+// optional information about where a chunk came from, never needed to run it
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DebugInfo {
+    pub name: String,
+    // (instruction pointer, source span) pairs sorted by instruction pointer
+    pub spans: Vec<(usize, Range<usize>)>,
+    pub locals: Vec<LocalInfo>,
+}
+// names a local while the instruction pointer is inside `live`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LocalInfo {
+    pub name: String,
+    // position on the locals stack counting up from the first local this chunk pushed
+    pub slot: usize,
+    pub live: Range<usize>,
+}
+impl DebugInfo {
+    // the span of the instruction at `ip`, an instruction without its own span belongs to the one before it
+    pub fn span_at(&self, ip: usize) -> Option<Range<usize>> {
+        let index = self.spans.partition_point(|(span_ip, _)| *span_ip <= ip);
+        Some(self.spans.get(index.checked_sub(1)?)?.1.clone())
+    }
+    pub fn local_name(&self, slot: usize, ip: usize) -> Option<&str> {
+        self.locals.iter()
+            .find(|local| local.slot == slot && local.live.contains(&ip))
+            .map(|local| local.name.as_str())
+    }
+}
+// called before every instruction, `run` passes `()` so none of this costs anything without a debugger attached
+pub trait Hook {
+    // compiled code skips `before_step`, so only hooks that don't need to see every instruction allow it
+    #[cfg(feature = "jit")]
+    const ALLOWS_JIT: bool = false;
+    fn before_step(&mut self, vm: &Vm) -> Result<(), VmErrorKind>;
+}
+impl Hook for () {
+    #[cfg(feature = "jit")]
+    const ALLOWS_JIT: bool = true;
+    #[inline(always)]
+    fn before_step(&mut self, _vm: &Vm) -> Result<(), VmErrorKind> {
+        Ok(())
+    }
 }
 impl Chunk {
+    pub fn new(instructions: Vec<u8>, constants: Vec<Value>) -> Self {
+        Self {
+            ip: 0,
+            body: Arc::new(ChunkBody {
+                instructions,
+                constants,
+                debug: None,
+            }),
+            #[cfg(feature = "jit")]
+            jit: Default::default(),
+        }
+    }
+    pub fn with_debug(mut self, debug: DebugInfo) -> Self {
+        Arc::make_mut(&mut self.body).debug = Some(debug);
+        self
+    }
+    pub fn get_debug(&self) -> Option<&DebugInfo> {
+        self.body.debug.as_ref()
+    }
     pub fn set_ip(&mut self, ip: usize) {
         self.ip = ip
     }
     pub fn get_ip(&self) -> usize {
         self.ip
     }
+    // changing a chunk throws away any code compiled for it
     pub fn get_instructions_mut(&mut self) -> &mut Vec<u8> {
-        &mut self.instructions
+        #[cfg(feature = "jit")]
+        {
+            self.jit = Default::default();
+        }
+        &mut Arc::make_mut(&mut self.body).instructions
     }
     pub fn get_constants_mut(&mut self) -> &mut Vec<Value> {
-        &mut self.constants
+        #[cfg(feature = "jit")]
+        {
+            self.jit = Default::default();
+        }
+        &mut Arc::make_mut(&mut self.body).constants
     }
     pub fn get_instructions(&self) -> &Vec<u8> {
-        &self.instructions
+        &self.body.instructions
     }
     pub fn get_constants(&self) -> &Vec<Value> {
-        &self.constants
+        &self.body.constants
     }
 }
 impl Vm {
-    pub fn load(&mut self, chunk: Chunk) {
+    // every chunk is verified before it's pushed, the run loop relies on that
+    pub fn load(&mut self, chunk: Chunk) -> Result<(), VerifyError> {
+        verify(&chunk)?;
         self.chunks.push(chunk);
+        Ok(())
     }
-    pub fn run(&mut self) {
-        while self.get_ip() < self.get_instructions().len() {
-            self.set_ip(self.get_ip()+1);
-            //println!("Instructions: {}", self.get_instruction());
-            match self.get_instruction() {
-                bytecode::CONSTANT => {
-                    let constant = self.get_constant();
-                    self.move_index();
-                    self.eval.push(constant);
-                }
-                bytecode::PRINT => {
-                    let val = self.eval.pop().unwrap();
-                    println!("{}", val);
-                }
-                bytecode::PUSH_LOCAL => {
-                    let value = self.eval.pop().unwrap();
-                    self.locals.push(value).unwrap();
-                }
-                bytecode::POP_LOCAL => {
-                    let val = self.locals.pop().unwrap();
-                    self.eval.push(val).unwrap();
-                }
-                bytecode::PEEK_LOCAL => {
-                    let index = self.get_index();
-                    self.move_index();
-                    let val = self.locals.peek(index).unwrap();
-                    self.eval.push(val);
-                }
-                bytecode::ALLOC_TABLE => {
-                    self.tables.push(Table::Map(IndexMap::default()));
-                    self.eval.push(Value::Number((self.tables.len()-1) as i64)).unwrap();
+    // loads a compiled `.tblc` file and runs its entry chunk
+    pub fn load_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), BytecodeFileError> {
+        self.load_bytecode(BytecodeFile::read_from(path)?)
+    }
+    // runs until every chunk has returned, the result is whatever is left on top of the eval stack
+    pub fn run(&mut self) -> Result<Value, VmError> {
+        self.run_with(&mut ())
+    }
+    pub fn run_with<H: Hook>(&mut self, hook: &mut H) -> Result<Value, VmError> {
+        self.start_clock();
+        self.run_until(0, hook)
+    }
+    // runs until the chunk stack is back down to `depth` chunks
+    fn run_until<H: Hook>(&mut self, depth: usize, hook: &mut H) -> Result<Value, VmError> {
+        while self.chunks.len() > depth {
+            let chunk = self.chunks.last().unwrap();
+            // falling off the end of a chunk is the same as returning from it
+            if chunk.get_ip() >= chunk.get_instructions().len() {
+                let (chunk_index, ip) = (self.chunks.len() - 1, chunk.get_ip());
+                self.pop_chunk().map_err(|kind| kind.at(chunk_index, ip))?;
+                continue;
+            }
+            #[cfg(feature = "jit")]
+            if H::ALLOWS_JIT && self.jit && !self.usage.limited && self.enter_jit() {
+                continue;
+            }
+            let (chunk_index, ip) = (self.chunks.len() - 1, self.get_ip());
+            hook.before_step(self).map_err(|kind| kind.at(chunk_index, ip))?;
+            if self.usage.limited {
+                self.check_limits().map_err(|kind| kind.at(chunk_index, ip))?;
+            }
+            if let Err(kind) = self.step() {
+                // a script can't catch running out of a limit
+                if self.handlers.is_empty() || matches!(kind, VmErrorKind::LimitExceeded(_)) {
+                    return Err(kind.at(chunk_index, ip));
                 }
-                bytecode::INSERT_INDEX_TABLE => {
-                    let index =
-                    match self.eval.pop().unwrap() {
-                        Number(number) => number as usize,
-                        _ => panic!("not a number"),
-                    };
-                    let value_index =
-                    match self.eval.pop().unwrap() {
-                        Number(number) => number as usize,
-                        _ => panic!("not a number"),
-                    };
-                    let value = self.eval.pop().unwrap();
-                    let table = match self.tables.get_mut(index).unwrap() {
-                        Table::Map(map) => map,
-                        _ => panic!("not a table"),
-                    };
-                    table.insert(TableKey::NoIdentifier(value_index), value);
+                // runtime errors can be caught too, the handler gets the message as a string
+                let message = self.alloc_string(kind.to_string()).map_err(|kind| kind.at(chunk_index, ip))?;
+                self.throw(message).map_err(|kind| kind.at(chunk_index, ip))?;
+            }
+        }
+        Ok(self.eval.pop().unwrap_or_default())
+    }
+    fn step(&mut self) -> Result<(), VmErrorKind> {
+        self.set_ip(self.get_ip()+1);
+        //println!("Instructions: {}", self.get_instruction());
+        match self.get_instruction() {
+            bytecode::CONSTANT => {
+                let constant = self.get_constant();
+                self.push(constant)?;
+            }
+            bytecode::PRINT => {
+                match self.pop()? {
+                    val @ Value::Table(_) => println!("{}", self.format_value(val, Layout::Compact)),
+                    val => println!("{}", val),
                 }
-                bytecode::GET_INDEX_TABLE => {
-                    let index =
-                    match self.eval.pop().unwrap() {
-                      Number(number) => number as usize,
-                        _ => panic!("not a number")
-                    };
-                    let value_index =
-                    match self.eval.pop().unwrap() {
-                        Number(number) => number as usize,
-                        _ => panic!("not a number"),
-                    };
-                    let table = match self.tables.get_mut(index).unwrap() {
-                        Table::Map(map) => map,
-                        _ => panic!("not a table"),
-                    };
-                    let value = table.get(&TableKey::NoIdentifier(value_index)).unwrap();
-                    self.eval.push(*value);
+            }
+            bytecode::PUSH_LOCAL => {
+                let value = self.pop()?;
+                self.locals.push(value).ok_or_else(|| self.stack_overflow())?;
+            }
+            bytecode::POP_LOCAL => {
+                let val = self.locals.pop().ok_or(VmErrorKind::StackUnderflow)?;
+                self.push(val)?;
+            }
+            bytecode::GET_LOCAL | bytecode::PEEK_LOCAL => {
+                let index = self.get_index();
+                self.move_index();
+                let val = self.locals.peek(index).ok_or(VmErrorKind::StackUnderflow)?;
+                self.push(val)?;
+            }
+            bytecode::SET_LOCAL => {
+                let index = self.get_index();
+                self.move_index();
+                let val = self.pop()?;
+                self.locals.set(index, val).ok_or(VmErrorKind::StackUnderflow)?;
+            }
+            bytecode::JUMP => {
+                let target = self.get_index();
+                #[cfg(feature = "jit")]
+                if target < self.get_ip() {
+                    self.get_chunk().jit.tick();
                 }
-                bytecode::INJECT => {
-                    let table_index = match self.eval.pop().unwrap() {
-                        Number(number) => number as usize,
-                        _ => panic!("not a number"),
-                    };
-                    let table = self.tables.get(table_index).unwrap();
-                    match table {
-                        Table::Map(map) => {
-                            let map: &IndexMap<TableKey, Value> = map;
-                            let constants_map = map.get(&TableKey::Identifier(String::from("constants"), None)).unwrap();
-                            let instructions_map = map.get(&TableKey::Identifier(String::from("instructions"), None)).unwrap();
-                            let constants_map:&misc::Table = match constants_map {
-                                Value::Table(index) => {
-                                    self.tables.get(*index).unwrap()
-                                }
-                                _ => panic!()
-                            };
-                            let instructions_map: &misc::Table = match instructions_map {
-                                Value::Table(index) => {
-                                    self.tables.get(*index).unwrap()
-                                }
-                                _ => panic!()
-                            };
-                            let constants_map: &IndexMap<TableKey, Value> = match constants_map {
-                                Table::Map(map) => map,
-                                _ => panic!()
-                            };
-                            let instructions_map: &IndexMap<TableKey, Value> = match instructions_map {
-                                Table::Map(map) => map,
-                                _ => panic!()
-                            };
-                            let new_constants = constants_map.values().map(|value| {
-                                *value
-                            }).collect();
-                            let new_instructions = instructions_map.values().map(|value| {
-                                match value {
-                                    Number(number) => *number as u8,
-                                    _ => panic!(),
-                                }
-                            }).collect();
-                            let new_chunk = Chunk {
-                                ip: 0,
-                                instructions: new_instructions,
-                                constants: new_constants
-                            };
-                            self.chunks.push(new_chunk);
-                        }
-                        _ => panic!(),
+                self.set_ip(target);
+            }
+            bytecode::JUMP_IF => {
+                let target = self.get_index();
+                self.move_index();
+                if self.pop()?.get_truthy() {
+                    #[cfg(feature = "jit")]
+                    if target < self.get_ip() {
+                        self.get_chunk().jit.tick();
                     }
+                    self.set_ip(target);
                 }
-                bytecode::RETURN => {
-                    self.chunks.pop().unwrap();
+            }
+            bytecode::TEST_TRUTHY => {
+                let val = self.pop()?.get_truthy();
+                self.push(Value::Boolean(val))?;
+            }
+            bytecode::TEST_NOT => {
+                let val = self.pop()?.get_truthy();
+                self.push(Value::Boolean(!val))?;
+            }
+            bytecode::TEST_EQUAL => {
+                let rhs = self.pop()?;
+                let lhs = self.pop()?;
+                let equal = self.values_equal(lhs, rhs);
+                self.push(Value::Boolean(equal))?;
+            }
+            bytecode::TEST_LESS => self.comparison(|ordering| ordering == Ordering::Less)?,
+            bytecode::TEST_LESS_EQUAL => self.comparison(|ordering| ordering != Ordering::Greater)?,
+            bytecode::TEST_GREATER => self.comparison(|ordering| ordering == Ordering::Greater)?,
+            bytecode::TEST_GREATER_EQUAL => self.comparison(|ordering| ordering != Ordering::Less)?,
+            bytecode::ALLOC_TABLE => {
+                let table = self.push_table(Table::Map(Map::default()))?;
+                self.push(table)?;
+            }
+            bytecode::INSERT_INDEX_TABLE => {
+                let index = self.pop_table_handle()?;
+                let value_index = self.pop_position()?;
+                let value = self.pop()?;
+                self.insert(index, TableKey::NoIdentifier(value_index), value)?;
+            }
+            bytecode::INSERT_STR_TABLE => {
+                let index = self.pop_table_handle()?;
+                let key = self.pop_string()?;
+                let value = self.pop()?;
+                self.insert(index, TableKey::Identifier(key, None), value)?;
+            }
+            bytecode::GET_INDEX_TABLE => {
+                let index = self.pop_table_handle()?;
+                let value_index = self.pop_position()?;
+                // missing entries read as nil
+                let value = self.get_map_mut(index)?.get(&TableKey::NoIdentifier(value_index)).copied().unwrap_or_default();
+                self.push(value)?;
+            }
+            bytecode::GET_STR_TABLE => {
+                let index = self.pop_table_handle()?;
+                let key = self.pop_string()?;
+                let value = self.get_map_mut(index)?.get(&TableKey::Identifier(key, None)).copied().unwrap_or_default();
+                self.push(value)?;
+            }
+            bytecode::INJECT => {
+                self.move_index();
+                let table_index = self.pop_table_handle()?;
+                let new_chunk = self.table_to_chunk(table_index)?;
+                self.load(new_chunk).map_err(|err| VmErrorKind::InvalidChunk(err.to_string()))?;
+            }
+            bytecode::ADD => self.arithmetic(ArithmeticOperator::Add)?,
+            bytecode::SUBTRACT => self.arithmetic(ArithmeticOperator::Subtract)?,
+            bytecode::MULTIPLY => self.arithmetic(ArithmeticOperator::Multiply)?,
+            bytecode::DIVIDE => self.arithmetic(ArithmeticOperator::Divide)?,
+            bytecode::RETURN => self.pop_chunk()?,
+            bytecode::PUSH_HANDLER => {
+                let target = self.get_index();
+                self.move_index();
+                self.handlers.push(Handler {
+                    chunk_depth: self.chunks.len(),
+                    target,
+                    eval_len: self.eval.len(),
+                    locals_len: self.locals.len(),
+                });
+            }
+            bytecode::POP_HANDLER => {
+                self.handlers.pop();
+            }
+            bytecode::THROW => {
+                let value = self.pop()?;
+                self.throw(value)?;
+            }
+            bytecode::NEW_COROUTINE => {
+                let table_index = self.pop_table_handle()?;
+                let coroutine = self.coroutine_from(table_index)?;
+                self.push(coroutine)?;
+            }
+            bytecode::RESUME => {
+                let sent = self.pop()?;
+                let handle = self.pop_table_handle()?;
+                self.resume(handle, sent, ResumeKind::Value)?;
+            }
+            bytecode::YIELD => {
+                let value = self.pop()?;
+                self.yield_value(value)?;
+            }
+            bytecode::POP => {
+                self.pop()?;
+            }
+            bytecode::CALL => {
+                let argument_count = self.get_index();
+                self.move_index();
+                self.call(argument_count)?;
+            }
+            bytecode::GET_GLOBAL => {
+                let id = self.global_id()?;
+                let value = self.globals.get(id)?;
+                self.push(value)?;
+            }
+            bytecode::SET_GLOBAL => {
+                let id = self.global_id()?;
+                let value = self.pop()?;
+                self.globals.set(id, value)?;
+            }
+            bytecode::DEFINE_GLOBAL => {
+                let id = self.global_id()?;
+                let value = self.pop()?;
+                self.globals.define(id, value)?;
+            }
+            bytecode::TABLE_LEN => self.table_len()?,
+            bytecode::TABLE_APPEND => self.table_append()?,
+            bytecode::TABLE_REMOVE => self.table_remove()?,
+            bytecode::TABLE_NEXT => {
+                let target = self.get_index();
+                self.move_index();
+                if self.table_next()? {
+                    self.set_ip(target);
                 }
-                _ => {}
             }
+            bytecode::FOR_NEXT => {
+                let target = self.get_index();
+                self.move_index();
+                let handle = Self::table_handle(self.eval.peek(0).ok_or(VmErrorKind::StackUnderflow)?)?;
+                self.resume(handle, Value::Nil, ResumeKind::ForLoop(target))?;
+            }
+            opcode => return Err(VmErrorKind::InvalidOpcode(opcode)),
+        }
+        Ok(())
+    }
+    // a handler only lives as long as the chunk that pushed it
+    fn pop_chunk(&mut self) -> Result<(), VmErrorKind> {
+        self.chunks.pop();
+        while self.handlers.last().map_or(false, |handler| handler.chunk_depth > self.chunks.len()) {
+            self.handlers.pop();
         }
+        self.finish_call()?;
+        self.finish_coroutine()
+    }
+    // unwinds to the innermost handler and jumps into it with the thrown value on the eval stack
+    fn throw(&mut self, value: Value) -> Result<(), VmErrorKind> {
+        let Some(handler) = self.handlers.pop() else {
+            return Err(VmErrorKind::Uncaught(self.describe(value)));
+        };
+        self.chunks.truncate(handler.chunk_depth);
+        self.unwind_calls();
+        self.unwind_coroutines();
+        self.eval.truncate(handler.eval_len);
+        self.locals.truncate(handler.locals_len);
+        self.set_ip(handler.target);
+        self.push(value)
+    }
+    // every table goes through here so its memory is counted and the table limits are checked
+    pub(crate) fn push_table(&mut self, table: Table) -> Result<Value, VmErrorKind> {
+        self.track_table(&table)?;
+        self.tables.push(table);
+        Ok(Value::Table(self.tables.len() - 1))
+    }
+    // all of `tables` or, if one of them goes over a limit, none of them
+    pub(crate) fn push_tables(&mut self, tables: impl IntoIterator<Item = Table>) -> Result<(), VmErrorKind> {
+        let (len, table_memory) = (self.tables.len(), self.usage.table_memory);
+        for table in tables {
+            if let Err(kind) = self.push_table(table) {
+                self.tables.truncate(len);
+                self.usage.table_memory = table_memory;
+                return Err(kind);
+            }
+        }
+        Ok(())
+    }
+    fn insert(&mut self, index: usize, key: TableKey, value: Value) -> Result<(), VmErrorKind> {
+        self.track_entry(&key)?;
+        self.get_map_mut(index)?.insert(key, value);
+        Ok(())
+    }
+    pub fn alloc_table(&mut self, entries: IndexMap<TableKey, Value>) -> Result<Value, VmErrorKind> {
+        self.push_table(Table::Map(entries.into()))
+    }
+    pub fn alloc_string(&mut self, string: String) -> Result<Value, VmErrorKind> {
+        self.push_table(Table::String(string))
+    }
+    // strings show as themselves, tables written out the way PRINT does, anything else as a literal
+    pub fn describe(&self, value: Value) -> String {
+        self.format_value(value, Layout::Compact)
+    }
+    // builds a chunk out of a table with `constants` and `instructions` entries
+    pub fn table_to_chunk(&self, table_index: usize) -> Result<Chunk, VmErrorKind> {
+        let map = self.get_map(table_index)?;
+        let constants_map = map.get(&TableKey::Identifier(String::from("constants"), None))
+            .ok_or_else(|| VmErrorKind::InvalidChunk("missing `constants`".to_string()))?;
+        let instructions_map = map.get(&TableKey::Identifier(String::from("instructions"), None))
+            .ok_or_else(|| VmErrorKind::InvalidChunk("missing `instructions`".to_string()))?;
+        let constants_map = self.get_map(Self::table_handle(*constants_map)?)?;
+        let instructions_map = self.get_map(Self::table_handle(*instructions_map)?)?;
+        let new_constants = constants_map.values().copied().collect();
+        let new_instructions = instructions_map.values().map(|value| {
+            match value {
+                Number(number) if (0..=u8::MAX as i64).contains(number) => Ok(*number as u8),
+                _ => Err(VmErrorKind::InvalidChunk(format!("instruction byte {:?} isn't a number from 0 to 255", value))),
+            }
+        }).collect::<Result<_, _>>()?;
+        Ok(Chunk::new(new_instructions, new_constants))
+    }
+    // the other way around, the instructions become a table of byte numbers that `table_to_chunk` reads back
+    pub fn chunk_to_table(&mut self, chunk: &Chunk) -> Result<Value, VmErrorKind> {
+        let constants = chunk.get_constants().iter().enumerate()
+            .map(|(index, constant)| (TableKey::NoIdentifier(index), *constant))
+            .collect();
+        let constants = self.alloc_table(constants)?;
+        let instructions = chunk.get_instructions().iter().enumerate()
+            .map(|(index, byte)| (TableKey::NoIdentifier(index), Number(*byte as i64)))
+            .collect();
+        let instructions = self.alloc_table(instructions)?;
+        let mut entries = IndexMap::new();
+        entries.insert(TableKey::Identifier(String::from("constants"), None), constants);
+        entries.insert(TableKey::Identifier(String::from("instructions"), None), instructions);
+        self.alloc_table(entries)
     }
     pub fn get_ip(&self) -> usize {
         self.get_chunk().get_ip()
@@ -203,6 +540,151 @@ impl Vm {
     pub fn set_ip(&mut self, ip: usize) {
         self.get_chunk_mut().set_ip(ip);
     }
+    fn push(&mut self, value: Value) -> Result<(), VmErrorKind> {
+        self.eval.push(value).ok_or_else(|| self.stack_overflow())
+    }
+    fn pop(&mut self) -> Result<Value, VmErrorKind> {
+        self.eval.pop().ok_or(VmErrorKind::StackUnderflow)
+    }
+    fn pop_numeric(&mut self) -> Result<Numeric, VmErrorKind> {
+        let value = self.pop()?;
+        value.get_numeric().ok_or(VmErrorKind::TypeError { expected: "number", found: value.type_name() })
+    }
+    fn pop_position(&mut self) -> Result<usize, VmErrorKind> {
+        match self.pop()? {
+            Number(number) if number >= 0 => Ok(number as usize),
+            value => Err(VmErrorKind::TypeError { expected: "positive number", found: value.type_name() }),
+        }
+    }
+    pub fn table_handle(value: Value) -> Result<usize, VmErrorKind> {
+        match value {
+            Value::Table(index) => Ok(index),
+            value => Err(VmErrorKind::TypeError { expected: "table", found: value.type_name() }),
+        }
+    }
+    fn pop_table_handle(&mut self) -> Result<usize, VmErrorKind> {
+        let index = Self::table_handle(self.pop()?)?;
+        if index >= self.tables.len() {
+            return Err(VmErrorKind::BadTableHandle(index));
+        }
+        Ok(index)
+    }
+    // string keys are handles to `Table::String`s
+    fn pop_string(&mut self) -> Result<String, VmErrorKind> {
+        let value = self.pop()?;
+        self.string(value).map(|string| string.to_string())
+    }
+    pub fn string(&self, value: Value) -> Result<&str, VmErrorKind> {
+        let index = Self::table_handle(value)?;
+        match self.tables.get(index) {
+            Some(Table::String(string)) => Ok(string.as_str()),
+            Some(table) => Err(VmErrorKind::TypeError { expected: "string", found: table.type_name() }),
+            None => Err(VmErrorKind::BadTableHandle(index)),
+        }
+    }
+    // interns the name the operand points at, reading a global that was never defined fails in `Globals::get`
+    fn global_id(&mut self) -> Result<usize, VmErrorKind> {
+        let name = self.get_constant();
+        let handle = Self::table_handle(name)?;
+        if let Some(Some(id)) = self.global_ids.get(handle) {
+            return Ok(*id);
+        }
+        let id = match self.tables.get(handle) {
+            Some(Table::String(string)) => self.globals.intern(string.as_str()),
+            _ => return Err(self.string(name).unwrap_err()),
+        };
+        if self.global_ids.len() <= handle {
+            self.global_ids.resize(handle + 1, None);
+        }
+        self.global_ids[handle] = Some(id);
+        Ok(id)
+    }
+    pub fn get_globals(&self) -> &Globals<Value> {
+        &self.globals
+    }
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        self.globals.get_by_name(name)
+    }
+    // defines the global if it doesn't exist yet
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        self.globals.set_by_name(name, value);
+    }
+    // a native with the same name already in the global, say one restored from a snapshot, gets the new function in place
+    pub fn define_native(&mut self, name: &str, function: NativeFunction) {
+        if let Some(Value::Table(handle)) = self.globals.get_by_name(name) {
+            if let Some(Table::Native(native)) = self.tables.get_mut(handle) {
+                if native.name == name {
+                    native.function = function;
+                    return;
+                }
+            }
+        }
+        let native = self.new_native(name, function);
+        self.set_global(name, native);
+    }
+    // drops whatever was running, say after an error, tables and globals stay
+    pub fn reset(&mut self) {
+        self.chunks.clear();
+        self.eval.clear();
+        self.locals.clear();
+        self.handlers.clear();
+        self.calls.clear();
+        self.unwind_coroutines();
+    }
+    fn get_map(&self, index: usize) -> Result<&Map, VmErrorKind> {
+        match self.tables.get(index) {
+            Some(Table::Map(map)) => Ok(map),
+            Some(table) => Err(VmErrorKind::TypeError { expected: "table", found: table.type_name() }),
+            None => Err(VmErrorKind::BadTableHandle(index)),
+        }
+    }
+    fn get_map_mut(&mut self, index: usize) -> Result<&mut Map, VmErrorKind> {
+        match self.tables.get_mut(index) {
+            Some(Table::Map(map)) => Ok(map),
+            Some(table) => Err(VmErrorKind::TypeError { expected: "table", found: table.type_name() }),
+            None => Err(VmErrorKind::BadTableHandle(index)),
+        }
+    }
+    fn arithmetic(&mut self, operator: ArithmeticOperator) -> Result<(), VmErrorKind> {
+        let rhs = self.pop_numeric()?;
+        let lhs = self.pop_numeric()?;
+        let result = lhs.operate(rhs, operator)?;
+        self.push(Value::from_numeric(result))
+    }
+    // NaN is unordered, so every ordering test against it is false
+    fn comparison(&mut self, test: fn(Ordering) -> bool) -> Result<(), VmErrorKind> {
+        let rhs = self.pop()?;
+        let lhs = self.pop()?;
+        let result = self.compare_values(lhs, rhs)?.map_or(false, test);
+        self.push(Value::Boolean(result))
+    }
+    pub fn get_locals(&self) -> &Stack<Value> {
+        &self.locals
+    }
+    pub fn get_eval(&self) -> &Stack<Value> {
+        &self.eval
+    }
+    // how many values the locals and the eval stack can each hold before a stack overflow
+    pub fn set_max_stack(&mut self, max: usize) {
+        self.locals.set_max(max);
+        self.eval.set_max(max);
+    }
+    pub fn get_max_stack(&self) -> usize {
+        self.eval.get_max()
+    }
+    // names the function every chunk on the chunk stack belongs to, innermost first
+    pub(super) fn stack_overflow(&self) -> VmErrorKind {
+        let call_stack = self.chunks.iter().rev()
+            .map(|chunk| chunk.get_debug().map_or(ANONYMOUS, |debug| debug.name.as_str()).to_string())
+            .collect();
+        VmErrorKind::StackOverflow(call_stack)
+    }
+    pub fn get_tables(&self) -> &Vec<Table> {
+        &self.tables
+    }
+    pub fn get_chunks(&self) -> &Vec<Chunk> {
+        &self.chunks
+    }
     pub fn get_chunk(&self) -> &Chunk {
         self.chunks.last().unwrap()
     }
@@ -221,24 +703,21 @@ impl Vm {
     pub fn get_constants_mut(&mut self) -> &mut Vec<Value> {
         self.get_chunk_mut().get_constants_mut()
     }
+    // operands and constant indices are checked by the verifier when the chunk is loaded
     pub fn get_index(&self) -> usize {
-        let index = convert_back(&self.get_instructions().as_slice()[self.get_ip()..self.get_ip()+size_of::<usize>()]);
+        let index = convert_back(&self.get_instructions().as_slice()[self.get_ip()..self.get_ip()+OPERAND_SIZE]);
         index
     }
     pub fn move_index(&mut self) {
-        self.set_ip(self.get_ip() + size_of::<usize>())
-    }
-    pub fn get_table_mut(&mut self) -> &mut Table {
-        let index = self.get_index();
-        self.tables.get_mut(index).unwrap()
+        self.set_ip(self.get_ip() + OPERAND_SIZE)
     }
     pub fn get_constant(&mut self) -> Value {
-        let constant = *self.get_constants().get(self.get_index()).unwrap();
+        let constant = self.get_constants()[self.get_index()];
         self.move_index();
         constant
     }
     pub fn get_instruction(&self) -> u8 {
-        *self.get_instructions().get(self.get_ip()-1).unwrap()
+        self.get_instructions()[self.get_ip()-1]
     }
 }
 impl Default for Vm {
@@ -247,54 +726,17 @@ impl Default for Vm {
             locals: Stack::default(),
             eval: Stack::default(),
             tables: vec![],
-            chunks: vec![]
+            chunks: vec![],
+            handlers: vec![],
+            coroutines: vec![],
+            calls: vec![],
+            globals: Globals::default(),
+            global_ids: vec![],
+            iterations: Iterations::default(),
+            limits: Limits::default(),
+            usage: Usage::default(),
+            #[cfg(feature = "jit")]
+            jit: true,
         }
     }
 }
-
-pub struct Stack<const N: usize> {
-    stack: [Value; N],
-    len: usize,
-}
-impl<const N: usize> Stack<N> {
-    pub fn pop(&mut self) -> Option<Value> {
-        if self.len == 0 {
-            return None;
-        }
-        let val = self.stack[self.len-1];
-        self.len -= 1;
-        Some(val)
-    }
-    pub fn push(&mut self, value: Value) -> Option<()> {
-        if self.len == N {
-            return None;
-        }
-        self.stack[self.len] = value;
-        self.len += 1;
-        Some(())
-    }
-    pub fn peek(&mut self, index: usize) -> Option<Value> {
-        Some(self.stack[self.compute_distance(index)?])
-    }
-    pub fn set(&mut self, index: usize, value: Value) -> Option<()> {
-        self.stack[self.compute_distance(index)?] = value;
-        Some(())
-    }
-    pub fn clear(&mut self) {
-        self.len = 0;
-    }
-    fn compute_distance(&self, index: usize) -> Option<usize> {
-        if self.len - index < 0 {
-            return None;
-        }
-        Some(self.len-index)
-    }
-}
-impl<const N: usize> Default for Stack<N> {
-    fn default() -> Self {
-        Self {
-            stack: [Default::default(); N],
-            len: 0
-        }
-    }
-}
\ No newline at end of file
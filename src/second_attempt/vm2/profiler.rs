@@ -0,0 +1,151 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use crate::second_attempt::vm2::{Hook, Vm, ANONYMOUS};
+use crate::second_attempt::vm2::bytecode::{opcode_info, ALLOC_TABLE};
+use crate::second_attempt::vm_error::VmErrorKind;
+
+/*
+A profiler is a `Hook`, attach it with `vm.run_with(&mut profiler)` and call `finish` once the run is over.
+
+Every chunk that runs is a frame, a chunk injected by another one is its callee. Frames are named
+after the chunk's debug info, chunks without it are all `<anonymous>`. The clock is only read when
+the chunk stack changes so counting opcodes is all the per instruction work there is.
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FunctionStats {
+    pub calls: u64,
+    // time spent in the chunk itself, not in chunks it injected
+    pub self_time: Duration,
+    pub total_time: Duration,
+    pub tables_allocated: u64,
+}
+struct Frame {
+    depth: usize,
+    name: String,
+    // the names of every frame from the root down to this one separated by `;`
+    stack: String,
+    start: Instant,
+    children: Duration,
+    tables_allocated: u64,
+}
+pub struct Profiler {
+    opcode_counts: [u64; 256],
+    frames: Vec<Frame>,
+    functions: HashMap<String, FunctionStats>,
+    stacks: BTreeMap<String, Duration>,
+    tables_allocated: u64,
+}
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    // ends every frame that's still running, call it when the vm stops even if it stopped with an error
+    pub fn finish(&mut self) {
+        let now = Instant::now();
+        while !self.frames.is_empty() {
+            self.end_frame(now);
+        }
+    }
+    pub fn opcode_count(&self, opcode: u8) -> u64 {
+        self.opcode_counts[opcode as usize]
+    }
+    pub fn get_functions(&self) -> &HashMap<String, FunctionStats> {
+        &self.functions
+    }
+    pub fn get_tables_allocated(&self) -> u64 {
+        self.tables_allocated
+    }
+    fn end_frame(&mut self, now: Instant) {
+        let frame = self.frames.pop().unwrap();
+        let total = now - frame.start;
+        let self_time = total.saturating_sub(frame.children);
+        if let Some(parent) = self.frames.last_mut() {
+            parent.children += total;
+        }
+        let stats = self.functions.entry(frame.name).or_default();
+        stats.calls += 1;
+        stats.self_time += self_time;
+        stats.total_time += total;
+        stats.tables_allocated += frame.tables_allocated;
+        *self.stacks.entry(frame.stack).or_default() += self_time;
+    }
+    fn enter(&mut self, vm: &Vm, depth: usize, now: Instant) {
+        let name = vm.get_chunk().get_debug().map_or(ANONYMOUS, |debug| debug.name.as_str()).to_string();
+        let stack = match self.frames.last() {
+            Some(parent) => format!("{};{}", parent.stack, name),
+            None => name.clone(),
+        };
+        self.frames.push(Frame {
+            depth,
+            name,
+            stack,
+            start: now,
+            children: Duration::ZERO,
+            tables_allocated: 0,
+        });
+    }
+    // opcodes by how often they ran, then functions by self time
+    pub fn report(&self) -> String {
+        let mut buffer = String::new();
+        let mut opcodes = (0..=u8::MAX).filter(|opcode| self.opcode_counts[*opcode as usize] != 0).collect::<Vec<_>>();
+        opcodes.sort_by_key(|opcode| std::cmp::Reverse(self.opcode_counts[*opcode as usize]));
+        buffer.push_str("opcode               count\n");
+        for opcode in opcodes {
+            let name = opcode_info(opcode).map_or_else(|| format!("{:#04x}", opcode), |(name, _)| name.to_string());
+            buffer.push_str(format!("{:<16}{:>10}\n", name, self.opcode_counts[opcode as usize]).as_str());
+        }
+        let mut functions = self.functions.iter().collect::<Vec<_>>();
+        functions.sort_by(|(_, lhs), (_, rhs)| rhs.self_time.cmp(&lhs.self_time));
+        buffer.push_str("\nchunk                 calls        self       total  tables\n");
+        for (name, stats) in functions {
+            buffer.push_str(format!("{:<16}{:>10}  {:>10.2?}  {:>10.2?}  {:>6}\n",
+                                    name, stats.calls, stats.self_time, stats.total_time, stats.tables_allocated).as_str());
+        }
+        buffer.push_str(format!("\ntables allocated: {}\n", self.tables_allocated).as_str());
+        buffer
+    }
+    // one `root;callee;... self_time_in_microseconds` line per stack, which is what flamegraph.pl and inferno read
+    pub fn collapsed_stacks(&self) -> String {
+        self.stacks.iter()
+            .map(|(stack, time)| format!("{} {}\n", stack, time.as_micros()))
+            .collect()
+    }
+    pub fn write_collapsed_stacks<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        std::fs::write(path, self.collapsed_stacks())
+    }
+}
+impl Default for Profiler {
+    fn default() -> Self {
+        Self {
+            opcode_counts: [0; 256],
+            frames: vec![],
+            functions: HashMap::new(),
+            stacks: BTreeMap::new(),
+            tables_allocated: 0,
+        }
+    }
+}
+impl Hook for Profiler {
+    fn before_step(&mut self, vm: &Vm) -> Result<(), VmErrorKind> {
+        let depth = vm.get_chunks().len();
+        if self.frames.last().map_or(true, |frame| frame.depth != depth) {
+            let now = Instant::now();
+            while self.frames.last().map_or(false, |frame| frame.depth > depth) {
+                self.end_frame(now);
+            }
+            // chunks loaded from a file run one after another at shrinking depths, so this can be a new root
+            if self.frames.last().map_or(true, |frame| frame.depth < depth) {
+                self.enter(vm, depth, now);
+            }
+        }
+        let chunk = vm.get_chunk();
+        let opcode = chunk.get_instructions()[chunk.get_ip()];
+        self.opcode_counts[opcode as usize] += 1;
+        if opcode == ALLOC_TABLE {
+            self.tables_allocated += 1;
+            self.frames.last_mut().unwrap().tables_allocated += 1;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,121 @@
+use crate::second_attempt::ir::File;
+use crate::second_attempt::vm2::{bytecode, Chunk, Vm};
+use crate::second_attempt::vm2::bytecode::decode;
+use crate::second_attempt::vm2::compiler::{compile, CompileError};
+use crate::second_attempt::vm2::misc::{Table, Value};
+
+/*
+Hot reloading. `Vm::reload` compiles a new version of a module and, for every function defined at
+its top level, swaps the new chunk into the function table already bound to the global of the same
+name. The table stays the same one, so every global, table and closure over it sees the new code on
+its next call. Calls already running keep the chunk they were called with.
+
+Nothing else from the new version runs: its top level isn't executed, so globals keep their values.
+A function that's new gets its global defined, one that's gone is left as it was.
+
+A function with a chunk on the chunk stack, or in a suspended coroutine, isn't swapped. It would
+return into code that no longer matches the old version, so it's reported instead and can be
+reloaded again once it's finished. Chunks are matched to functions by the name in their debug info.
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ReloadReport {
+    pub replaced: Vec<String>,
+    pub added: Vec<String>,
+    pub unchanged: Vec<String>,
+    // changed but running, still on the old code
+    pub on_stack: Vec<String>,
+}
+
+impl Vm {
+    pub fn reload(&mut self, file: File) -> Result<ReloadReport, CompileError> {
+        let module = compile(file, self)?;
+        let mut report = ReloadReport::default();
+        for (name, new) in top_level_functions(self, &module) {
+            let old = match self.get_global(name.as_str()) {
+                Some(Value::Table(handle)) if matches!(self.tables.get(handle), Some(Table::Function(_))) => handle,
+                // a new function, or a global that wasn't a function before
+                _ => {
+                    self.set_global(name.as_str(), Value::Table(new));
+                    report.added.push(name);
+                    continue;
+                }
+            };
+            if self.same_function(old, new) {
+                report.unchanged.push(name);
+                continue;
+            }
+            if self.is_running(name.as_str()) {
+                report.on_stack.push(name);
+                continue;
+            }
+            let Some(Table::Function(function)) = self.tables.get(new) else {
+                unreachable!()
+            };
+            let function = function.clone();
+            let Some(Table::Function(old)) = self.tables.get_mut(old) else {
+                unreachable!()
+            };
+            old.arity = function.arity;
+            old.exported = function.exported;
+            old.chunk = function.chunk;
+            report.replaced.push(name);
+        }
+        Ok(report)
+    }
+    fn same_function(&self, old: usize, new: usize) -> bool {
+        match (self.tables.get(old), self.tables.get(new)) {
+            (Some(Table::Function(old)), Some(Table::Function(new))) => {
+                old.arity == new.arity && old.exported == new.exported
+                    && old.chunk.get_instructions() == new.chunk.get_instructions()
+                    && old.chunk.get_constants().len() == new.chunk.get_constants().len()
+                    && old.chunk.get_constants().iter().zip(new.chunk.get_constants())
+                        .all(|(old, new)| self.same_constant(*old, *new))
+            }
+            _ => false,
+        }
+    }
+    // the new version's strings and nested functions are new tables, so those are compared by what's in them
+    fn same_constant(&self, old: Value, new: Value) -> bool {
+        match (old, new) {
+            (Value::Number(old), Value::Number(new)) => old == new,
+            (Value::Float(old), Value::Float(new)) => old.to_bits() == new.to_bits(),
+            (Value::Boolean(old), Value::Boolean(new)) => old == new,
+            (Value::Nil, Value::Nil) => true,
+            (Value::Table(old), Value::Table(new)) => match (self.tables.get(old), self.tables.get(new)) {
+                (Some(Table::String(old)), Some(Table::String(new))) => old == new,
+                (Some(Table::Function(_)), Some(Table::Function(_))) => self.same_function(old, new),
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+    fn is_running(&self, name: &str) -> bool {
+        let named = |chunk: &Chunk| chunk.get_debug().map_or(false, |debug| debug.name == name);
+        self.chunks.iter().any(named) || self.tables.iter().any(|table| match table {
+            Table::Coroutine(coroutine) => coroutine.chunks.iter().any(named),
+            _ => false,
+        })
+    }
+}
+// (name, function handle) for every `fn` at the top level, which compiles to CONSTANT then DEFINE_GLOBAL
+fn top_level_functions(vm: &Vm, module: &Chunk) -> Vec<(String, usize)> {
+    let mut functions = vec![];
+    let mut previous = None;
+    let mut ip = 0;
+    while let Ok(instruction) = decode(module.get_instructions(), ip) {
+        if let (Some(constant), bytecode::DEFINE_GLOBAL) = (previous, instruction.opcode) {
+            let name = module.get_constants()[instruction.operand.unwrap_or_default()];
+            if let (Value::Table(handle), Ok(name)) = (module.get_constants()[constant], vm.string(name)) {
+                if matches!(vm.get_tables().get(handle), Some(Table::Function(_))) {
+                    functions.push((name.to_string(), handle));
+                }
+            }
+        }
+        previous = match instruction.opcode {
+            bytecode::CONSTANT => instruction.operand,
+            _ => None,
+        };
+        ip = instruction.next_ip();
+    }
+    functions
+}
@@ -0,0 +1,533 @@
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use std::sync::Arc;
+use indexmap::IndexMap;
+use crate::second_attempt::vm2::{Chunk, Handler, Vm};
+use crate::second_attempt::vm2::coroutine::{Coroutine, CoroutineFrame, CoroutineState, ResumeKind};
+use crate::second_attempt::vm2::file::{BytecodeFileError, Reader, Writer};
+use crate::second_attempt::vm2::function::{CallFrame, Function, Native};
+use crate::second_attempt::vm2::misc::{Table, TableKey, Value};
+use crate::second_attempt::vm2::table_ops::Iterations;
+use crate::second_attempt::vm2::verify::{is_instruction_boundary, verify, VerifyError};
+use crate::second_attempt::vm_error::VmErrorKind;
+
+/*
+The `.tbls` snapshot format, a whole vm written out so it can carry on later. Integers are little
+endian and values, strings and debug info are written the same way as in `.tblc` files.
+
+    magic           b"TBLS"
+    version         u16
+    tables          u64 count, then each table as a u8 tag followed by its payload
+    globals         u64 count, then (name, value) for each
+    locals, eval    u64 count, then the values from the bottom up
+    chunks          u64 count, then each chunk from the bottom up
+    handlers        u64 count, then (chunk depth, target, eval length, locals length) as u64s
+    calls           u64 count, then (chunk depth, eval length, locals length) as u64s
+    coroutines      u64 count, then (handle, chunk depth, eval base, locals base) as u64s
+                        followed by the resume kind
+    iterations      u64 count, then the snapshot behind each TABLE_NEXT cursor
+
+A chunk is its ip, its constants, its instruction bytes and then its debug info.
+
+Tables are written in arena order and a table value is just its index in the arena, so a table
+referenced from several places is still one table after restoring and cycles need nothing special.
+
+Natives are Rust closures and can't be written out, only their names are. A restored native fails
+with `MissingNative` when called until the host defines a native with the same name again, which
+puts the function back into the same table so every reference to it sees the new one.
+
+Limits belong to the host and aren't part of the snapshot, nor is what's left of the fuel.
+ */
+pub const MAGIC: [u8; 4] = *b"TBLS";
+pub const VERSION: u16 = 2;
+
+const TABLE_MAP: u8 = 0x00;
+const TABLE_STRING: u8 = 0x01;
+const TABLE_ARRAY: u8 = 0x02;
+const TABLE_COROUTINE: u8 = 0x03;
+const TABLE_FUNCTION: u8 = 0x04;
+const TABLE_NATIVE: u8 = 0x05;
+
+const KEY_IDENTIFIER: u8 = 0x00;
+const KEY_INDEX: u8 = 0x01;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(std::io::Error),
+    BadMagic,
+    UnsupportedVersion(u16),
+    UnexpectedEnd,
+    UnknownTag(u8),
+    InvalidUtf8,
+    BadTableHandle(usize),
+    Invalid(VerifyError),
+    // the bytes decode fine but don't describe a vm that could run
+    Inconsistent(&'static str),
+}
+impl Display for SnapshotError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::Io(err) => write!(f, "io error: {}", err),
+            SnapshotError::BadMagic => write!(f, "not a vm snapshot"),
+            SnapshotError::UnsupportedVersion(version) => write!(f, "unsupported snapshot version {}, expected {}", version, VERSION),
+            SnapshotError::UnexpectedEnd => write!(f, "snapshot ended unexpectedly"),
+            SnapshotError::UnknownTag(tag) => write!(f, "unknown tag {:#04x}", tag),
+            SnapshotError::InvalidUtf8 => write!(f, "string isn't valid utf8"),
+            SnapshotError::BadTableHandle(handle) => write!(f, "no table with handle {}", handle),
+            SnapshotError::Invalid(err) => write!(f, "chunk failed verification: {}", err),
+            SnapshotError::Inconsistent(reason) => write!(f, "inconsistent snapshot: {}", reason),
+        }
+    }
+}
+impl From<BytecodeFileError> for SnapshotError {
+    fn from(err: BytecodeFileError) -> Self {
+        match err {
+            BytecodeFileError::Io(err) => SnapshotError::Io(err),
+            BytecodeFileError::UnknownValueTag(tag) => SnapshotError::UnknownTag(tag),
+            BytecodeFileError::InvalidUtf8 => SnapshotError::InvalidUtf8,
+            BytecodeFileError::Invalid(err) => SnapshotError::Invalid(err),
+            _ => SnapshotError::UnexpectedEnd,
+        }
+    }
+}
+impl From<VerifyError> for SnapshotError {
+    fn from(err: VerifyError) -> Self {
+        SnapshotError::Invalid(err)
+    }
+}
+impl From<std::io::Error> for SnapshotError {
+    fn from(err: std::io::Error) -> Self {
+        SnapshotError::Io(err)
+    }
+}
+
+impl Vm {
+    // everything needed to carry on from here, can be taken between instructions, e.g. from a `Hook`
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut writer = Writer::default();
+        writer.write_bytes(&MAGIC);
+        writer.write_u16(VERSION);
+        writer.write_u64(self.tables.len() as u64);
+        for table in &self.tables {
+            write_table(&mut writer, table);
+        }
+        // sorted so the same vm always gives the same bytes
+        let mut globals: Vec<_> = self.globals.iter().collect();
+        globals.sort_by_key(|(name, _)| *name);
+        writer.write_u64(globals.len() as u64);
+        for (name, value) in globals {
+            writer.write_str(name);
+            writer.write_value(value);
+        }
+        write_values(&mut writer, self.locals.iter());
+        write_values(&mut writer, self.eval.iter());
+        write_chunks(&mut writer, &self.chunks);
+        write_handlers(&mut writer, &self.handlers);
+        write_calls(&mut writer, &self.calls);
+        writer.write_u64(self.coroutines.len() as u64);
+        for frame in &self.coroutines {
+            writer.write_u64(frame.handle as u64);
+            writer.write_u64(frame.chunk_depth as u64);
+            writer.write_u64(frame.eval_base as u64);
+            writer.write_u64(frame.locals_base as u64);
+            match frame.kind {
+                ResumeKind::Value => writer.write_u8(0),
+                ResumeKind::ForLoop(target) => {
+                    writer.write_u8(1);
+                    writer.write_u64(target as u64);
+                }
+            }
+        }
+        write_iterations(&mut writer, &self.iterations);
+        writer.buffer
+    }
+    pub fn snapshot_to<P: AsRef<Path>>(&self, path: P) -> Result<(), SnapshotError> {
+        std::fs::write(path, self.snapshot())?;
+        Ok(())
+    }
+    // the restored vm has no limits set, `run` carries on from where the snapshot was taken
+    pub fn restore(bytes: &[u8]) -> Result<Vm, SnapshotError> {
+        let mut reader = Reader::new(bytes);
+        if reader.read_bytes(4)? != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        let version = reader.read_u16()?;
+        if version != VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        let mut vm = Vm::default();
+        let table_count = reader.read_len()?;
+        for _ in 0..table_count {
+            let table = read_table(&mut reader)?;
+            // a new vm has no limits, so every table fits
+            vm.push_table(table).unwrap();
+        }
+        for _ in 0..reader.read_len()? {
+            let name = reader.read_str()?;
+            let value = reader.read_value()?;
+            vm.globals.set_by_name(name.as_str(), value);
+        }
+        for value in read_values(&mut reader)? {
+            vm.locals.push(value).ok_or(SnapshotError::Inconsistent("too many locals"))?;
+        }
+        for value in read_values(&mut reader)? {
+            vm.eval.push(value).ok_or(SnapshotError::Inconsistent("too many values on the eval stack"))?;
+        }
+        vm.chunks = read_chunks(&mut reader)?;
+        vm.handlers = read_handlers(&mut reader)?;
+        vm.calls = read_calls(&mut reader)?;
+        for _ in 0..reader.read_len()? {
+            let handle = reader.read_u64()? as usize;
+            let chunk_depth = reader.read_u64()? as usize;
+            let eval_base = reader.read_u64()? as usize;
+            let locals_base = reader.read_u64()? as usize;
+            let kind = match reader.read_u8()? {
+                0 => ResumeKind::Value,
+                1 => ResumeKind::ForLoop(reader.read_u64()? as usize),
+                tag => return Err(SnapshotError::UnknownTag(tag)),
+            };
+            vm.coroutines.push(CoroutineFrame {
+                handle,
+                chunk_depth,
+                eval_base,
+                locals_base,
+                kind
+            });
+        }
+        vm.iterations = read_iterations(&mut reader)?;
+        if !reader.is_empty() {
+            return Err(SnapshotError::Inconsistent("trailing bytes"));
+        }
+        vm.check_snapshot()?;
+        Ok(vm)
+    }
+    pub fn restore_from<P: AsRef<Path>>(path: P) -> Result<Vm, SnapshotError> {
+        Self::restore(std::fs::read(path)?.as_slice())
+    }
+    // handles and depths are only trusted once they're known to point at something
+    fn check_snapshot(&self) -> Result<(), SnapshotError> {
+        let check_value = |value: &Value| match value {
+            Value::Table(handle) if *handle >= self.tables.len() => Err(SnapshotError::BadTableHandle(*handle)),
+            _ => Ok(()),
+        };
+        let check_chunks = |chunks: &[Chunk]| chunks.iter().try_for_each(|chunk| {
+            chunk.get_constants().iter().try_for_each(check_value)
+        });
+        for table in &self.tables {
+            match table {
+                Table::Map(map) => map.values().try_for_each(check_value)?,
+                Table::Array(values) => values.iter().try_for_each(check_value)?,
+                Table::Function(function) => check_chunks(std::slice::from_ref(&function.chunk))?,
+                Table::Coroutine(coroutine) => {
+                    coroutine.eval.iter().chain(coroutine.locals.iter()).try_for_each(check_value)?;
+                    check_chunks(&coroutine.chunks)?;
+                    check_frames(&coroutine.chunks, &coroutine.handlers, &coroutine.calls, coroutine.eval.len(), coroutine.locals.len())?;
+                }
+                Table::String(_) | Table::Native(_) => {}
+            }
+        }
+        self.globals.iter().try_for_each(|(_, value)| check_value(&value))?;
+        self.locals.iter().chain(self.eval.iter()).try_for_each(|value| check_value(&value))?;
+        self.iterations.snapshots().iter().flatten().flatten()
+            .filter_map(|(_, value)| value.as_ref())
+            .try_for_each(check_value)?;
+        check_chunks(&self.chunks)?;
+        check_frames(&self.chunks, &self.handlers, &self.calls, self.eval.len(), self.locals.len())?;
+        let chunk_depth = self.chunks.len();
+        for frame in &self.coroutines {
+            if frame.chunk_depth > chunk_depth || frame.eval_base > self.eval.len() || frame.locals_base > self.locals.len() {
+                return Err(SnapshotError::Inconsistent("coroutine frame past the top of the vm"));
+            }
+            // a for loop carries on in the chunk that resumed the coroutine
+            if let ResumeKind::ForLoop(target) = frame.kind {
+                let resumer = frame.chunk_depth.checked_sub(1).and_then(|index| self.chunks.get(index))
+                    .ok_or(SnapshotError::Inconsistent("for loop without the chunk that runs it"))?;
+                if !is_instruction_boundary(resumer, target) {
+                    return Err(SnapshotError::Inconsistent("for loop target isn't the start of an instruction"));
+                }
+            }
+            // yielding takes everything above the frame with it, so that has to be inside its part of the stacks
+            let inside = |eval_len: usize, locals_len: usize| eval_len >= frame.eval_base && locals_len >= frame.locals_base;
+            let handlers = self.handlers.iter().filter(|handler| handler.chunk_depth > frame.chunk_depth);
+            let calls = self.calls.iter().filter(|call| call.chunk_depth >= frame.chunk_depth);
+            if !handlers.map(|handler| (handler.eval_len, handler.locals_len)).chain(calls.map(|call| (call.eval_len, call.locals_len)))
+                .all(|(eval_len, locals_len)| inside(eval_len, locals_len)) {
+                return Err(SnapshotError::Inconsistent("handler or call below the coroutine it runs in"));
+            }
+            match self.tables.get(frame.handle) {
+                Some(Table::Coroutine(coroutine)) if coroutine.state == CoroutineState::Running => {}
+                _ => return Err(SnapshotError::Inconsistent("coroutine frame for something that isn't a running coroutine")),
+            }
+        }
+        Ok(())
+    }
+}
+// handlers and calls of the vm or of a suspended coroutine, against the chunks and stacks they belong to
+fn check_frames(chunks: &[Chunk], handlers: &[Handler], calls: &[CallFrame], eval_len: usize, locals_len: usize) -> Result<(), SnapshotError> {
+    // unwinding and yielding search these by depth
+    if handlers.windows(2).any(|pair| pair[0].chunk_depth > pair[1].chunk_depth) || calls.windows(2).any(|pair| pair[0].chunk_depth >= pair[1].chunk_depth) {
+        return Err(SnapshotError::Inconsistent("handlers or calls out of order"));
+    }
+    for handler in handlers {
+        // pushed with the chunk stack's length, so it belongs to the chunk below that
+        let chunk = handler.chunk_depth.checked_sub(1).and_then(|index| chunks.get(index))
+            .ok_or(SnapshotError::Inconsistent("handler for a chunk that isn't there"))?;
+        if !is_instruction_boundary(chunk, handler.target) {
+            return Err(SnapshotError::Inconsistent("handler target isn't the start of an instruction"));
+        }
+        if handler.eval_len > eval_len || handler.locals_len > locals_len {
+            return Err(SnapshotError::Inconsistent("handler past the top of the stacks"));
+        }
+    }
+    for call in calls {
+        // the called chunk is at `chunk_depth`
+        if call.chunk_depth >= chunks.len() {
+            return Err(SnapshotError::Inconsistent("call for a chunk that isn't there"));
+        }
+        if call.eval_len > eval_len || call.locals_len > locals_len {
+            return Err(SnapshotError::Inconsistent("call past the top of the stacks"));
+        }
+    }
+    Ok(())
+}
+
+fn write_key(writer: &mut Writer, key: &TableKey) {
+    match key {
+        TableKey::Identifier(name, index) => {
+            writer.write_u8(KEY_IDENTIFIER);
+            writer.write_str(name.as_str());
+            match index {
+                None => writer.write_u8(0),
+                Some(index) => {
+                    writer.write_u8(1);
+                    writer.write_u64(*index as u64);
+                }
+            }
+        }
+        TableKey::NoIdentifier(index) => {
+            writer.write_u8(KEY_INDEX);
+            writer.write_u64(*index as u64);
+        }
+    }
+}
+fn read_key(reader: &mut Reader) -> Result<TableKey, SnapshotError> {
+    Ok(match reader.read_u8()? {
+        KEY_IDENTIFIER => {
+            let name = reader.read_str()?;
+            let index = match reader.read_u8()? {
+                0 => None,
+                _ => Some(reader.read_u64()? as usize),
+            };
+            TableKey::Identifier(name, index)
+        }
+        KEY_INDEX => TableKey::NoIdentifier(reader.read_u64()? as usize),
+        tag => return Err(SnapshotError::UnknownTag(tag)),
+    })
+}
+// each slot is 0 when it's free, otherwise 1 and what's left of its snapshot
+fn write_iterations(writer: &mut Writer, iterations: &Iterations) {
+    writer.write_u64(iterations.snapshots().len() as u64);
+    for snapshot in iterations.snapshots() {
+        let Some(entries) = snapshot else {
+            writer.write_u8(0);
+            continue;
+        };
+        writer.write_u8(1);
+        writer.write_u64(entries.len() as u64);
+        for (key, value) in entries {
+            write_key(writer, key);
+            match value {
+                None => writer.write_u8(0),
+                Some(value) => {
+                    writer.write_u8(1);
+                    writer.write_value(*value);
+                }
+            }
+        }
+    }
+}
+fn read_iterations(reader: &mut Reader) -> Result<Iterations, SnapshotError> {
+    let mut snapshots = vec![];
+    for _ in 0..reader.read_len()? {
+        if reader.read_u8()? == 0 {
+            snapshots.push(None);
+            continue;
+        }
+        let mut entries = vec![];
+        for _ in 0..reader.read_len()? {
+            let key = read_key(reader)?;
+            let value = match reader.read_u8()? {
+                0 => None,
+                _ => Some(reader.read_value()?),
+            };
+            entries.push((key, value));
+        }
+        snapshots.push(Some(entries));
+    }
+    Ok(Iterations::restore(snapshots))
+}
+fn write_table(writer: &mut Writer, table: &Table) {
+    match table {
+        Table::Map(map) => {
+            writer.write_u8(TABLE_MAP);
+            writer.write_u64(map.len() as u64);
+            for (key, value) in map {
+                write_key(writer, key);
+                writer.write_value(*value);
+            }
+        }
+        Table::String(string) => {
+            writer.write_u8(TABLE_STRING);
+            writer.write_str(string.as_str());
+        }
+        Table::Array(values) => {
+            writer.write_u8(TABLE_ARRAY);
+            write_values(writer, values.iter().copied());
+        }
+        Table::Coroutine(coroutine) => {
+            writer.write_u8(TABLE_COROUTINE);
+            writer.write_u8(match coroutine.state {
+                CoroutineState::Suspended => 0,
+                CoroutineState::Running => 1,
+                CoroutineState::Finished => 2,
+            });
+            writer.write_u8(coroutine.started as u8);
+            write_chunks(writer, &coroutine.chunks);
+            write_values(writer, coroutine.eval.iter().copied());
+            write_values(writer, coroutine.locals.iter().copied());
+            write_handlers(writer, &coroutine.handlers);
+            write_calls(writer, &coroutine.calls);
+        }
+        Table::Function(function) => {
+            writer.write_u8(TABLE_FUNCTION);
+            writer.write_str(function.name.as_str());
+            writer.write_u64(function.arity as u64);
+            writer.write_u8(function.exported as u8);
+            write_chunk(writer, &function.chunk);
+        }
+        Table::Native(native) => {
+            writer.write_u8(TABLE_NATIVE);
+            writer.write_str(native.name.as_str());
+        }
+    }
+}
+fn read_table(reader: &mut Reader) -> Result<Table, SnapshotError> {
+    Ok(match reader.read_u8()? {
+        TABLE_MAP => {
+            let len = reader.read_len()?;
+            let mut map = IndexMap::with_capacity(len);
+            for _ in 0..len {
+                let key = read_key(reader)?;
+                map.insert(key, reader.read_value()?);
+            }
+            Table::Map(map.into())
+        }
+        TABLE_STRING => Table::String(reader.read_str()?),
+        TABLE_ARRAY => Table::Array(read_values(reader)?),
+        TABLE_COROUTINE => {
+            let state = match reader.read_u8()? {
+                0 => CoroutineState::Suspended,
+                1 => CoroutineState::Running,
+                2 => CoroutineState::Finished,
+                tag => return Err(SnapshotError::UnknownTag(tag)),
+            };
+            Table::Coroutine(Coroutine {
+                state,
+                started: reader.read_u8()? != 0,
+                chunks: read_chunks(reader)?,
+                eval: read_values(reader)?,
+                locals: read_values(reader)?,
+                handlers: read_handlers(reader)?,
+                calls: read_calls(reader)?,
+            })
+        }
+        TABLE_FUNCTION => Table::Function(Function {
+            name: reader.read_str()?,
+            arity: reader.read_u64()? as usize,
+            exported: reader.read_u8()? != 0,
+            chunk: read_chunk(reader)?,
+        }),
+        TABLE_NATIVE => {
+            let name = reader.read_str()?;
+            let missing = name.clone();
+            Table::Native(Native {
+                name,
+                function: Arc::new(move |_, _| Err(VmErrorKind::MissingNative(missing.clone()))),
+            })
+        }
+        tag => return Err(SnapshotError::UnknownTag(tag)),
+    })
+}
+
+fn write_values(writer: &mut Writer, values: impl ExactSizeIterator<Item = Value>) {
+    writer.write_u64(values.len() as u64);
+    for value in values {
+        writer.write_value(value);
+    }
+}
+fn read_values(reader: &mut Reader) -> Result<Vec<Value>, SnapshotError> {
+    (0..reader.read_len()?).map(|_| Ok(reader.read_value()?)).collect()
+}
+fn write_chunk(writer: &mut Writer, chunk: &Chunk) {
+    writer.write_u64(chunk.ip as u64);
+    write_values(writer, chunk.get_constants().iter().copied());
+    writer.write_u64(chunk.get_instructions().len() as u64);
+    writer.write_bytes(chunk.get_instructions().as_slice());
+    writer.write_debug(chunk.get_debug());
+}
+fn read_chunk(reader: &mut Reader) -> Result<Chunk, SnapshotError> {
+    let ip = reader.read_u64()? as usize;
+    let constants = read_values(reader)?;
+    let len = reader.read_len()?;
+    let mut chunk = Chunk::new(reader.read_bytes(len)?.to_vec(), constants);
+    if let Some(debug) = reader.read_debug()? {
+        chunk = chunk.with_debug(debug);
+    }
+    verify(&chunk)?;
+    if !is_instruction_boundary(&chunk, ip) {
+        return Err(SnapshotError::Inconsistent("instruction pointer isn't the start of an instruction"));
+    }
+    chunk.ip = ip;
+    Ok(chunk)
+}
+fn write_chunks(writer: &mut Writer, chunks: &[Chunk]) {
+    writer.write_u64(chunks.len() as u64);
+    for chunk in chunks {
+        write_chunk(writer, chunk);
+    }
+}
+fn read_chunks(reader: &mut Reader) -> Result<Vec<Chunk>, SnapshotError> {
+    (0..reader.read_len()?).map(|_| read_chunk(reader)).collect()
+}
+fn write_handlers(writer: &mut Writer, handlers: &[Handler]) {
+    writer.write_u64(handlers.len() as u64);
+    for handler in handlers {
+        writer.write_u64(handler.chunk_depth as u64);
+        writer.write_u64(handler.target as u64);
+        writer.write_u64(handler.eval_len as u64);
+        writer.write_u64(handler.locals_len as u64);
+    }
+}
+fn read_handlers(reader: &mut Reader) -> Result<Vec<Handler>, SnapshotError> {
+    (0..reader.read_len()?).map(|_| Ok(Handler {
+        chunk_depth: reader.read_u64()? as usize,
+        target: reader.read_u64()? as usize,
+        eval_len: reader.read_u64()? as usize,
+        locals_len: reader.read_u64()? as usize,
+    })).collect()
+}
+fn write_calls(writer: &mut Writer, calls: &[CallFrame]) {
+    writer.write_u64(calls.len() as u64);
+    for call in calls {
+        writer.write_u64(call.chunk_depth as u64);
+        writer.write_u64(call.eval_len as u64);
+        writer.write_u64(call.locals_len as u64);
+    }
+}
+fn read_calls(reader: &mut Reader) -> Result<Vec<CallFrame>, SnapshotError> {
+    (0..reader.read_len()?).map(|_| Ok(CallFrame {
+        chunk_depth: reader.read_u64()? as usize,
+        eval_len: reader.read_u64()? as usize,
+        locals_len: reader.read_u64()? as usize,
+    })).collect()
+}
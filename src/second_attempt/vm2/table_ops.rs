@@ -0,0 +1,195 @@
+use crate::second_attempt::vm2::Vm;
+use crate::second_attempt::vm2::misc::{Table, TableKey, Value};
+use crate::second_attempt::vm_error::VmErrorKind;
+
+/*
+Enumerating and resizing tables, for `for` loops over tables and for reflection. Maps and arrays
+both work, keys are numbers for positional entries and strings for named ones.
+
+    TABLE_LEN           table -> how many entries it has
+    TABLE_APPEND        value, table -> (nothing), the value goes one past the highest index
+    TABLE_REMOVE        key, table -> the value that was removed, or nil
+    TABLE_NEXT target   table, cursor -> table, cursor, key, value, or jumps to target with the
+                        table left where it was and the cursor back to nil
+
+A loop starts with nil as the cursor. The first TABLE_NEXT takes a snapshot of what there is to
+visit and replaces the cursor with a number naming it, and from then on each step takes the next
+entry off the snapshot. So the loop sees the table as it was when it started: a map entry removed
+before it's reached is skipped, one added during the loop isn't visited, and nothing is visited
+twice. A map's snapshot is its keys and each value is read when its key comes up. An array's
+positions move when something is removed, so its snapshot is its values, each with the position it
+had when the loop started.
+
+Snapshots belong to the vm rather than the arena, there's no GC so a table per loop would never go
+away. A loop that runs to the end frees its own, a loop left early by a jump or a throw leaves it
+behind until the next sweep finds no cursor for it on any stack.
+ */
+// the most snapshots kept before looking for ones nothing refers to any more
+pub(super) const FIRST_SWEEP: usize = 64;
+
+// what's left of a loop's snapshot, last first so a step pops the next one off the end. A value is
+// only there for arrays, a map's is looked up
+pub(super) type Snapshot = Vec<(TableKey, Option<Value>)>;
+#[derive(Default)]
+pub(super) struct Iterations {
+    snapshots: Vec<Option<Snapshot>>,
+    free: Vec<usize>,
+    sweep_at: usize,
+}
+impl Iterations {
+    pub(super) fn snapshots(&self) -> &[Option<Snapshot>] {
+        &self.snapshots
+    }
+    pub(super) fn restore(snapshots: Vec<Option<Snapshot>>) -> Self {
+        let free = (0..snapshots.len()).filter(|slot| snapshots[*slot].is_none()).collect();
+        Self {
+            snapshots,
+            free,
+            sweep_at: 0,
+        }
+    }
+    fn live(&self) -> usize {
+        self.snapshots.len() - self.free.len()
+    }
+    fn start(&mut self, snapshot: Snapshot) -> usize {
+        match self.free.pop() {
+            Some(slot) => {
+                self.snapshots[slot] = Some(snapshot);
+                slot
+            }
+            None => {
+                self.snapshots.push(Some(snapshot));
+                self.snapshots.len() - 1
+            }
+        }
+    }
+    fn finish(&mut self, slot: usize) {
+        self.snapshots[slot] = None;
+        self.free.push(slot);
+    }
+}
+
+impl Vm {
+    pub(super) fn table_len(&mut self) -> Result<(), VmErrorKind> {
+        let handle = self.pop_table_handle()?;
+        let len = match &self.tables[handle] {
+            Table::Map(map) => map.len(),
+            Table::Array(values) => values.len(),
+            table => return Err(VmErrorKind::TypeError { expected: "table", found: table.type_name() }),
+        };
+        self.push(Value::Number(len as i64))
+    }
+    pub(super) fn table_append(&mut self) -> Result<(), VmErrorKind> {
+        let handle = self.pop_table_handle()?;
+        let value = self.pop()?;
+        let key = match &self.tables[handle] {
+            Table::Map(map) => TableKey::NoIdentifier(map.next_index()),
+            Table::Array(values) => TableKey::NoIdentifier(values.len()),
+            table => return Err(VmErrorKind::TypeError { expected: "table", found: table.type_name() }),
+        };
+        self.track_entry(&key)?;
+        match &mut self.tables[handle] {
+            Table::Map(map) => {
+                map.insert(key, value);
+            }
+            Table::Array(values) => values.push(value),
+            _ => {}
+        }
+        Ok(())
+    }
+    pub(super) fn table_remove(&mut self) -> Result<(), VmErrorKind> {
+        let handle = self.pop_table_handle()?;
+        let key = self.pop()?;
+        let key = self.table_key(key)?;
+        let removed = match (&mut self.tables[handle], key) {
+            // shifting keeps the rest in insertion order
+            (Table::Map(map), key) => map.shift_remove(&key),
+            (Table::Array(values), TableKey::NoIdentifier(index)) if index < values.len() => Some(values.remove(index)),
+            (Table::Array(_), _) => None,
+            (table, _) => return Err(VmErrorKind::TypeError { expected: "table", found: table.type_name() }),
+        };
+        self.push(removed.unwrap_or_default())
+    }
+    // true once the loop is over
+    pub(super) fn table_next(&mut self) -> Result<bool, VmErrorKind> {
+        let cursor = self.pop()?;
+        let handle = Self::table_handle(self.eval.peek(0).ok_or(VmErrorKind::StackUnderflow)?)?;
+        let slot = match cursor {
+            Value::Nil => self.start_iteration(handle)?,
+            Value::Number(slot) if self.iterations.snapshots.get(slot as usize).map_or(false, Option::is_some) => slot as usize,
+            cursor => return Err(VmErrorKind::TypeError { expected: "table cursor", found: cursor.type_name() }),
+        };
+        loop {
+            let next = self.iterations.snapshots[slot].as_mut().and_then(Vec::pop);
+            let Some((key, value)) = next else {
+                self.iterations.finish(slot);
+                self.push(Value::Nil)?;
+                return Ok(true);
+            };
+            let value = match (value, self.tables.get(handle)) {
+                (Some(value), _) => value,
+                (None, Some(Table::Map(map))) => match map.get(&key) {
+                    Some(value) => *value,
+                    // removed since the loop started
+                    None => continue,
+                },
+                (None, Some(table)) => return Err(VmErrorKind::TypeError { expected: "table", found: table.type_name() }),
+                (None, None) => return Err(VmErrorKind::BadTableHandle(handle)),
+            };
+            let key = match key {
+                TableKey::Identifier(name, _) => self.alloc_string(name)?,
+                TableKey::NoIdentifier(index) => Value::Number(index as i64),
+            };
+            self.push(Value::Number(slot as i64))?;
+            self.push(key)?;
+            self.push(value)?;
+            return Ok(false);
+        }
+    }
+    fn start_iteration(&mut self, handle: usize) -> Result<usize, VmErrorKind> {
+        let snapshot = match self.tables.get(handle) {
+            Some(Table::Map(map)) => map.keys().rev().map(|key| (key.clone(), None)).collect(),
+            Some(Table::Array(values)) => values.iter().enumerate().rev()
+                .map(|(index, value)| (TableKey::NoIdentifier(index), Some(*value)))
+                .collect(),
+            Some(table) => return Err(VmErrorKind::TypeError { expected: "table", found: table.type_name() }),
+            None => return Err(VmErrorKind::BadTableHandle(handle)),
+        };
+        if self.iterations.free.is_empty() && self.iterations.live() >= self.iterations.sweep_at {
+            self.sweep_iterations();
+            self.iterations.sweep_at = FIRST_SWEEP.max(self.iterations.live() * 2);
+        }
+        Ok(self.iterations.start(snapshot))
+    }
+    // a cursor is a number on a stack, the vm's own or a suspended coroutine's, so a snapshot with no
+    // number like it anywhere can't be reached again. Any number counts, keeping a few too many is fine
+    fn sweep_iterations(&mut self) {
+        let mut reachable = vec![false; self.iterations.snapshots.len()];
+        let mut mark = |value: &Value| {
+            if let Value::Number(slot) = value {
+                if let Some(reachable) = reachable.get_mut(*slot as usize) {
+                    *reachable = true;
+                }
+            }
+        };
+        self.eval.iter().chain(self.locals.iter()).for_each(|value| mark(&value));
+        for table in &self.tables {
+            if let Table::Coroutine(coroutine) = table {
+                coroutine.eval.iter().chain(coroutine.locals.iter()).for_each(&mut mark);
+            }
+        }
+        for (slot, reachable) in reachable.into_iter().enumerate() {
+            if !reachable && self.iterations.snapshots[slot].is_some() {
+                self.iterations.finish(slot);
+            }
+        }
+    }
+    // numbers are positions and strings are names, like the keys TABLE_NEXT gives back
+    fn table_key(&self, key: Value) -> Result<TableKey, VmErrorKind> {
+        match key {
+            Value::Number(index) if index >= 0 => Ok(TableKey::NoIdentifier(index as usize)),
+            Value::Table(_) => Ok(TableKey::Identifier(self.string(key)?.to_string(), None)),
+            key => Err(VmErrorKind::TypeError { expected: "positive number or string", found: key.type_name() }),
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use indexmap::IndexMap;
+use crate::second_attempt::vm2::{Chunk, Vm};
+use crate::second_attempt::vm2::bytecode::{convert_bytecode_array, Bytecode, Bytecode::*};
+use crate::second_attempt::vm2::misc::{Table, TableKey, Value};
+use crate::second_attempt::vm2::table_ops::FIRST_SWEEP;
+
+fn size(code: &[Bytecode]) -> usize {
+    code.iter().map(Bytecode::size).sum()
+}
+fn run(vm: &mut Vm, code: Vec<Bytecode>, constants: Vec<Value>) {
+    vm.load(Chunk::new(convert_bytecode_array(code), constants)).unwrap();
+    vm.run().unwrap();
+}
+// loops over constant 0, each step appends the key and value to constant 1 and then runs `body`
+fn collecting_loop(body: Vec<Bytecode>) -> Vec<Bytecode> {
+    let head = size(&[Constant(0), Constant(2)]);
+    let step = [TableNext(0), Constant(1), TableAppend, Constant(1), TableAppend];
+    let end = head + size(&step) + size(&body) + Jump(0).size();
+    let mut code = vec![Constant(0), Constant(2), TableNext(end), Constant(1), TableAppend, Constant(1), TableAppend];
+    code.extend(body);
+    code.push(Jump(head));
+    code
+}
+// what TABLE_NEXT handed out, keys and values one after the other
+fn collected(vm: &Vm, out: Value) -> Vec<String> {
+    let Value::Table(handle) = out else { unreachable!() };
+    let Some(Table::Map(map)) = vm.get_tables().get(handle) else { unreachable!() };
+    map.values().map(|value| match vm.string(*value) {
+        Ok(string) => string.to_string(),
+        Err(_) => value.to_string(),
+    }).collect()
+}
+
+#[test]
+fn map_loop_sees_the_table_as_it_started() {
+    let mut vm = Vm::default();
+    let mut entries = IndexMap::new();
+    for index in 0..3 {
+        entries.insert(TableKey::NoIdentifier(index), Value::Number(10 + index as i64));
+    }
+    entries.insert(TableKey::Identifier("x".to_string(), None), Value::Number(13));
+    let table = vm.alloc_table(entries).unwrap();
+    let out = vm.alloc_table(IndexMap::new()).unwrap();
+    // every step removes 1 and appends 99, neither the removed entry nor the appended ones are visited
+    let code = collecting_loop(vec![Constant(3), Constant(0), TableRemove, Pop, Constant(4), Constant(0), TableAppend]);
+    run(&mut vm, code, vec![table, out, Value::Nil, Value::Number(1), Value::Number(99)]);
+    // the value comes off the stack first, so it's appended before its key
+    assert_eq!(collected(&vm, out), ["10", "0", "12", "2", "13", "x"]);
+    let Value::Table(handle) = table else { unreachable!() };
+    let Some(Table::Map(map)) = vm.get_tables().get(handle) else { unreachable!() };
+    let keys: Vec<_> = map.keys().cloned().collect();
+    let expected = [0, 2].map(TableKey::NoIdentifier).into_iter()
+        .chain([TableKey::Identifier("x".to_string(), None)])
+        .chain([3, 4, 5].map(TableKey::NoIdentifier));
+    assert!(keys.into_iter().eq(expected));
+}
+#[test]
+fn array_loop_sees_the_values_it_started_with() {
+    let mut vm = Vm::default();
+    let table = vm.push_table(Table::Array(vec![Value::Number(10), Value::Number(11), Value::Number(12)])).unwrap();
+    let out = vm.alloc_table(IndexMap::new()).unwrap();
+    // every step removes the first value and appends 99
+    let code = collecting_loop(vec![Constant(3), Constant(0), TableRemove, Pop, Constant(4), Constant(0), TableAppend]);
+    run(&mut vm, code, vec![table, out, Value::Nil, Value::Number(0), Value::Number(99)]);
+    assert_eq!(collected(&vm, out), ["10", "0", "11", "1", "12", "2"]);
+}
+#[test]
+fn loops_left_early_are_swept() {
+    let mut vm = Vm::default();
+    let mut entries = IndexMap::new();
+    entries.insert(TableKey::NoIdentifier(0), Value::Number(0));
+    let table = vm.alloc_table(entries).unwrap();
+    // starts a loop and drops it after the first entry, a thousand times over
+    let constants = vec![table, Value::Nil, Value::Number(0), Value::Number(1), Value::Number(1000)];
+    let head = size(&[Constant(2), PushLocal]);
+    let test = [GetLocal(0), Constant(4), TestLess, TestNot, JumpIf(0)];
+    let body_start = head + size(&test);
+    let skip = body_start + size(&[Constant(0), Constant(1), TableNext(0), Pop, Pop]);
+    let body = [Constant(0), Constant(1), TableNext(skip), Pop, Pop, Pop, Pop, GetLocal(0), Constant(3), Add, SetLocal(0), Jump(head)];
+    let end = body_start + size(&body);
+    let mut code = vec![Constant(2), PushLocal, GetLocal(0), Constant(4), TestLess, TestNot, JumpIf(end)];
+    code.extend(body);
+    run(&mut vm, code, constants);
+    assert!(vm.iterations.snapshots().len() <= FIRST_SWEEP);
+}
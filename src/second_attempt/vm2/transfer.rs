@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+use indexmap::IndexMap;
+use crate::second_attempt::vm2::{Chunk, Vm};
+use crate::second_attempt::vm2::misc::{Table, Value};
+use crate::second_attempt::vm_error::VmErrorKind;
+
+/*
+Moving values between vms, say between isolates on different threads. A table value is only a handle
+into its own vm's arena, so sending one means copying everything it can reach.
+
+`Vm::export_values` deep copies the values into a `Message`, a little arena of its own with the
+handles renumbered to point into it. Each table is copied once however many times it's reached, so
+shared references stay shared and cycles are fine. `Vm::import_values` appends the message's tables
+to another vm's arena and moves the handles along with them.
+
+Functions are copied with their chunks and whatever their constants reach. Natives share the same
+Rust closure. Coroutines can't be copied, a paused coroutine's stacks belong to the vm running it.
+ */
+pub struct Message {
+    tables: Vec<Table>,
+    values: Vec<Value>,
+}
+impl Message {
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+impl Vm {
+    pub fn export_values(&self, values: &[Value]) -> Result<Message, VmErrorKind> {
+        let mut exporter = Exporter {
+            vm: self,
+            copied: HashMap::new(),
+            tables: vec![],
+        };
+        let values = values.iter().map(|value| exporter.value(*value)).collect::<Result<_, _>>()?;
+        Ok(Message {
+            tables: exporter.tables,
+            values,
+        })
+    }
+    // the values in the order they were exported, now pointing into this vm
+    // nothing is imported if the tables go over one of this vm's limits
+    pub fn import_values(&mut self, message: Message) -> Result<Vec<Value>, VmErrorKind> {
+        let offset = self.tables.len();
+        let mut tables = message.tables;
+        for table in &mut tables {
+            match table {
+                Table::Map(map) => map.values_mut().for_each(|value| *value = relocate(*value, offset)),
+                Table::Array(values) => values.iter_mut().for_each(|value| *value = relocate(*value, offset)),
+                Table::Function(function) => {
+                    function.chunk.get_constants_mut().iter_mut().for_each(|value| *value = relocate(*value, offset));
+                }
+                Table::String(_) | Table::Native(_) | Table::Coroutine(_) => {}
+            }
+        }
+        self.push_tables(tables)?;
+        Ok(message.values.into_iter().map(|value| relocate(value, offset)).collect())
+    }
+}
+fn relocate(value: Value, offset: usize) -> Value {
+    match value {
+        Value::Table(handle) => Value::Table(handle + offset),
+        value => value,
+    }
+}
+
+struct Exporter<'a> {
+    vm: &'a Vm,
+    // handle in the vm to index in the message
+    copied: HashMap<usize, usize>,
+    tables: Vec<Table>,
+}
+impl<'a> Exporter<'a> {
+    fn value(&mut self, value: Value) -> Result<Value, VmErrorKind> {
+        match value {
+            Value::Table(handle) => Ok(Value::Table(self.table(handle)?)),
+            value => Ok(value),
+        }
+    }
+    fn table(&mut self, handle: usize) -> Result<usize, VmErrorKind> {
+        if let Some(index) = self.copied.get(&handle) {
+            return Ok(*index);
+        }
+        // claimed before the contents are copied so a cycle back to it finds it
+        let index = self.tables.len();
+        self.copied.insert(handle, index);
+        self.tables.push(Table::Array(vec![]));
+        let table = match self.vm.tables.get(handle).ok_or(VmErrorKind::BadTableHandle(handle))? {
+            Table::Map(map) => {
+                let mut copy = IndexMap::with_capacity(map.len());
+                for (key, value) in map {
+                    copy.insert(key.clone(), self.value(*value)?);
+                }
+                Table::Map(copy.into())
+            }
+            Table::String(string) => Table::String(string.clone()),
+            Table::Array(values) => Table::Array(values.iter().map(|value| self.value(*value)).collect::<Result<_, _>>()?),
+            Table::Function(function) => {
+                let mut copy = function.clone();
+                copy.chunk = self.chunk(&function.chunk)?;
+                Table::Function(copy)
+            }
+            Table::Native(native) => Table::Native(native.clone()),
+            Table::Coroutine(_) => return Err(VmErrorKind::Native("a coroutine can't be sent to another vm".to_string())),
+        };
+        self.tables[index] = table;
+        Ok(index)
+    }
+    fn chunk(&mut self, chunk: &Chunk) -> Result<Chunk, VmErrorKind> {
+        let mut copy = chunk.clone();
+        for constant in copy.get_constants_mut() {
+            *constant = self.value(*constant)?;
+        }
+        Ok(copy)
+    }
+}
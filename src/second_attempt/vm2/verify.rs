@@ -0,0 +1,148 @@
+use std::fmt::{Display, Formatter};
+use crate::second_attempt::vm2::bytecode::*;
+use crate::second_attempt::vm2::Chunk;
+
+// checked before any chunk is pushed onto the vm, so the run loop can trust what it reads
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum VerifyError {
+    InvalidOpcode { ip: usize, opcode: u8 },
+    TruncatedOperand { ip: usize },
+    BadJumpTarget { ip: usize, target: usize },
+    ConstantOutOfRange { ip: usize, index: usize },
+    StackUnderflow { ip: usize, depth: usize, needed: usize },
+    InconsistentStackDepth { ip: usize, expected: usize, found: usize },
+    StackTooDeep { ip: usize, depth: usize },
+}
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerifyError::InvalidOpcode { ip, opcode } => write!(f, "{:04}: invalid opcode {:#04x}", ip, opcode),
+            VerifyError::TruncatedOperand { ip } => write!(f, "{:04}: operand runs past the end of the chunk", ip),
+            VerifyError::BadJumpTarget { ip, target } => write!(f, "{:04}: jump target {} isn't the start of an instruction", ip, target),
+            VerifyError::ConstantOutOfRange { ip, index } => write!(f, "{:04}: constant index {} out of range", ip, index),
+            VerifyError::StackUnderflow { ip, depth, needed } => write!(f, "{:04}: needs {} values on the stack but only {} are there", ip, needed, depth),
+            VerifyError::InconsistentStackDepth { ip, expected, found } => write!(f, "{:04}: reached with stack depth {} and {}", ip, expected, found),
+            VerifyError::StackTooDeep { ip, depth } => write!(f, "{:04}: stack depth {} is over the limit of {}", ip, depth, MAX_STACK_DEPTH),
+        }
+    }
+}
+
+// a sanity bound on how deep one chunk's own expressions go, the stacks themselves grow as they need to
+pub const MAX_STACK_DEPTH: usize = 1 << 16;
+
+// (values popped, values pushed) on the eval stack
+pub fn stack_effect(opcode: u8) -> (usize, usize) {
+    match opcode {
+        CONSTANT | GET_LOCAL | PEEK_LOCAL | POP_LOCAL | ALLOC_TABLE | GET_GLOBAL => (0, 1),
+        SET_LOCAL | PUSH_LOCAL | INJECT | JUMP_IF | PRINT | THROW | POP | SET_GLOBAL | DEFINE_GLOBAL => (1, 0),
+        TEST_TRUTHY | TEST_NOT | NEW_COROUTINE | YIELD | TABLE_LEN => (1, 1),
+        RESUME => (2, 2),
+        FOR_NEXT => (1, 1),
+        TABLE_NEXT => (2, 2),
+        TABLE_APPEND => (2, 0),
+        TABLE_REMOVE => (2, 1),
+        TEST_EQUAL | TEST_LESS | TEST_LESS_EQUAL | TEST_GREATER | TEST_GREATER_EQUAL => (2, 1),
+        ADD | SUBTRACT | MULTIPLY | DIVIDE => (2, 1),
+        GET_INDEX_TABLE | GET_STR_TABLE => (2, 1),
+        INSERT_INDEX_TABLE | INSERT_STR_TABLE => (3, 0),
+        _ => (0, 0),
+    }
+}
+
+// whether an instruction starts at `ip` or it's the very end of the chunk, for ips that weren't
+// read out of the chunk itself
+pub fn is_instruction_boundary(chunk: &Chunk, ip: usize) -> bool {
+    let instructions = chunk.get_instructions().as_slice();
+    let mut current = 0;
+    while current < ip {
+        match decode(instructions, current) {
+            Ok(instruction) => current = instruction.next_ip(),
+            Err(_) => return false,
+        }
+    }
+    current == ip
+}
+// returns the deepest the eval stack gets while running the chunk, assuming it starts empty
+pub fn verify(chunk: &Chunk) -> Result<usize, VerifyError> {
+    let instructions = chunk.get_instructions().as_slice();
+    // first pass decodes everything, so we know where the instruction boundaries are
+    let mut decoded = vec![];
+    let mut ip = 0;
+    while ip < instructions.len() {
+        let instruction = decode(instructions, ip).map_err(|err| match err {
+            DecodeError::InvalidOpcode(opcode) => VerifyError::InvalidOpcode { ip, opcode },
+            DecodeError::TruncatedOperand => VerifyError::TruncatedOperand { ip },
+        })?;
+        let (_, kind) = opcode_info(instruction.opcode).unwrap();
+        if let (OperandKind::Constant, Some(index)) = (kind, instruction.operand) {
+            if index >= chunk.get_constants().len() {
+                return Err(VerifyError::ConstantOutOfRange { ip, index });
+            }
+        }
+        ip = instruction.next_ip();
+        decoded.push(instruction);
+    }
+    let position = |target: usize| decoded.binary_search_by_key(&target, |instruction| instruction.ip);
+    for instruction in &decoded {
+        if let (Some((_, OperandKind::Jump)), Some(target)) = (opcode_info(instruction.opcode), instruction.operand) {
+            // jumping to the very end just finishes the chunk
+            if target != instructions.len() && position(target).is_err() {
+                return Err(VerifyError::BadJumpTarget { ip: instruction.ip, target });
+            }
+        }
+    }
+    // second pass walks every path through the chunk tracking the stack depth at each instruction
+    let mut depths: Vec<Option<usize>> = vec![None; decoded.len()];
+    let mut work = vec![(0, 0)];
+    let mut max_depth = 0;
+    while let Some((index, depth)) = work.pop() {
+        let Some(instruction) = decoded.get(index) else {
+            continue;
+        };
+        match depths[index] {
+            Some(expected) if expected == depth => continue,
+            Some(expected) => return Err(VerifyError::InconsistentStackDepth { ip: instruction.ip, expected, found: depth }),
+            None => depths[index] = Some(depth),
+        }
+        let (pops, pushes) = match instruction.opcode {
+            // the arguments and the function itself
+            CALL => (instruction.operand.unwrap() + 1, 1),
+            opcode => stack_effect(opcode),
+        };
+        if depth < pops {
+            return Err(VerifyError::StackUnderflow { ip: instruction.ip, depth, needed: pops });
+        }
+        let depth = depth - pops + pushes;
+        if depth > MAX_STACK_DEPTH {
+            return Err(VerifyError::StackTooDeep { ip: instruction.ip, depth });
+        }
+        max_depth = max_depth.max(depth);
+        // a jump to the end of the chunk lands one past the last instruction, which ends the path
+        let jump_index = |target: usize| position(target).unwrap_or(decoded.len());
+        match instruction.opcode {
+            RETURN | THROW => {}
+            // the coroutine stays on the stack, a yielded value goes on top of it
+            FOR_NEXT => {
+                work.push((jump_index(instruction.operand.unwrap()), depth));
+                work.push((index + 1, depth + 1));
+            }
+            // the table and cursor stay, the key and value go on top of them
+            TABLE_NEXT => {
+                work.push((jump_index(instruction.operand.unwrap()), depth));
+                work.push((index + 1, depth + 2));
+            }
+            // the handler is entered with whatever was on the stack when it was pushed plus the thrown value
+            PUSH_HANDLER => {
+                work.push((jump_index(instruction.operand.unwrap()), depth + 1));
+                work.push((index + 1, depth));
+            }
+            JUMP => work.push((jump_index(instruction.operand.unwrap()), depth)),
+            JUMP_IF => {
+                work.push((jump_index(instruction.operand.unwrap()), depth));
+                work.push((index + 1, depth));
+            }
+            _ => work.push((index + 1, depth)),
+        }
+    }
+    Ok(max_depth)
+}
@@ -0,0 +1,282 @@
+use std::fmt::{Display, Formatter};
+use crate::second_attempt::ir::{BinaryOperation, BinaryOperator, Block, Exp, File, FnCall, Identifier, LetStatement, Statement, TableKey};
+use crate::second_attempt::ir;
+use crate::second_attempt::vm3::Chunk;
+use crate::second_attempt::vm3::instruction::*;
+use crate::second_attempt::vm3::value::Value;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CompileError {
+    TooManyRegisters,
+    TooManyConstants,
+    TooManyNames,
+    JumpTooFar,
+    Unsupported(&'static str),
+}
+impl Display for CompileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompileError::TooManyRegisters => write!(f, "expression needs more than 256 registers"),
+            CompileError::TooManyConstants => write!(f, "more than 65536 constants in one chunk"),
+            CompileError::TooManyNames => write!(f, "more than 256 field names in one chunk"),
+            CompileError::JumpTooFar => write!(f, "jump is too far to encode"),
+            CompileError::Unsupported(what) => write!(f, "{} can't be compiled for the register vm yet", what),
+        }
+    }
+}
+
+pub fn compile(file: File) -> Result<Chunk, CompileError> {
+    let mut compiler = Compiler::default();
+    compiler.scopes.push(vec![]);
+    let result = match file {
+        File::Block(block) => compiler.block(block)?,
+        File::None => {
+            let register = compiler.allocate()?;
+            compiler.emit(Instruction::abc(LOAD_NIL, register, 0, 0));
+            register
+        }
+    };
+    compiler.emit(Instruction::abc(RETURN, result, 0, 0));
+    Ok(Chunk::new(compiler.instructions, compiler.constants, compiler.names, compiler.register_count))
+}
+
+/*
+Registers are handed out like a stack. A variable keeps its register until its scope ends,
+temporaries sit above the variables and are given back as soon as the expression using them is done,
+so `free_register` always marks the first register nobody is using.
+ */
+#[derive(Default)]
+struct Compiler {
+    instructions: Vec<Instruction>,
+    constants: Vec<Value>,
+    names: Vec<String>,
+    register_count: usize,
+    free_register: usize,
+    scopes: Vec<Vec<(Identifier, u8)>>,
+}
+impl Compiler {
+    fn emit(&mut self, instruction: Instruction) -> usize {
+        self.instructions.push(instruction);
+        self.instructions.len() - 1
+    }
+    fn allocate(&mut self) -> Result<u8, CompileError> {
+        if self.free_register > u8::MAX as usize {
+            return Err(CompileError::TooManyRegisters);
+        }
+        let register = self.free_register;
+        self.free_register += 1;
+        self.register_count = self.register_count.max(self.free_register);
+        Ok(register as u8)
+    }
+    fn constant(&mut self, value: Value) -> Result<u16, CompileError> {
+        self.constants.push(value);
+        u16::try_from(self.constants.len() - 1).map_err(|_| CompileError::TooManyConstants)
+    }
+    fn name(&mut self, name: String) -> Result<u8, CompileError> {
+        let index = match self.names.iter().position(|existing| *existing == name) {
+            Some(index) => index,
+            None => {
+                self.names.push(name);
+                self.names.len() - 1
+            }
+        };
+        u8::try_from(index).map_err(|_| CompileError::TooManyNames)
+    }
+    // jumps are emitted with a zero offset and patched once we know where they land
+    fn patch_jump(&mut self, jump: usize) -> Result<(), CompileError> {
+        let offset = i16::try_from(self.instructions.len() as isize - (jump as isize + 1)).map_err(|_| CompileError::JumpTooFar)?;
+        let instruction = self.instructions[jump];
+        self.instructions[jump] = Instruction::asbx(instruction.opcode(), instruction.a() as u8, offset);
+        Ok(())
+    }
+    fn find_variable(&self, identifier: &Identifier) -> Option<u8> {
+        for scope in self.scopes.iter().rev() {
+            if let Some((_, register)) = scope.iter().rev().find(|(name, _)| name == identifier) {
+                return Some(*register);
+            }
+        }
+        None
+    }
+    // a name that isn't a variable is read from the globals when it runs
+    fn variable(&mut self, identifier: Identifier) -> Result<u8, CompileError> {
+        if let Some(register) = self.find_variable(&identifier) {
+            return Ok(register);
+        }
+        let name = self.name(identifier)?;
+        let target = self.allocate()?;
+        self.emit(Instruction::abx(GET_GLOBAL, target, name as u16));
+        Ok(target)
+    }
+    fn is_global_scope(&self) -> bool {
+        self.scopes.len() == 1
+    }
+    fn push_scope(&mut self) {
+        self.scopes.push(vec![]);
+    }
+    fn pop_scope(&mut self) {
+        self.scopes.pop();
+    }
+    // compiles a block into a fresh register which is left allocated
+    fn block(&mut self, block: Block) -> Result<u8, CompileError> {
+        let target = self.allocate()?;
+        match block {
+            Block::WithExp(statements, exp) => {
+                for statement in statements {
+                    self.statement(*statement)?;
+                }
+                self.expression_into(*exp, target)?;
+            }
+            Block::WithoutExp(statements) => {
+                for statement in statements {
+                    self.statement(*statement)?;
+                }
+                self.emit(Instruction::abc(LOAD_NIL, target, 0, 0));
+            }
+        }
+        Ok(target)
+    }
+    fn statement(&mut self, statement: Statement) -> Result<(), CompileError> {
+        let mark = self.free_register;
+        match statement {
+            // a `let` at the top level defines a global, so later chunks can see it
+            Statement::LetStatement(LetStatement { identifier, exp, .. }) if self.is_global_scope() => {
+                let register = self.expression(*exp)?;
+                let name = self.name(identifier)?;
+                self.emit(Instruction::abx(DEFINE_GLOBAL, register, name as u16));
+            }
+            Statement::LetStatement(LetStatement { identifier, exp, .. }) => {
+                let register = self.allocate()?;
+                self.expression_into(*exp, register)?;
+                self.scopes.last_mut().unwrap().push((identifier, register));
+                // the variable stays, anything the expression used above it goes
+                self.free_register = mark + 1;
+                return Ok(());
+            }
+            Statement::ExpStatement(exp) => {
+                self.expression(*exp)?;
+            }
+            Statement::Block(statements) => {
+                self.push_scope();
+                for statement in statements {
+                    self.statement(*statement)?;
+                }
+                self.pop_scope();
+            }
+            Statement::FnDef(_) => return Err(CompileError::Unsupported("function definitions")),
+            Statement::TryCatch(_) | Statement::Throw(_) => return Err(CompileError::Unsupported("exceptions")),
+            Statement::For(_) => return Err(CompileError::Unsupported("coroutines")),
+        }
+        self.free_register = mark;
+        Ok(())
+    }
+    // puts the result of the expression in `target`
+    fn expression_into(&mut self, exp: Exp, target: u8) -> Result<(), CompileError> {
+        let mark = self.free_register;
+        let register = self.expression(exp)?;
+        if register != target {
+            self.emit(Instruction::abc(MOVE, target, register, 0));
+        }
+        self.free_register = mark;
+        Ok(())
+    }
+    // returns the register holding the result, which can be a variable's own register so don't write to it
+    fn expression(&mut self, exp: Exp) -> Result<u8, CompileError> {
+        match exp {
+            Exp::Variable(identifier) => self.variable(identifier),
+            Exp::Value(value) => self.value(value),
+            Exp::BinaryOperation(binary_operation) => self.binary_operation(binary_operation),
+            Exp::FnCall(fn_call) => self.fn_call(fn_call),
+            Exp::Yield(_) => Err(CompileError::Unsupported("coroutines")),
+            Exp::Block(statements, exp) => {
+                let target = self.allocate()?;
+                self.push_scope();
+                for statement in statements {
+                    self.statement(*statement)?;
+                }
+                self.expression_into(*exp, target)?;
+                self.pop_scope();
+                self.free_register = target as usize + 1;
+                Ok(target)
+            }
+        }
+    }
+    fn value(&mut self, value: ir::Value) -> Result<u8, CompileError> {
+        let target = self.allocate()?;
+        match value {
+            ir::Value::Integer(number) => {
+                let constant = self.constant(Value::Number(number))?;
+                self.emit(Instruction::abx(LOAD_CONSTANT, target, constant));
+            }
+            ir::Value::Float(number) => {
+                let constant = self.constant(Value::Float(number))?;
+                self.emit(Instruction::abx(LOAD_CONSTANT, target, constant));
+            }
+            ir::Value::String(_) => return Err(CompileError::Unsupported("strings")),
+            ir::Value::Table(ir::Table(entries)) => {
+                self.emit(Instruction::abc(NEW_TABLE, target, 0, 0));
+                for (key, exp) in entries {
+                    let mark = self.free_register;
+                    let value = self.expression(*exp)?;
+                    match key {
+                        TableKey::HasString(name) => {
+                            let name = self.name(name)?;
+                            self.emit(Instruction::abc(SET_FIELD, target, name, value));
+                        }
+                        TableKey::NoString => {
+                            self.emit(Instruction::abc(APPEND, target, value, 0));
+                        }
+                    }
+                    self.free_register = mark;
+                }
+            }
+        }
+        Ok(target)
+    }
+    fn binary_operation(&mut self, binary_operation: BinaryOperation) -> Result<u8, CompileError> {
+        let BinaryOperation { left_hand_side, operator, right_hand_side } = binary_operation;
+        // `and` and `or` only evaluate the right hand side if they have to
+        if let BinaryOperator::And | BinaryOperator::Or = operator {
+            let target = self.allocate()?;
+            self.expression_into(*left_hand_side, target)?;
+            let opcode = match operator {
+                BinaryOperator::And => JUMP_IF_NOT,
+                _ => JUMP_IF,
+            };
+            let jump = self.emit(Instruction::asbx(opcode, target, 0));
+            self.expression_into(*right_hand_side, target)?;
+            self.patch_jump(jump)?;
+            return Ok(target);
+        }
+        let mark = self.free_register;
+        let lhs = self.expression(*left_hand_side)?;
+        let rhs = self.expression(*right_hand_side)?;
+        // the operands are read before the result is written, so the result can reuse their registers
+        self.free_register = mark;
+        let target = self.allocate()?;
+        let opcode = match operator {
+            BinaryOperator::Add => ADD,
+            BinaryOperator::Subtract => SUBTRACT,
+            BinaryOperator::Multiply => MULTIPLY,
+            BinaryOperator::Divide => DIVIDE,
+            BinaryOperator::EqualsEquals => EQUAL,
+            BinaryOperator::And | BinaryOperator::Or => unreachable!(),
+        };
+        self.emit(Instruction::abc(opcode, target, lhs, rhs));
+        Ok(target)
+    }
+    fn fn_call(&mut self, fn_call: FnCall) -> Result<u8, CompileError> {
+        let FnCall { identifier, args, .. } = fn_call;
+        if identifier != "print" {
+            return Err(CompileError::Unsupported("calling functions other than `print`"));
+        }
+        for arg in args {
+            let mark = self.free_register;
+            let register = self.expression(*arg)?;
+            self.emit(Instruction::abc(PRINT, register, 0, 0));
+            self.free_register = mark;
+        }
+        let target = self.allocate()?;
+        self.emit(Instruction::abc(LOAD_NIL, target, 0, 0));
+        Ok(target)
+    }
+}
@@ -0,0 +1,114 @@
+use std::fmt::{Debug, Formatter};
+
+/*
+Every instruction is 32 bits wide, the opcode is the low byte and the rest is operands.
+
+    ABC     | C: 8 | B: 8 | A: 8 | opcode: 8 |
+    ABx     |     Bx: 16  | A: 8 | opcode: 8 |
+    AsBx    |    sBx: 16  | A: 8 | opcode: 8 |
+
+A, B and C are registers unless said otherwise, Bx is an unsigned index and sBx is a signed
+jump offset relative to the instruction after the jump.
+ */
+pub const LOAD_CONSTANT: u8 = 0x01; // R[A] = constants[Bx]
+pub const LOAD_NIL: u8 = 0x02; // R[A] = nil
+pub const LOAD_TRUE: u8 = 0x03; // R[A] = true
+pub const LOAD_FALSE: u8 = 0x04; // R[A] = false
+pub const MOVE: u8 = 0x05; // R[A] = R[B]
+pub const ADD: u8 = 0x06; // R[A] = R[B] + R[C]
+pub const SUBTRACT: u8 = 0x07; // R[A] = R[B] - R[C]
+pub const MULTIPLY: u8 = 0x08; // R[A] = R[B] * R[C]
+pub const DIVIDE: u8 = 0x09; // R[A] = R[B] / R[C]
+pub const EQUAL: u8 = 0x0A; // R[A] = R[B] == R[C]
+pub const LESS: u8 = 0x0B; // R[A] = R[B] < R[C]
+pub const LESS_EQUAL: u8 = 0x0C; // R[A] = R[B] <= R[C]
+pub const NOT: u8 = 0x0D; // R[A] = !R[B]
+pub const JUMP: u8 = 0x0E; // pc += sBx
+pub const JUMP_IF: u8 = 0x0F; // if R[A] { pc += sBx }
+pub const JUMP_IF_NOT: u8 = 0x10; // if !R[A] { pc += sBx }
+pub const NEW_TABLE: u8 = 0x11; // R[A] = []
+pub const APPEND: u8 = 0x12; // R[A].push(R[B])
+pub const GET_INDEX: u8 = 0x13; // R[A] = R[B][R[C]]
+pub const SET_INDEX: u8 = 0x14; // R[A][R[B]] = R[C]
+pub const GET_FIELD: u8 = 0x15; // R[A] = R[B].names[C], C is an index into the chunk's names
+pub const SET_FIELD: u8 = 0x16; // R[A].names[B] = R[C], B is an index into the chunk's names
+pub const PRINT: u8 = 0x17; // print(R[A])
+pub const RETURN: u8 = 0x18; // return R[A]
+pub const GET_GLOBAL: u8 = 0x19; // R[A] = globals[names[Bx]]
+pub const SET_GLOBAL: u8 = 0x1A; // globals[names[Bx]] = R[A], the global has to be defined already
+pub const DEFINE_GLOBAL: u8 = 0x1B; // globals[names[Bx]] = R[A], defining it if it isn't
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct Instruction(u32);
+impl Instruction {
+    pub fn abc(opcode: u8, a: u8, b: u8, c: u8) -> Self {
+        Self(opcode as u32 | (a as u32) << 8 | (b as u32) << 16 | (c as u32) << 24)
+    }
+    pub fn abx(opcode: u8, a: u8, bx: u16) -> Self {
+        Self(opcode as u32 | (a as u32) << 8 | (bx as u32) << 16)
+    }
+    pub fn asbx(opcode: u8, a: u8, sbx: i16) -> Self {
+        Self::abx(opcode, a, sbx as u16)
+    }
+    pub fn opcode(self) -> u8 {
+        self.0 as u8
+    }
+    pub fn a(self) -> usize {
+        (self.0 >> 8) as u8 as usize
+    }
+    pub fn b(self) -> usize {
+        (self.0 >> 16) as u8 as usize
+    }
+    pub fn c(self) -> usize {
+        (self.0 >> 24) as u8 as usize
+    }
+    pub fn bx(self) -> usize {
+        (self.0 >> 16) as u16 as usize
+    }
+    pub fn sbx(self) -> isize {
+        (self.0 >> 16) as u16 as i16 as isize
+    }
+    pub fn to_bits(self) -> u32 {
+        self.0
+    }
+    pub fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+}
+impl Debug for Instruction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self.opcode() {
+            LOAD_CONSTANT => "LOAD_CONSTANT",
+            LOAD_NIL => "LOAD_NIL",
+            LOAD_TRUE => "LOAD_TRUE",
+            LOAD_FALSE => "LOAD_FALSE",
+            MOVE => "MOVE",
+            ADD => "ADD",
+            SUBTRACT => "SUBTRACT",
+            MULTIPLY => "MULTIPLY",
+            DIVIDE => "DIVIDE",
+            EQUAL => "EQUAL",
+            LESS => "LESS",
+            LESS_EQUAL => "LESS_EQUAL",
+            NOT => "NOT",
+            JUMP => "JUMP",
+            JUMP_IF => "JUMP_IF",
+            JUMP_IF_NOT => "JUMP_IF_NOT",
+            NEW_TABLE => "NEW_TABLE",
+            APPEND => "APPEND",
+            GET_INDEX => "GET_INDEX",
+            SET_INDEX => "SET_INDEX",
+            GET_FIELD => "GET_FIELD",
+            SET_FIELD => "SET_FIELD",
+            PRINT => "PRINT",
+            RETURN => "RETURN",
+            _ => return write!(f, "INVALID({:#010x})", self.0),
+        };
+        match self.opcode() {
+            LOAD_CONSTANT => write!(f, "{} {} {}", name, self.a(), self.bx()),
+            JUMP => write!(f, "{} {}", name, self.sbx()),
+            JUMP_IF | JUMP_IF_NOT => write!(f, "{} {} {}", name, self.a(), self.sbx()),
+            _ => write!(f, "{} {} {} {}", name, self.a(), self.b(), self.c()),
+        }
+    }
+}
@@ -1,10 +1,297 @@
-mod value;
+pub mod value;
 mod table;
+pub mod instruction;
+pub mod compiler;
 
+use std::cmp::Ordering;
 use value::Value;
-pub fn test_vm() {
+use table::Table;
+use instruction::*;
+use crate::second_attempt::ir::{BinaryOperation, BinaryOperator, Block, Exp, File, FnCall, LetStatement, Statement};
+use crate::second_attempt::ir;
+use crate::second_attempt::numeric::{ArithmeticOperator, Numeric};
+use crate::second_attempt::pretty::{self, Layout, Shape, Tables};
+use crate::second_attempt::vm_error::{VmError, VmErrorKind};
+use crate::second_attempt::globals::Globals;
 
+pub fn test_vm() {
+    // let x = 1; print(x + 2.5); [x, y: x * 2]
+    let file = File::Block(Block::WithExp(
+        vec![
+            Box::new(Statement::LetStatement(LetStatement {
+                identifier: "x".to_string(),
+                exp: Box::new(Exp::Value(ir::Value::Integer(1))),
+                span: 0..0,
+            })),
+            Box::new(Statement::ExpStatement(Box::new(Exp::FnCall(FnCall {
+                identifier: "print".to_string(),
+                args: vec![Box::new(Exp::BinaryOperation(BinaryOperation {
+                    left_hand_side: Box::new(Exp::Variable("x".to_string())),
+                    operator: BinaryOperator::Add,
+                    right_hand_side: Box::new(Exp::Value(ir::Value::Float(2.5))),
+                }))],
+                span: 0..0,
+            })))),
+        ],
+        Box::new(Exp::Value(ir::Value::Table(ir::Table(vec![
+            (ir::TableKey::NoString, Box::new(Exp::Variable("x".to_string()))),
+            (ir::TableKey::HasString("y".to_string()), Box::new(Exp::BinaryOperation(BinaryOperation {
+                left_hand_side: Box::new(Exp::Variable("x".to_string())),
+                operator: BinaryOperator::Multiply,
+                right_hand_side: Box::new(Exp::Value(ir::Value::Integer(2))),
+            }))),
+        ])))),
+    ));
+    let chunk = compiler::compile(file).unwrap();
+    let mut vm = Vm::default();
+    match vm.run(&chunk) {
+        Ok(value) => println!("finished with {:?}", value),
+        Err(err) => println!("runtime error: {}", err),
+    }
 }
+
 pub struct Chunk {
-    pointer: usize,
-}
\ No newline at end of file
+    instructions: Vec<Instruction>,
+    constants: Vec<Value>,
+    // names used by GET_FIELD, SET_FIELD and the global instructions
+    names: Vec<String>,
+    register_count: usize,
+}
+impl Chunk {
+    pub fn new(instructions: Vec<Instruction>, constants: Vec<Value>, names: Vec<String>, register_count: usize) -> Self {
+        Self {
+            instructions,
+            constants,
+            names,
+            register_count
+        }
+    }
+    pub fn get_instructions(&self) -> &Vec<Instruction> {
+        &self.instructions
+    }
+    pub fn get_constants(&self) -> &Vec<Value> {
+        &self.constants
+    }
+    pub fn get_names(&self) -> &Vec<String> {
+        &self.names
+    }
+    pub fn get_register_count(&self) -> usize {
+        self.register_count
+    }
+    // checks every operand once up front so the run loop can index registers directly
+    fn validate(&self) -> Result<(), VmError> {
+        let len = self.instructions.len() as isize;
+        for (ip, instruction) in self.instructions.iter().enumerate() {
+            let invalid = |reason: &str| VmErrorKind::InvalidChunk(format!("{:?}: {}", instruction, reason)).at(0, ip);
+            let register = |register: usize| {
+                if register >= self.register_count {
+                    return Err(invalid("register out of range"));
+                }
+                Ok(())
+            };
+            let jump = |offset: isize| {
+                let target = ip as isize + 1 + offset;
+                if target < 0 || target > len {
+                    return Err(invalid("jump out of range"));
+                }
+                Ok(())
+            };
+            match instruction.opcode() {
+                LOAD_CONSTANT => {
+                    register(instruction.a())?;
+                    if instruction.bx() >= self.constants.len() {
+                        return Err(invalid("constant out of range"));
+                    }
+                }
+                LOAD_NIL | LOAD_TRUE | LOAD_FALSE | NEW_TABLE | PRINT | RETURN => register(instruction.a())?,
+                MOVE | NOT | APPEND => {
+                    register(instruction.a())?;
+                    register(instruction.b())?;
+                }
+                ADD | SUBTRACT | MULTIPLY | DIVIDE | EQUAL | LESS | LESS_EQUAL | GET_INDEX | SET_INDEX => {
+                    register(instruction.a())?;
+                    register(instruction.b())?;
+                    register(instruction.c())?;
+                }
+                GET_FIELD | SET_FIELD => {
+                    let (name, value) = match instruction.opcode() {
+                        GET_FIELD => (instruction.c(), instruction.b()),
+                        _ => (instruction.b(), instruction.c()),
+                    };
+                    register(instruction.a())?;
+                    register(value)?;
+                    if name >= self.names.len() {
+                        return Err(invalid("name out of range"));
+                    }
+                }
+                GET_GLOBAL | SET_GLOBAL | DEFINE_GLOBAL => {
+                    register(instruction.a())?;
+                    if instruction.bx() >= self.names.len() {
+                        return Err(invalid("name out of range"));
+                    }
+                }
+                JUMP => jump(instruction.sbx())?,
+                JUMP_IF | JUMP_IF_NOT => {
+                    register(instruction.a())?;
+                    jump(instruction.sbx())?;
+                }
+                opcode => return Err(VmErrorKind::InvalidOpcode(opcode).at(0, ip)),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct Vm {
+    registers: Vec<Value>,
+    tables: Vec<Table>,
+    globals: Globals<Value>,
+    // the running chunk's names interned, so global instructions index straight into the globals
+    global_ids: Vec<usize>,
+}
+impl Vm {
+    // globals and tables outlive the run, so chunks run one after another can share them
+    pub fn run(&mut self, chunk: &Chunk) -> Result<Value, VmError> {
+        chunk.validate()?;
+        self.registers.clear();
+        self.registers.resize(chunk.register_count, Value::Nil);
+        self.global_ids = chunk.names.iter().map(|name| self.globals.intern(name.as_str())).collect();
+        let mut pc = 0;
+        while pc < chunk.instructions.len() {
+            let instruction = chunk.instructions[pc];
+            let ip = pc;
+            pc += 1;
+            match self.step(chunk, instruction, &mut pc) {
+                Ok(None) => {}
+                Ok(Some(value)) => return Ok(value),
+                Err(kind) => return Err(kind.at(0, ip)),
+            }
+        }
+        Ok(Value::Nil)
+    }
+    // returns the value once the chunk has returned
+    #[inline(always)]
+    fn step(&mut self, chunk: &Chunk, instruction: Instruction, pc: &mut usize) -> Result<Option<Value>, VmErrorKind> {
+        let a = instruction.a();
+        match instruction.opcode() {
+            LOAD_CONSTANT => self.registers[a] = chunk.constants[instruction.bx()],
+            LOAD_NIL => self.registers[a] = Value::Nil,
+            LOAD_TRUE => self.registers[a] = Value::Boolean(true),
+            LOAD_FALSE => self.registers[a] = Value::Boolean(false),
+            MOVE => self.registers[a] = self.registers[instruction.b()],
+            ADD => self.arithmetic(instruction, ArithmeticOperator::Add)?,
+            SUBTRACT => self.arithmetic(instruction, ArithmeticOperator::Subtract)?,
+            MULTIPLY => self.arithmetic(instruction, ArithmeticOperator::Multiply)?,
+            DIVIDE => self.arithmetic(instruction, ArithmeticOperator::Divide)?,
+            EQUAL => self.registers[a] = Value::Boolean(self.registers[instruction.b()].equals(self.registers[instruction.c()])),
+            LESS => self.comparison(instruction, |ordering| ordering == Ordering::Less)?,
+            LESS_EQUAL => self.comparison(instruction, |ordering| ordering != Ordering::Greater)?,
+            NOT => self.registers[a] = Value::Boolean(!self.registers[instruction.b()].get_truthy()),
+            JUMP => *pc = (*pc as isize + instruction.sbx()) as usize,
+            JUMP_IF => {
+                if self.registers[a].get_truthy() {
+                    *pc = (*pc as isize + instruction.sbx()) as usize;
+                }
+            }
+            JUMP_IF_NOT => {
+                if !self.registers[a].get_truthy() {
+                    *pc = (*pc as isize + instruction.sbx()) as usize;
+                }
+            }
+            NEW_TABLE => {
+                self.tables.push(Table::new());
+                self.registers[a] = Value::Table(self.tables.len() - 1);
+            }
+            APPEND => {
+                let value = self.registers[instruction.b()];
+                self.table_mut(self.registers[a])?.push(value);
+            }
+            GET_INDEX => {
+                let index = Self::position(self.registers[instruction.c()])?;
+                // missing entries read as nil
+                let value = self.table(self.registers[instruction.b()])?.get_with_index(index).copied().unwrap_or_default();
+                self.registers[a] = value;
+            }
+            SET_INDEX => {
+                let index = Self::position(self.registers[instruction.b()])?;
+                let value = self.registers[instruction.c()];
+                self.table_mut(self.registers[a])?.set_with_index(index, value);
+            }
+            GET_FIELD => {
+                let name = chunk.names[instruction.c()].as_str();
+                let value = self.table(self.registers[instruction.b()])?.get_with_name(name).copied().unwrap_or_default();
+                self.registers[a] = value;
+            }
+            SET_FIELD => {
+                let name = chunk.names[instruction.b()].clone();
+                let value = self.registers[instruction.c()];
+                self.table_mut(self.registers[a])?.insert_with_name(name, value);
+            }
+            GET_GLOBAL => self.registers[a] = self.globals.get(self.global_ids[instruction.bx()])?,
+            SET_GLOBAL => self.globals.set(self.global_ids[instruction.bx()], self.registers[a])?,
+            DEFINE_GLOBAL => self.globals.define(self.global_ids[instruction.bx()], self.registers[a])?,
+            PRINT => println!("{}", self.format_value(self.registers[a], Layout::Compact)),
+            RETURN => return Ok(Some(self.registers[a])),
+            opcode => return Err(VmErrorKind::InvalidOpcode(opcode)),
+        }
+        Ok(None)
+    }
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        self.globals.get_by_name(name)
+    }
+    // defines the global if it doesn't exist yet
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        self.globals.set_by_name(name, value);
+    }
+    // tables are written out entry by entry, see `pretty`, anything else the way it displays
+    pub fn format_value(&self, value: Value, layout: Layout) -> String {
+        pretty::print(self, value, layout)
+    }
+    fn numeric(value: Value) -> Result<Numeric, VmErrorKind> {
+        value.get_numeric().ok_or(VmErrorKind::TypeError { expected: "number", found: value.type_name() })
+    }
+    fn position(value: Value) -> Result<usize, VmErrorKind> {
+        match value {
+            Value::Number(number) if number >= 0 => Ok(number as usize),
+            value => Err(VmErrorKind::TypeError { expected: "positive number", found: value.type_name() }),
+        }
+    }
+    fn table(&self, value: Value) -> Result<&Table, VmErrorKind> {
+        match value {
+            Value::Table(index) => self.tables.get(index).ok_or(VmErrorKind::BadTableHandle(index)),
+            value => Err(VmErrorKind::TypeError { expected: "table", found: value.type_name() }),
+        }
+    }
+    fn table_mut(&mut self, value: Value) -> Result<&mut Table, VmErrorKind> {
+        match value {
+            Value::Table(index) => self.tables.get_mut(index).ok_or(VmErrorKind::BadTableHandle(index)),
+            value => Err(VmErrorKind::TypeError { expected: "table", found: value.type_name() }),
+        }
+    }
+    fn arithmetic(&mut self, instruction: Instruction, operator: ArithmeticOperator) -> Result<(), VmErrorKind> {
+        let lhs = Self::numeric(self.registers[instruction.b()])?;
+        let rhs = Self::numeric(self.registers[instruction.c()])?;
+        self.registers[instruction.a()] = Value::from_numeric(lhs.operate(rhs, operator)?);
+        Ok(())
+    }
+    // NaN is unordered, so every ordering test against it is false
+    fn comparison(&mut self, instruction: Instruction, test: fn(Ordering) -> bool) -> Result<(), VmErrorKind> {
+        let lhs = Self::numeric(self.registers[instruction.b()])?;
+        let rhs = Self::numeric(self.registers[instruction.c()])?;
+        self.registers[instruction.a()] = Value::Boolean(lhs.compare(rhs).map_or(false, test));
+        Ok(())
+    }
+}
+impl Tables for Vm {
+    type Value = Value;
+    fn shape(&self, value: Value) -> Shape<Value> {
+        let Value::Table(index) = value else {
+            return Shape::Text(value.to_string());
+        };
+        match self.tables.get(index) {
+            Some(table) => Shape::Table(index, table.iter().map(|(name, value)| (name.map(str::to_string), *value)).collect()),
+            None => Shape::Text(format!("<bad table {}>", index)),
+        }
+    }
+}
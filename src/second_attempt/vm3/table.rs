@@ -1,18 +1,106 @@
+use indexmap::IndexMap;
+use crate::second_attempt::vm3::value::Value;
+
+// Every entry in a table has a position, and named entries can also be reached by their name,
+// so `["yo", a: 1.2][1]` and `.a` are the same slot.
+// Values live in order in the array part, the hash part maps names to their position in it.
 pub struct Table {
-    inner: Vec<(Option<String>, Value)>,
+    array: Vec<Value>,
+    hash: IndexMap<String, usize>,
 }
 impl Table {
     pub fn new() -> Self {
         Self::default()
     }
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            array: Vec::with_capacity(capacity),
+            hash: IndexMap::default()
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.array.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.array.is_empty()
+    }
+    // appends an unnamed entry and returns its position
+    pub fn push(&mut self, value: Value) -> usize {
+        self.array.push(value);
+        self.array.len() - 1
+    }
+    // overwrites the entry if the name is already there, otherwise appends a new one
+    pub fn insert_with_name(&mut self, name: String, value: Value) -> usize {
+        match self.hash.get(&name) {
+            Some(index) => {
+                self.array[*index] = value;
+                *index
+            }
+            None => {
+                let index = self.push(value);
+                self.hash.insert(name, index);
+                index
+            }
+        }
+    }
+    // setting past the end grows the table, filling the gap with nil
+    pub fn set_with_index(&mut self, index: usize, value: Value) {
+        if index >= self.array.len() {
+            self.array.resize(index + 1, Value::Nil);
+        }
+        self.array[index] = value;
+    }
     pub fn get_with_index(&self, index: usize) -> Option<&Value> {
-        self.inner.get(index).unwrap()
+        self.array.get(index)
+    }
+    pub fn get_with_name(&self, name: &str) -> Option<&Value> {
+        self.array.get(*self.hash.get(name)?)
+    }
+    pub fn get_with_index_mut(&mut self, index: usize) -> Option<&mut Value> {
+        self.array.get_mut(index)
+    }
+    pub fn get_with_name_mut(&mut self, name: &str) -> Option<&mut Value> {
+        self.array.get_mut(*self.hash.get(name)?)
+    }
+    pub fn position_of(&self, name: &str) -> Option<usize> {
+        self.hash.get(name).copied()
+    }
+    // everything after the removed entry moves down one position, names included
+    pub fn remove_with_index(&mut self, index: usize) -> Option<Value> {
+        if index >= self.array.len() {
+            return None;
+        }
+        let value = self.array.remove(index);
+        self.hash.retain(|_, position| *position != index);
+        for position in self.hash.values_mut() {
+            if *position > index {
+                *position -= 1;
+            }
+        }
+        Some(value)
+    }
+    pub fn remove_with_name(&mut self, name: &str) -> Option<Value> {
+        let index = self.position_of(name)?;
+        self.remove_with_index(index)
+    }
+    pub fn clear(&mut self) {
+        self.array.clear();
+        self.hash.clear();
+    }
+    // entries in position order, with their name if they have one
+    pub fn iter(&self) -> impl Iterator<Item = (Option<&str>, &Value)> {
+        let mut names = vec![None; self.array.len()];
+        for (name, position) in &self.hash {
+            names[*position] = Some(name.as_str());
+        }
+        names.into_iter().zip(self.array.iter())
     }
 }
 impl Default for Table {
     fn default() -> Self {
         Self {
-            inner: vec![]
+            array: vec![],
+            hash: IndexMap::default()
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,257 @@
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+use crate::second_attempt::vm2;
+use crate::second_attempt::vm2::bytecode::{convert_bytecode_array, Bytecode};
+use crate::second_attempt::vm2::bytecode::Bytecode::*;
+use crate::second_attempt::vm3;
+use crate::second_attempt::vm3::instruction::*;
+use crate::second_attempt::stack::Stack;
+use crate::second_attempt::vm2::misc::Value;
+
+const ITERATIONS: i64 = 1_000_000;
+const RUNS: u32 = 5;
+
+// runs the same programs on the stack vm (vm2) and the register vm (vm3) and prints how long each took
+pub fn bench_vms() {
+    println!("{} iterations, best of {} runs", ITERATIONS, RUNS);
+    compare("arithmetic loop", vm2_arithmetic_loop, vm3_arithmetic_loop);
+    compare("table loop", vm2_table_loop, vm3_table_loop);
+    println!();
+    // shallow stays in the first segment, deep crosses segment boundaries on the way up and down
+    compare_stack("shallow push/pop", 4);
+    compare_stack("deep push/pop", 1000);
+    #[cfg(feature = "jit")]
+    {
+        println!();
+        compare_jit("arithmetic loop", vm2_arithmetic_loop);
+        compare_jit("table loop", vm2_table_loop);
+        compare_jit("overflow loop", vm2_overflow_loop);
+        compare_jit("division by zero", vm2_division_by_zero);
+    }
+}
+// runs a vm2 chunk interpreted and with the jit, they have to end the same way, errors included
+#[cfg(feature = "jit")]
+fn compare_jit(name: &str, chunk: fn() -> vm2::Chunk) {
+    let run = |jit: bool| best_of(|| {
+        let mut vm = vm2::Vm::default();
+        vm.set_jit(jit);
+        vm.load(chunk()).unwrap();
+        match vm.run() {
+            Ok(value) => value.to_string(),
+            Err(err) => err.to_string(),
+        }
+    });
+    let (interpreted_time, interpreted_result) = run(false);
+    let (jit_time, jit_result) = run(true);
+    assert_eq!(interpreted_result, jit_result, "{} gave different results with the jit", name);
+    println!("{:<16} vm2 {:>10.2?}  jit {:>10.2?}  ({:.2}x)  = {}", name, interpreted_time, jit_time,
+             interpreted_time.as_secs_f64() / jit_time.as_secs_f64(), jit_result);
+}
+fn compare(name: &str, vm2_chunk: fn() -> vm2::Chunk, vm3_chunk: fn() -> vm3::Chunk) {
+    let (vm2_time, vm2_result) = best_of(|| {
+        let mut vm = vm2::Vm::default();
+        vm.load(vm2_chunk()).unwrap();
+        vm.run().unwrap().to_string()
+    });
+    let chunk = vm3_chunk();
+    let (vm3_time, vm3_result) = best_of(|| vm3::Vm::default().run(&chunk).unwrap().to_string());
+    assert_eq!(vm2_result, vm3_result, "{} gave different results", name);
+    println!("{:<16} vm2 {:>10.2?}  vm3 {:>10.2?}  ({:.2}x)  = {}", name, vm2_time, vm3_time,
+             vm2_time.as_secs_f64() / vm3_time.as_secs_f64(), vm3_result);
+}
+// the segmented stack the vms use against a plain fixed array, `depth` values pushed then popped each iteration
+fn compare_stack(name: &str, depth: usize) {
+    let iterations = ITERATIONS as usize / depth;
+    let (segmented_time, segmented_result) = best_of(|| {
+        let mut stack = Stack::<Value>::default();
+        let mut sum = 0;
+        for _ in 0..iterations {
+            for value in 0..depth {
+                stack.push(black_box(Value::Number(value as i64))).unwrap();
+            }
+            for _ in 0..depth {
+                sum += stack.pop().unwrap().get_number().unwrap();
+            }
+        }
+        sum.to_string()
+    });
+    let (fixed_time, fixed_result) = best_of(|| {
+        let mut stack = FixedStack::default();
+        let mut sum = 0;
+        for _ in 0..iterations {
+            for value in 0..depth {
+                stack.push(black_box(Value::Number(value as i64))).unwrap();
+            }
+            for _ in 0..depth {
+                sum += stack.pop().unwrap().get_number().unwrap();
+            }
+        }
+        sum.to_string()
+    });
+    assert_eq!(segmented_result, fixed_result, "{} gave different results", name);
+    println!("{:<16} segmented {:>10.2?}  fixed {:>10.2?}  ({:.2}x)", name, segmented_time, fixed_time,
+             segmented_time.as_secs_f64() / fixed_time.as_secs_f64());
+}
+// what the vms' stacks used to be, big enough for the deep benchmark
+struct FixedStack {
+    stack: [Value; 1024],
+    len: usize,
+}
+impl Default for FixedStack {
+    fn default() -> Self {
+        Self {
+            stack: [Value::Nil; 1024],
+            len: 0,
+        }
+    }
+}
+impl FixedStack {
+    fn push(&mut self, value: Value) -> Option<()> {
+        if self.len == self.stack.len() {
+            return None;
+        }
+        self.stack[self.len] = value;
+        self.len += 1;
+        Some(())
+    }
+    fn pop(&mut self) -> Option<Value> {
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+        Some(self.stack[self.len])
+    }
+}
+fn best_of(mut run: impl FnMut() -> String) -> (Duration, String) {
+    let mut best = Duration::MAX;
+    let mut result = String::new();
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        result = run();
+        best = best.min(start.elapsed());
+    }
+    (best, result)
+}
+// byte offset of `code[index]` once encoded
+fn offset_of(code: &[Bytecode], index: usize) -> usize {
+    code[..index].iter().map(Bytecode::size).sum()
+}
+
+// let sum = 0; let i = 0; while i < ITERATIONS { sum = sum + i; i = i + 1 }; sum
+fn vm2_arithmetic_loop() -> vm2::Chunk {
+    use vm2::misc::Value::Number;
+    // locals from the top: i, sum
+    let mut code = vec![
+        Constant(0), PushLocal,
+        Constant(0), PushLocal,
+        // loop:
+        PeekLocal(0), Constant(1), TestLess, TestNot, JumpIf(0),
+        PeekLocal(1), PeekLocal(0), Add, SetLocal(1),
+        PeekLocal(0), Constant(2), Add, SetLocal(0),
+        Jump(0),
+        // end:
+        PeekLocal(1),
+    ];
+    let (start, end) = (offset_of(&code, 4), offset_of(&code, 18));
+    code[8] = JumpIf(end);
+    code[17] = Jump(start);
+    vm2::Chunk::new(convert_bytecode_array(code), vec![Number(0), Number(ITERATIONS), Number(1)])
+}
+// let sum = 0; let i = 0; while i < ITERATIONS { sum = sum + i * i * i; i = i + 1 }; sum
+// sum overflows partway through and carries on as a float, which compiled code has to hand back
+#[cfg(feature = "jit")]
+fn vm2_overflow_loop() -> vm2::Chunk {
+    use vm2::misc::Value::Number;
+    // locals from the top: i, sum
+    let mut code = vec![
+        Constant(0), PushLocal,
+        Constant(0), PushLocal,
+        // loop:
+        PeekLocal(0), Constant(1), TestLess, TestNot, JumpIf(0),
+        PeekLocal(1), PeekLocal(0), PeekLocal(0), Multiply, PeekLocal(0), Multiply, Add, SetLocal(1),
+        PeekLocal(0), Constant(2), Add, SetLocal(0),
+        Jump(0),
+        // end:
+        PeekLocal(1),
+    ];
+    let (start, end) = (offset_of(&code, 4), offset_of(&code, 22));
+    code[8] = JumpIf(end);
+    code[21] = Jump(start);
+    vm2::Chunk::new(convert_bytecode_array(code), vec![Number(0), Number(ITERATIONS), Number(1)])
+}
+// let i = 0; while true { i = i + 1; ITERATIONS / (ITERATIONS - i) }, which fails once i gets there
+#[cfg(feature = "jit")]
+fn vm2_division_by_zero() -> vm2::Chunk {
+    use vm2::misc::Value::{Boolean, Number};
+    // locals from the top: i
+    let mut code = vec![
+        Constant(0), PushLocal,
+        // loop:
+        PeekLocal(0), Constant(2), Add, SetLocal(0),
+        Constant(1), Constant(1), PeekLocal(0), Subtract, Divide, Pop,
+        Constant(3), JumpIf(0),
+    ];
+    let start = offset_of(&code, 2);
+    code[13] = JumpIf(start);
+    vm2::Chunk::new(convert_bytecode_array(code), vec![Number(0), Number(ITERATIONS), Number(1), Boolean(true)])
+}
+fn vm3_arithmetic_loop() -> vm3::Chunk {
+    use vm3::value::Value::Number;
+    // r0 = sum, r1 = i, r2 = ITERATIONS, r3 = 1, r4 = i < ITERATIONS
+    let instructions = vec![
+        Instruction::abx(LOAD_CONSTANT, 0, 0),
+        Instruction::abx(LOAD_CONSTANT, 1, 0),
+        Instruction::abx(LOAD_CONSTANT, 2, 1),
+        Instruction::abx(LOAD_CONSTANT, 3, 2),
+        Instruction::abc(LESS, 4, 1, 2),
+        Instruction::asbx(JUMP_IF_NOT, 4, 3),
+        Instruction::abc(ADD, 0, 0, 1),
+        Instruction::abc(ADD, 1, 1, 3),
+        Instruction::asbx(JUMP, 0, -5),
+        Instruction::abc(RETURN, 0, 0, 0),
+    ];
+    vm3::Chunk::new(instructions, vec![Number(0), Number(ITERATIONS), Number(1)], vec![], 5)
+}
+
+// let t = []; let sum = 0; let i = 0; while i < ITERATIONS { t[i] = i; sum = sum + t[i]; i = i + 1 }; sum
+fn vm2_table_loop() -> vm2::Chunk {
+    use vm2::misc::Value::Number;
+    // locals from the top: i, sum, t
+    let mut code = vec![
+        AllocTable, PushLocal,
+        Constant(0), PushLocal,
+        Constant(0), PushLocal,
+        // loop:
+        PeekLocal(0), Constant(1), TestLess, TestNot, JumpIf(0),
+        PeekLocal(0), PeekLocal(0), PeekLocal(2), InsertIndexTable,
+        PeekLocal(1), PeekLocal(0), PeekLocal(2), GetIndexTable, Add, SetLocal(1),
+        PeekLocal(0), Constant(2), Add, SetLocal(0),
+        Jump(0),
+        // end:
+        PeekLocal(1),
+    ];
+    let (start, end) = (offset_of(&code, 6), offset_of(&code, 26));
+    code[10] = JumpIf(end);
+    code[25] = Jump(start);
+    vm2::Chunk::new(convert_bytecode_array(code), vec![Number(0), Number(ITERATIONS), Number(1)])
+}
+fn vm3_table_loop() -> vm3::Chunk {
+    use vm3::value::Value::Number;
+    // r0 = t, r1 = sum, r2 = i, r3 = ITERATIONS, r4 = 1, r5 = scratch
+    let instructions = vec![
+        Instruction::abc(NEW_TABLE, 0, 0, 0),
+        Instruction::abx(LOAD_CONSTANT, 1, 0),
+        Instruction::abx(LOAD_CONSTANT, 2, 0),
+        Instruction::abx(LOAD_CONSTANT, 3, 1),
+        Instruction::abx(LOAD_CONSTANT, 4, 2),
+        Instruction::abc(LESS, 5, 2, 3),
+        Instruction::asbx(JUMP_IF_NOT, 5, 5),
+        Instruction::abc(SET_INDEX, 0, 2, 2),
+        Instruction::abc(GET_INDEX, 5, 0, 2),
+        Instruction::abc(ADD, 1, 1, 5),
+        Instruction::abc(ADD, 2, 2, 4),
+        Instruction::asbx(JUMP, 0, -7),
+        Instruction::abc(RETURN, 1, 0, 0),
+    ];
+    vm3::Chunk::new(instructions, vec![Number(0), Number(ITERATIONS), Number(1)], vec![], 6)
+}
@@ -0,0 +1,117 @@
+use std::fmt::{Display, Formatter};
+use crate::second_attempt::numeric::ArithmeticError;
+
+// a runtime error from one of the vms, along with where it happened
+#[derive(Clone, Debug, PartialEq)]
+pub struct VmError {
+    pub kind: VmErrorKind,
+    // index of the chunk on the chunk stack, always 0 for vms without chunks
+    pub chunk: usize,
+    // instruction pointer of the instruction that failed
+    pub ip: usize,
+}
+#[derive(Clone, Debug, PartialEq)]
+pub enum VmErrorKind {
+    // the function names on the call stack when it overflowed, innermost first, empty for vms without calls
+    StackOverflow(Vec<String>),
+    StackUnderflow,
+    TypeError { expected: &'static str, found: &'static str },
+    BadTableHandle(usize),
+    InvalidOpcode(u8),
+    DivisionByZero,
+    InvalidChunk(String),
+    Unsupported(&'static str),
+    ArityMismatch { expected: usize, found: usize },
+    UndefinedGlobal(String),
+    // resuming a coroutine that's running or finished
+    CannotResume(&'static str),
+    // a thrown value nothing caught, shown as a string
+    Uncaught(String),
+    // a limit set with `Vm::set_limits` was hit, scripts can't catch these
+    LimitExceeded(Limit),
+    // a native function failed for its own reasons, e.g. `compile` given code that doesn't parse
+    Native(String),
+    // a native restored from a snapshot that the host hasn't defined again
+    MissingNative(String),
+    // stopped from outside, e.g. by quitting the debugger
+    Interrupted,
+}
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Limit {
+    Fuel,
+    CallDepth,
+    Tables,
+    TableMemory,
+    Deadline,
+}
+impl Display for Limit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Limit::Fuel => write!(f, "ran out of fuel"),
+            Limit::CallDepth => write!(f, "too many nested calls"),
+            Limit::Tables => write!(f, "too many tables"),
+            Limit::TableMemory => write!(f, "too much table memory"),
+            Limit::Deadline => write!(f, "ran past its deadline"),
+        }
+    }
+}
+impl VmErrorKind {
+    pub fn at(self, chunk: usize, ip: usize) -> VmError {
+        VmError {
+            kind: self,
+            chunk,
+            ip
+        }
+    }
+}
+impl From<ArithmeticError> for VmErrorKind {
+    fn from(err: ArithmeticError) -> Self {
+        match err {
+            ArithmeticError::DivisionByZero => VmErrorKind::DivisionByZero,
+        }
+    }
+}
+impl Display for VmErrorKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VmErrorKind::StackOverflow(call_stack) => {
+                write!(f, "stack overflow")?;
+                // deep recursion would list the same function thousands of times, so runs of it are counted
+                let mut frames = call_stack.iter().peekable();
+                let mut separator = " in ";
+                while let Some(name) = frames.next() {
+                    let mut count = 1;
+                    while frames.next_if_eq(&name).is_some() {
+                        count += 1;
+                    }
+                    match count {
+                        1 => write!(f, "{}{}", separator, name)?,
+                        count => write!(f, "{}{} (x{})", separator, name, count)?,
+                    }
+                    separator = " <- ";
+                }
+                Ok(())
+            }
+            VmErrorKind::StackUnderflow => write!(f, "stack underflow"),
+            VmErrorKind::TypeError { expected, found } => write!(f, "type error: expected {} but found {}", expected, found),
+            VmErrorKind::BadTableHandle(handle) => write!(f, "no table with handle {}", handle),
+            VmErrorKind::InvalidOpcode(opcode) => write!(f, "invalid opcode {:#04x}", opcode),
+            VmErrorKind::DivisionByZero => write!(f, "integer division by zero"),
+            VmErrorKind::InvalidChunk(reason) => write!(f, "invalid chunk: {}", reason),
+            VmErrorKind::Unsupported(what) => write!(f, "{} isn't supported by this vm", what),
+            VmErrorKind::ArityMismatch { expected, found } => write!(f, "expected {} arguments but got {}", expected, found),
+            VmErrorKind::UndefinedGlobal(name) => write!(f, "undefined global `{}`", name),
+            VmErrorKind::CannotResume(state) => write!(f, "can't resume a {} coroutine", state),
+            VmErrorKind::Uncaught(value) => write!(f, "uncaught exception: {}", value),
+            VmErrorKind::LimitExceeded(limit) => write!(f, "limit exceeded: {}", limit),
+            VmErrorKind::Native(message) => write!(f, "{}", message),
+            VmErrorKind::MissingNative(name) => write!(f, "native `{}` hasn't been defined since the vm was restored", name),
+            VmErrorKind::Interrupted => write!(f, "interrupted"),
+        }
+    }
+}
+impl Display for VmError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (chunk {}, ip {:04})", self.kind, self.chunk, self.ip)
+    }
+}
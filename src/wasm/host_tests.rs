@@ -0,0 +1,51 @@
+use crate::wasm::{Host, TableValue};
+
+/*
+A stand-in for what the C backend builds: `_start` calls the import and, if it threw, hands the
+thrown string to the runtime's `exception` the way the generated `_start` does. It exports just
+enough of the runtime for the host to throw a string into it.
+ */
+const MODULE: &str = r#"
+(module
+  (import "imports" "fail" (func $fail (param i32 i32) (result i32)))
+  (import "host" "exception" (func $exception (param i32)))
+  (memory (export "memory") 1)
+  (global $heap (mut i32) (i32.const 1024))
+  (global $thrown (mut i32) (i32.const 0))
+  (func $malloc (export "malloc") (param $size i32) (result i32)
+    (local $pointer i32)
+    (local.set $pointer (global.get $heap))
+    (global.set $heap (i32.add (global.get $heap) (i32.and (i32.add (local.get $size) (i32.const 15)) (i32.const -16))))
+    (local.get $pointer))
+  (func (export "free") (param i32))
+  (func (export "increment") (param i32))
+  (func (export "decrement") (param i32))
+  ;; keeps the buffer rather than copying it, nothing here is ever freed
+  (func (export "String_new") (param $string i32) (result i32)
+    (local $value i32)
+    (local.set $value (call $malloc (i32.const 16)))
+    (i32.store (local.get $value) (local.get $string))
+    (i32.store offset=8 (local.get $value) (i32.const 1))
+    (local.get $value))
+  (func (export "throw_value") (param $value i32)
+    (global.set $thrown (local.get $value)))
+  (func (export "_start")
+    (if (i32.eqz (call $fail (i32.const 0) (i32.const 0)))
+      (then (call $exception (i32.load (global.get $thrown))))))
+)
+"#;
+
+#[test]
+fn uncaught_host_error_is_returned_from_run() {
+    let mut host = Host::new();
+    host.register("fail", |_| Err("the host failed".to_string()));
+    let err = host.run(MODULE.as_bytes()).expect_err("the exception should trap");
+    assert!(format!("{:?}", err).contains("exception: the host failed"), "{:?}", err);
+}
+#[test]
+fn host_function_that_succeeds_runs_to_the_end() {
+    let mut host = Host::new();
+    // a closure is handed back as the same pointer, so nothing has to be built in the module
+    host.register("fail", |_| Ok(TableValue::Closure(1024)));
+    assert!(host.run(MODULE.as_bytes()).is_ok());
+}
@@ -1,10 +1,100 @@
 use std::ffi::{c_char, CStr, CString};
 use wasmtime::*;
+use crate::second_attempt::c_gen_helper::IMPORT_MODULE;
+use crate::second_attempt::numeric::Numeric;
+
+#[cfg(test)]
+mod host_tests;
+
+/*
+Runs modules the C backend built. The runtime's own imports (printing and exceptions) are always
+linked, everything a Table program declares with `import fn` comes from functions registered on a
+`Host` under the same name.
+
+    let mut host = Host::new();
+    host.register("add", |arguments| match arguments {
+        [TableValue::Number(a), TableValue::Number(b)] => Ok(TableValue::Number(a + b)),
+        _ => Err("add takes two numbers".to_string()),
+    });
+    host.run(&wasm)?;
+
+The generated shim calls the import with a pointer to an array of `Value*` and how many there are,
+and takes a `Value*` back. Arguments are read straight out of linear memory, results are built with
+the runtime's own constructors so the module owns them like any other value. A host function that
+returns an error throws its message as a string, the same as a runtime error in C would.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum TableValue {
+    None,
+    Number(i32),
+    Float(f64),
+    Boolean(bool),
+    String(String),
+    // a closure can't be called from the host, only handed back, so this is its `Value*`
+    Closure(u32),
+}
+pub type HostFunction = dyn Fn(&[TableValue]) -> std::result::Result<TableValue, String> + Send + Sync;
+
+// the C runtime's `Value` on wasm32, see c_files/table_std.h: the union is as big as its double,
+// then the tag and the ref count
+const VALUE_SIZE: usize = 16;
+const TAG_OFFSET: usize = 8;
+const TAG_NONE: u32 = 0;
+const TAG_STRING: u32 = 1;
+const TAG_NUMBER: u32 = 2;
+const TAG_CLOSURE: u32 = 3;
+const TAG_BOOLEAN: u32 = 4;
+const TAG_FLOAT: u32 = 5;
+
+pub struct Host {
+    engine: Engine,
+    linker: Linker<()>,
+}
+impl Host {
+    pub fn new() -> Self {
+        let engine = Engine::default();
+        let mut linker = Linker::new(&engine);
+        // registering a name again replaces the function
+        linker.allow_shadowing(true);
+        link_runtime(&mut linker);
+        Self { engine, linker }
+    }
+    pub fn register<F>(&mut self, name: &str, function: F)
+        where F: Fn(&[TableValue]) -> std::result::Result<TableValue, String> + Send + Sync + 'static {
+        self.linker.func_wrap(IMPORT_MODULE, name, move |mut caller: Caller<'_, ()>, arguments: u32, count: u32| -> Result<u32> {
+            let memory = memory(&mut caller)?;
+            let data = memory.data(&caller);
+            let arguments = (0..count)
+                .map(|index| read_value(data, read_u32(data, arguments + index * 4)?))
+                .collect::<Result<Vec<_>>>()?;
+            match function(&arguments) {
+                Ok(value) => write_value(&mut caller, value),
+                Err(message) => {
+                    throw(&mut caller, message)?;
+                    Ok(0)
+                }
+            }
+        }).expect("host functions all have the same signature");
+    }
+    // instantiates the module and runs its `_start`
+    pub fn run(&self, file: &[u8]) -> Result<()> {
+        // wasmtime takes the text format as well, which the tests are written in
+        let module = Module::new(&self.engine, file)?;
+        let mut store = Store::new(&self.engine, ());
+        let instance = self.linker.instantiate(&mut store, &module)?;
+        let start = instance.get_typed_func::<(), ()>(&mut store, "_start")?;
+        start.call(&mut store, ())
+    }
+}
+impl Default for Host {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 pub fn wasmtime_runner(file: Vec<u8>) {
-    let engine = Engine::default();
-    let module = wasmtime::Module::from_binary(&engine, file.as_slice()).unwrap();
-    let mut store = Store::new(&engine, ());
-    let mut linker = Linker::new(&engine);
+    Host::new().run(file.as_slice()).unwrap();
+}
+fn link_runtime(linker: &mut Linker<()>) {
     linker.func_wrap("host", "print", |mut caller: Caller<'_, ()>, param: u32| {
         let memory = caller.get_export("memory").unwrap().into_memory().unwrap();
         unsafe {
@@ -13,18 +103,86 @@ pub fn wasmtime_runner(file: Vec<u8>) {
             print!("{}", c_str.to_str().unwrap())
         }
     }).unwrap();
-    linker.func_wrap("host", "exception", |mut caller: Caller<'_, ()>, param: u32| {
-        let memory = caller.get_export("memory").unwrap().into_memory().unwrap();
-        unsafe {
-            let ptr = memory.data_ptr(caller).add(param as usize);
-            let c_str = CStr::from_ptr(ptr as *mut c_char);
-            panic!("exception: {}", c_str.to_str().unwrap())
-        }
+    // `_start` calls this on an uncaught exception, the error traps out of the module and `run` returns it
+    linker.func_wrap("host", "exception", |mut caller: Caller<'_, ()>, param: u32| -> Result<()> {
+        let memory = memory(&mut caller)?;
+        let message = read_string(memory.data(&caller), param)?;
+        Err(Error::msg(format!("exception: {}", message)))
     }).unwrap();
     linker.func_wrap("host", "print_num", |mut caller: Caller<'_, ()>, param: i32| {
         print!("number: {}", param);
     }).unwrap();
-    let instance = linker.instantiate(&mut store, &module).unwrap();
-    let start = instance.get_typed_func::<(), ()>(&mut store, "_start").unwrap();
-    start.call(&mut store, ()).unwrap();
-}
\ No newline at end of file
+    linker.func_wrap("host", "print_float", |mut caller: Caller<'_, ()>, param: f64| {
+        print!("float: {}", Numeric::Float(param));
+    }).unwrap();
+    linker.func_wrap("host", "print_boolean", |param: i32| {
+        print!("boolean: {}", param != 0);
+    }).unwrap();
+}
+
+fn memory(caller: &mut Caller<'_, ()>) -> Result<Memory> {
+    caller.get_export("memory").and_then(Extern::into_memory).ok_or_else(|| Error::msg("the module doesn't export its memory"))
+}
+// calls one of the runtime's functions, the module is linked with everything exported
+fn call<P: WasmParams, R: WasmResults>(caller: &mut Caller<'_, ()>, name: &str, params: P) -> Result<R> {
+    let function = caller.get_export(name)
+        .and_then(Extern::into_func)
+        .ok_or_else(|| Error::msg(format!("the module doesn't export `{}`", name)))?;
+    function.typed::<P, R>(&*caller)?.call(&mut *caller, params)
+}
+fn read_bytes(data: &[u8], pointer: u32, len: usize) -> Result<&[u8]> {
+    let start = pointer as usize;
+    data.get(start..start + len).ok_or_else(|| Error::msg(format!("pointer {:#x} is out of bounds", pointer)))
+}
+fn read_u32(data: &[u8], pointer: u32) -> Result<u32> {
+    Ok(u32::from_le_bytes(read_bytes(data, pointer, 4)?.try_into().unwrap()))
+}
+fn read_string(data: &[u8], pointer: u32) -> Result<String> {
+    let string = data.get(pointer as usize..)
+        .and_then(|bytes| CStr::from_bytes_until_nul(bytes).ok())
+        .ok_or_else(|| Error::msg(format!("string at {:#x} isn't terminated", pointer)))?;
+    Ok(string.to_string_lossy().into_owned())
+}
+fn read_value(data: &[u8], pointer: u32) -> Result<TableValue> {
+    let value = read_bytes(data, pointer, VALUE_SIZE)?;
+    let word = u32::from_le_bytes(value[..4].try_into().unwrap());
+    let tag = u32::from_le_bytes(value[TAG_OFFSET..TAG_OFFSET + 4].try_into().unwrap());
+    Ok(match tag {
+        TAG_NONE => TableValue::None,
+        TAG_STRING => TableValue::String(read_string(data, word)?),
+        TAG_NUMBER => TableValue::Number(word as i32),
+        TAG_CLOSURE => TableValue::Closure(pointer),
+        TAG_BOOLEAN => TableValue::Boolean(word != 0),
+        TAG_FLOAT => TableValue::Float(f64::from_le_bytes(value[..8].try_into().unwrap())),
+        tag => return Err(Error::msg(format!("value at {:#x} has unknown tag {}", pointer, tag))),
+    })
+}
+// a new `Value*` the caller owns
+fn write_value(caller: &mut Caller<'_, ()>, value: TableValue) -> Result<u32> {
+    match value {
+        TableValue::None => call(caller, "None_new", ()),
+        TableValue::Number(num) => call(caller, "Number_new", num),
+        TableValue::Float(num) => call(caller, "Float_new", num),
+        TableValue::Boolean(bool) => call(caller, "Boolean_new", bool as i32),
+        TableValue::String(string) => {
+            // `String_new` copies the string, so the buffer it's read from is only borrowed
+            let string = CString::new(string).map_err(|_| Error::msg("strings passed to wasm can't contain nul"))?;
+            let bytes = string.as_bytes_with_nul();
+            let buffer: u32 = call(caller, "malloc", bytes.len() as u32)?;
+            memory(caller)?.write(&mut *caller, buffer as usize, bytes)?;
+            let value = call(caller, "String_new", buffer)?;
+            call::<u32, ()>(caller, "free", buffer)?;
+            Ok(value)
+        }
+        TableValue::Closure(pointer) => {
+            call::<u32, ()>(caller, "increment", pointer)?;
+            Ok(pointer)
+        }
+    }
+}
+// like the runtime's own errors: throw_value keeps its own reference, so ours is dropped again
+fn throw(caller: &mut Caller<'_, ()>, message: String) -> Result<()> {
+    let value = write_value(caller, TableValue::String(message))?;
+    call::<u32, ()>(caller, "throw_value", value)?;
+    call::<u32, ()>(caller, "decrement", value)
+}